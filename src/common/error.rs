@@ -26,15 +26,9 @@ pub enum Sv2Error {
     #[error("Send failed")]
     ChannelSend,
 
-    #[error("Recv failed")]
-    ChannelRecv,
-
     #[error("Bad state: {0}")]
     InvalidState(String),
 
-    #[error("Template: {0}")]
-    TemplateBuilding(String),
-
     #[error("Serialize: {0}")]
     Serialization(String),
 
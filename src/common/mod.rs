@@ -2,4 +2,4 @@ pub mod error;
 pub mod types;
 
 pub use error::{Sv2Error, Result};
-pub use types::{Event, Stats, CoinbaseOut};
+pub use types::{Event, Stats, CoinbaseOut, CustomJob, PoolCoinbase, MiningJob, Share, Solution, TplHeader, TplSnapshot};
@@ -1,5 +1,5 @@
 use serde::{Deserialize, Serialize};
-use std::time::SystemTime;
+use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 pub enum Event {
@@ -11,6 +11,14 @@ pub enum Event {
         fees: u64,
     },
     TemplateErr(String),
+    /// The chain tip moved, by a new block or a reorg. Templates on any
+    /// other prev hash are stale.
+    NewPrevHash {
+        /// Height of blocks built on the new tip
+        height: u64,
+        /// Internal byte order
+        prev_hash: [u8; 32],
+    },
 
     PoolConnecting,
     PoolUp,
@@ -18,6 +26,8 @@ pub enum Event {
     Handshaking,
     HandshakeDone,
     HandshakeErr(String),
+    /// SetupConnection with the pool succeeded
+    SetupDone,
     
     JobSent {
         tpl_id: u64,
@@ -31,29 +41,151 @@ pub enum Event {
         tpl_id: u64,
         reason: String,
     },
+    /// Declarations abandoned or skipped because their tip went stale
+    StaleAvoided(u64),
+    /// Miners were switched to (true) or back from (false) solo jobs
+    SoloMode(bool),
 
     DeclareJob {
-        tpl_id: u64,
+        template: Arc<TplSnapshot>,
         outputs: Vec<CoinbaseOut>,
-        txs: Vec<Vec<u8>>,
     },
+    /// The pool's coinbase requirements changed; template sources reserve
+    /// room for them
+    PoolCoinbase(PoolCoinbase),
+
+    NewMiningJob(MiningJob),
+    CustomJob(CustomJob),
+    ChannelUp {
+        channel_id: u32,
+        extranonce_prefix: Vec<u8>,
+    },
+    ChannelDown,
+    Share(Share),
+    BlockFound(Solution),
 
     Shutdown,
     Err(String),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Block header fields of the template a job is built on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TplHeader {
+    pub version: u32,
+    /// Internal byte order
+    pub prev_hash: [u8; 32],
+    pub bits: u32,
+    pub time: u32,
+}
+
+/// One template exactly as the template source gave it. A declaration is
+/// built from a single snapshot so its fields can't come from different
+/// templates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TplSnapshot {
+    pub id: u64,
+    pub height: u64,
+    pub header: TplHeader,
+    /// Earliest header time the block is valid with
+    pub min_time: u32,
+    /// Non-coinbase transactions in block order, witnesses included
+    pub txs: Arc<Vec<Vec<u8>>>,
+    pub fees: u64,
+    /// Subsidy plus fees available to the coinbase outputs
+    pub coinbase_value: u64,
+    /// Witness commitment script the template source expects, if it gave
+    /// one
+    pub witness_commitment: Option<Vec<u8>>,
+}
+
+/// Work derived from a declared template, ready to hand to miners. The
+/// coinbase parts are the non-witness serialization, split where the
+/// extranonce goes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MiningJob {
+    pub tpl_id: u64,
+    pub version: u32,
+    pub prev_hash: [u8; 32],
+    pub bits: u32,
+    pub time: u32,
+    /// Earliest header time the block is valid with
+    pub min_time: u32,
+    pub cb_prefix: Vec<u8>,
+    pub cb_suffix: Vec<u8>,
+    pub extranonce_len: usize,
+    /// Upstream channel prefix, already at the end of `cb_prefix`
+    pub extranonce_prefix: Vec<u8>,
+    pub merkle_path: Vec<[u8; 32]>,
+    /// Non-coinbase transactions in block order, for block assembly
+    pub txs: Arc<Vec<Vec<u8>>>,
+    /// The coinbase commits to witnesses, so the block needs its witness
+    pub cb_witness: bool,
+    /// Built by the solo fallback rather than declared to the pool
+    pub solo: bool,
+}
+
+/// A declared job as registered on the upstream mining channel with
+/// SetCustomMiningJob
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CustomJob {
+    pub tpl_id: u64,
+    pub token: Vec<u8>,
+    pub header: TplHeader,
+    /// scriptSig bytes before the extranonce
+    pub script_prefix: Vec<u8>,
+    pub cb_value: u64,
+    /// Serialized outputs, count included
+    pub cb_outputs: Vec<u8>,
+    pub merkle_path: Vec<[u8; 32]>,
+    /// Whole extranonce, upstream channel prefix included
+    pub extranonce_size: u16,
+}
+
+/// A share that met its channel target
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Share {
+    pub tpl_id: u64,
+    pub version: u32,
+    pub ntime: u32,
+    pub nonce: u32,
+    /// Full extranonce placed between the coinbase prefix and suffix
+    pub extranonce: Vec<u8>,
+    /// Header hash, internal byte order
+    pub hash: [u8; 32],
+    /// Mined on a solo job, so of no use to the pool
+    pub solo: bool,
+}
+
+/// A share that met the network target, assembled into a block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Solution {
+    pub tpl_id: u64,
+    /// Block hash, internal byte order
+    pub hash: [u8; 32],
+    /// Header fields as mined
+    pub header: TplHeader,
+    pub nonce: u32,
+    /// Full extranonce, upstream channel prefix included
+    pub extranonce: Vec<u8>,
+    pub coinbase: Vec<u8>,
+    pub block: Vec<u8>,
+    /// Found on a solo job; only the node can use it
+    pub solo: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct CoinbaseOut {
     pub value: u64,
     pub script_pubkey: Vec<u8>,
 }
 
-#[derive(Debug, Clone)]
-pub enum JobState {
-    Pending,
-    Sent { ts: SystemTime },
-    Accepted { token: Vec<u8> },
-    Rejected { reason: String },
+/// What the pool asks of our coinbase when it allocates a token
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PoolCoinbase {
+    /// Outputs that must come first in the coinbase
+    pub outputs: Vec<CoinbaseOut>,
+    /// Serialized size all coinbase outputs may take, the pool's included
+    pub max_extra: u32,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -66,6 +198,10 @@ pub struct Stats {
     pub declared: u64,
     pub accepted: u64,
     pub rejected: u64,
+    pub stale_avoided: u64,
+    pub solo: bool,
+    pub shares: u64,
+    pub blocks: u64,
     pub fees: u64,
     pub uptime: u64,
 }
@@ -0,0 +1,331 @@
+//! Downstream server - Stratum V2 Mining Protocol and SV1 translator
+//!
+//! Miners connect here and mine on the jobs the pool client declared.
+//! Each connection runs its own session task; the latest job is handed
+//! to all of them through a watch channel. SV1 and SV2 connections draw
+//! from one set of live connection ids so their extranonce prefixes
+//! never overlap.
+
+mod session;
+mod sv1;
+
+use secp256k1::{Keypair, Secp256k1};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, watch};
+use tracing::{error, info, warn};
+
+use crate::common::{Event, MiningJob, Result, Share, Solution};
+use crate::pool::{block, noise, work};
+use session::Session;
+use sv1::Sv1Session;
+
+/// Header version bits miners may roll (BIP320)
+const VERSION_ROLLING_MASK: u32 = 0x1FFF_E000;
+
+/// Jobs kept per connection for late shares
+const MAX_JOBS: usize = 8;
+
+/// How far past our clock a share's ntime may be, as nodes allow for
+/// blocks
+const MAX_FUTURE_NTIME: u64 = 2 * 60 * 60;
+
+/// Bytes of extranonce we assign per channel: connection id + channel id
+const CHANNEL_PREFIX_LEN: usize = 4;
+
+/// An accepted share and, if it also met the network target, its block
+type Accepted = (Share, Option<Solution>);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DownstreamConfig {
+    pub listen_address: String,
+    /// Stratum V1 endpoint for legacy miners, off when unset
+    #[serde(default)]
+    pub sv1_listen_address: Option<String>,
+    /// Base58-check secret key that signs our Noise certificates. A fresh
+    /// one is generated at startup when unset.
+    #[serde(default)]
+    pub authority_secret_key: Option<String>,
+    /// Certificate validity in seconds
+    #[serde(default = "default_cert_validity")]
+    pub cert_validity: u64,
+    /// Difficulty of the shares we ask miners for
+    #[serde(default = "default_share_difficulty")]
+    pub share_difficulty: f64,
+    /// Shares per minute SV2 channels are retargeted towards, never below
+    /// `share_difficulty`; 0 keeps every channel at `share_difficulty`
+    #[serde(default)]
+    pub shares_per_minute: f64,
+}
+
+fn default_cert_validity() -> u64 {
+    3600
+}
+
+fn default_share_difficulty() -> f64 {
+    1.0
+}
+
+pub struct MiningServer {
+    cfg: DownstreamConfig,
+    bus_tx: broadcast::Sender<Event>,
+    bus_rx: broadcast::Receiver<Event>,
+    conn_ids: ConnIds,
+}
+
+impl MiningServer {
+    pub fn new(
+        cfg: DownstreamConfig,
+        bus_tx: broadcast::Sender<Event>,
+        bus_rx: broadcast::Receiver<Event>,
+    ) -> Self {
+        Self {
+            cfg,
+            bus_tx,
+            bus_rx,
+            conn_ids: ConnIds::default(),
+        }
+    }
+
+    pub async fn run(mut self) -> Result<()> {
+        info!("Starting downstream mining server");
+
+        let authority = match self.cfg.authority_secret_key.as_deref().map(str::trim) {
+            None | Some("") => {
+                warn!("No downstream authority_secret_key configured, using a throwaway key");
+                Keypair::new(&Secp256k1::new(), &mut rand::thread_rng())
+            }
+            Some(s) => noise::parse_authority_secret(s)?,
+        };
+        info!(
+            "Downstream authority key: {}",
+            noise::encode_authority_key(&authority.x_only_public_key().0)
+        );
+
+        let validity = Duration::from_secs(self.cfg.cert_validity);
+        let listener = TcpListener::bind(&self.cfg.listen_address).await?;
+        info!("Listening for miners on {}", self.cfg.listen_address);
+
+        let sv1_listener = match &self.cfg.sv1_listen_address {
+            Some(addr) => {
+                info!("Listening for SV1 miners on {}", addr);
+                Some(TcpListener::bind(addr).await?)
+            }
+            None => None,
+        };
+
+        let (job_tx, job_rx) = watch::channel::<Option<Arc<MiningJob>>>(None);
+
+        loop {
+            tokio::select! {
+                res = listener.accept() => {
+                    let (stream, peer) = match res {
+                        Ok(c) => c,
+                        Err(e) => {
+                            error!("Accept failed: {}", e);
+                            continue;
+                        }
+                    };
+
+                    let Some(conn) = self.conn_ids.take() else {
+                        warn!("Refusing miner {}: no free connection ids", peer);
+                        continue;
+                    };
+                    info!("Miner connected: {} (conn {})", peer, conn.id);
+
+                    let session = Session::new(
+                        conn.id,
+                        self.bus_tx.clone(),
+                        self.cfg.share_difficulty,
+                        self.cfg.shares_per_minute,
+                    );
+                    let jobs = job_rx.clone();
+                    tokio::spawn(async move {
+                        let _conn = conn;
+                        match session.run(stream, authority, validity, jobs).await {
+                            Ok(()) => info!("Miner {} disconnected", peer),
+                            Err(e) => warn!("Miner {} dropped: {}", peer, e),
+                        }
+                    });
+                }
+
+                res = accept_opt(&sv1_listener) => {
+                    let (stream, peer) = match res {
+                        Ok(c) => c,
+                        Err(e) => {
+                            error!("SV1 accept failed: {}", e);
+                            continue;
+                        }
+                    };
+
+                    let Some(conn) = self.conn_ids.take() else {
+                        warn!("Refusing SV1 miner {}: no free connection ids", peer);
+                        continue;
+                    };
+                    info!("SV1 miner connected: {} (conn {})", peer, conn.id);
+
+                    let session = Sv1Session::new(
+                        conn.id, self.bus_tx.clone(), self.cfg.share_difficulty,
+                    );
+                    let jobs = job_rx.clone();
+                    tokio::spawn(async move {
+                        let _conn = conn;
+                        match session.run(stream, jobs).await {
+                            Ok(()) => info!("SV1 miner {} disconnected", peer),
+                            Err(e) => warn!("SV1 miner {} dropped: {}", peer, e),
+                        }
+                    });
+                }
+
+                ev = self.bus_rx.recv() => {
+                    match ev {
+                        Ok(Event::NewMiningJob(job)) => {
+                            job_tx.send_replace(Some(Arc::new(job)));
+                        }
+                        Ok(Event::Shutdown) | Err(broadcast::error::RecvError::Closed) => {
+                            info!("Mining server shutting down");
+                            return Ok(());
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Accept on an optional listener, never resolving when there is none
+/// Connection ids held by open sessions. An id goes back to the pool
+/// when its session ends, so a prefix is never shared by two live
+/// connections.
+#[derive(Default)]
+struct ConnIds {
+    live: Arc<Mutex<HashSet<u16>>>,
+    last: u16,
+}
+
+impl ConnIds {
+    /// The next free id after the last one handed out, or None when
+    /// every id is taken
+    fn take(&mut self) -> Option<ConnId> {
+        let mut live = self.live.lock().unwrap();
+        let id = (1..=1u32 << 16)
+            .map(|step| self.last.wrapping_add(step as u16))
+            .find(|id| !live.contains(id))?;
+        live.insert(id);
+        self.last = id;
+        Some(ConnId { id, live: self.live.clone() })
+    }
+}
+
+/// A connection id, released when dropped
+struct ConnId {
+    id: u16,
+    live: Arc<Mutex<HashSet<u16>>>,
+}
+
+impl Drop for ConnId {
+    fn drop(&mut self) {
+        self.live.lock().unwrap().remove(&self.id);
+    }
+}
+
+async fn accept_opt(listener: &Option<TcpListener>) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(l) => l.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Rebuild the header for a submission on `job` and check it against
+/// `target`. `extranonce` is the full extranonce, prefix included.
+fn check_work(
+    job: &MiningJob,
+    target: &[u8; 32],
+    extranonce: Vec<u8>,
+    version: u32,
+    ntime: u32,
+    nonce: u32,
+) -> std::result::Result<Accepted, &'static str> {
+    if extranonce.len() != job.extranonce_len {
+        return Err("invalid-extranonce-size");
+    }
+
+    // A block outside these bounds would be refused by the node
+    if ntime < job.min_time {
+        return Err("time-too-old");
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    if u64::from(ntime) > now + MAX_FUTURE_NTIME {
+        return Err("time-too-new");
+    }
+
+    let mut cb = Vec::with_capacity(job.cb_prefix.len() + extranonce.len() + job.cb_suffix.len());
+    cb.extend_from_slice(&job.cb_prefix);
+    cb.extend_from_slice(&extranonce);
+    cb.extend_from_slice(&job.cb_suffix);
+
+    let root = work::merkle_root_from_path(work::sha256d(&cb), &job.merkle_path);
+    let header = work::block_header(version, &job.prev_hash, &root, ntime, job.bits, nonce);
+    let hash = work::sha256d(&header);
+
+    if !work::le_lte(&hash, target) {
+        return Err("difficulty-too-low");
+    }
+
+    let share = Share {
+        tpl_id: job.tpl_id,
+        version,
+        ntime,
+        nonce,
+        extranonce,
+        hash,
+        solo: job.solo,
+    };
+    let solution = block::assemble(job, &share);
+    Ok((share, solution))
+}
+
+/// Put an accepted share, and its block if any, on the bus
+fn publish(bus: &broadcast::Sender<Event>, (share, solution): Accepted) {
+    if let Some(sol) = solution {
+        let mut hash = sol.hash;
+        hash.reverse();
+        info!("Block found: tpl={}, hash={}", sol.tpl_id, hex::encode(hash));
+        let _ = bus.send(Event::BlockFound(sol));
+    }
+    let _ = bus.send(Event::Share(share));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conn_ids() {
+        let mut ids = ConnIds::default();
+        let first = ids.take().unwrap();
+        assert_eq!(first.id, 1);
+
+        // Hold every id but the first, then the counter wraps past them
+        let held: Vec<_> = (0..u16::MAX).map(|_| ids.take().unwrap()).collect();
+        assert!(held.iter().all(|c| c.id != first.id));
+        assert!(ids.take().is_none());
+
+        // A closed connection's id is the only one handed out again
+        let mut held = held;
+        let closed = held.swap_remove(100);
+        let freed = closed.id;
+        drop(closed);
+        let reopened = ids.take().unwrap();
+        assert_eq!(reopened.id, freed);
+        assert!(ids.take().is_none());
+
+        drop((held, first, reopened));
+        assert!(ids.take().is_some());
+    }
+}
@@ -0,0 +1,680 @@
+//! One miner connection: setup, channels, jobs and share validation
+
+use futures::{SinkExt, StreamExt};
+use secp256k1::Keypair;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, watch};
+use tokio_util::codec::Framed;
+use tracing::{debug, info, warn};
+
+use crate::common::{Event, MiningJob, Result, Sv2Error};
+use crate::pool::binary::*;
+use crate::pool::codec::{Sv2Frame, Sv2NoiseCodec};
+use crate::pool::mining_messages::*;
+use crate::pool::noise;
+use crate::pool::sv2_messages::{
+    self, SetupConn, SetupConnErr, SetupConnOk, CB_EXTRANONCE_LEN, COMMON_EXT,
+    SV2_MAX_VERSION, SV2_MIN_VERSION,
+};
+use crate::pool::work;
+
+use super::{check_work, publish, Accepted, CHANNEL_PREFIX_LEN, MAX_JOBS, VERSION_ROLLING_MASK};
+
+/// Seconds of shares a channel's difficulty is judged on
+const RETARGET_SECS: f64 = 30.0;
+/// Shares after which a channel is judged early, so fast miners ramp up
+/// quickly
+const RETARGET_SHARES: u32 = 30;
+
+struct Channel {
+    extended: bool,
+    target: [u8; 32],
+    /// Easiest target the channel may get: the miner's `max_target` or our
+    /// configured one, whichever is harder
+    max_target: [u8; 32],
+    difficulty: f64,
+    /// Shares accepted since `since`, for retargeting
+    shares: u32,
+    since: Instant,
+    /// Part of the extranonce we fix. For standard channels this is the
+    /// whole extranonce.
+    extranonce_prefix: Vec<u8>,
+}
+
+impl Channel {
+    fn new(extended: bool, max_target: [u8; 32], difficulty: f64, extranonce_prefix: Vec<u8>) -> Self {
+        Self {
+            extended,
+            target: max_target,
+            max_target,
+            difficulty,
+            shares: 0,
+            since: Instant::now(),
+            extranonce_prefix,
+        }
+    }
+}
+
+pub(super) struct Session {
+    conn_id: u16,
+    bus: broadcast::Sender<Event>,
+    share_difficulty: f64,
+    share_target: [u8; 32],
+    /// Share rate channels are retargeted towards, 0 for a fixed target
+    shares_per_min: f64,
+    setup_done: bool,
+    channels: HashMap<u32, Channel>,
+    channel_seq: u16,
+    jobs: BTreeMap<u32, Arc<MiningJob>>,
+    job_seq: u32,
+    prev_hash: Option<[u8; 32]>,
+}
+
+impl Session {
+    pub(super) fn new(
+        conn_id: u16,
+        bus: broadcast::Sender<Event>,
+        share_difficulty: f64,
+        shares_per_min: f64,
+    ) -> Self {
+        Self {
+            conn_id,
+            bus,
+            share_difficulty,
+            share_target: work::target_from_difficulty(share_difficulty),
+            shares_per_min,
+            setup_done: false,
+            channels: HashMap::new(),
+            channel_seq: 0,
+            jobs: BTreeMap::new(),
+            job_seq: 0,
+            prev_hash: None,
+        }
+    }
+
+    pub(super) async fn run(
+        mut self,
+        mut stream: TcpStream,
+        authority: Keypair,
+        cert_validity: Duration,
+        mut jobs: watch::Receiver<Option<Arc<MiningJob>>>,
+    ) -> Result<()> {
+        let codec = noise::responder_handshake(&mut stream, &authority, cert_validity).await?;
+        let mut framed = Framed::new(stream, Sv2NoiseCodec::new(codec));
+
+        // Pick up the current job so channels opened right away get work
+        let current = jobs.borrow_and_update().clone();
+        if let Some(job) = current {
+            self.on_job(job)?;
+        }
+
+        loop {
+            tokio::select! {
+                res = framed.next() => {
+                    let Some(frame) = res else { return Ok(()) };
+                    for out in self.handle_msg(frame?)? {
+                        framed.send(out).await?;
+                    }
+                }
+
+                res = jobs.changed() => {
+                    if res.is_err() {
+                        return Ok(());
+                    }
+                    let current = jobs.borrow_and_update().clone();
+                    if let Some(job) = current {
+                        for out in self.on_job(job)? {
+                            framed.send(out).await?;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handle one miner message, returning the replies
+    fn handle_msg(&mut self, frame: Sv2Frame) -> Result<Vec<Sv2Frame>> {
+        debug!("Miner msg: type=0x{:02X}, len={}", frame.mtype, frame.payload.len());
+
+        if frame.mtype == sv2_messages::msg_types::SETUP_CONN {
+            return self.on_setup(&frame.payload);
+        }
+        if !self.setup_done {
+            return Err(Sv2Error::InvalidState(
+                format!("msg 0x{:02X} before SetupConnection", frame.mtype)
+            ));
+        }
+
+        match frame.mtype {
+            msg_types::OPEN_STANDARD_CHANNEL => {
+                let msg = OpenStandardChannel::parse(&frame.payload)?;
+                self.open_standard(msg)
+            }
+            msg_types::OPEN_EXTENDED_CHANNEL => {
+                let msg = OpenExtendedChannel::parse(&frame.payload)?;
+                self.open_extended(msg)
+            }
+            msg_types::SUBMIT_SHARES_STANDARD => {
+                let msg = SubmitSharesStandard::parse(&frame.payload)?;
+                let res = self.check_share(
+                    msg.channel_id, msg.job_id, msg.nonce, msg.ntime, msg.version, &[],
+                );
+                self.share_reply(msg.channel_id, msg.seq, res, Instant::now())
+            }
+            msg_types::SUBMIT_SHARES_EXTENDED => {
+                let msg = SubmitSharesExtended::parse(&frame.payload)?;
+                let res = self.check_share(
+                    msg.channel_id, msg.job_id, msg.nonce, msg.ntime, msg.version, &msg.extranonce,
+                );
+                self.share_reply(msg.channel_id, msg.seq, res, Instant::now())
+            }
+            other => {
+                warn!("Unsupported miner msg type: 0x{:02X}", other);
+                Ok(Vec::new())
+            }
+        }
+    }
+
+    fn on_setup(&mut self, data: &[u8]) -> Result<Vec<Sv2Frame>> {
+        let msg = SetupConn::parse(data)?;
+        info!("Miner setup: vendor={}, device={}", msg.vendor, msg.device_id);
+
+        let code = if msg.protocol != PROTO_MINING {
+            Some("unsupported-protocol")
+        } else if msg.min_ver > SV2_MAX_VERSION || msg.max_ver < SV2_MIN_VERSION {
+            Some("protocol-version-mismatch")
+        } else if msg.flags & mining_flags::REQUIRES_WORK_SELECTION != 0 {
+            Some("unsupported-feature-flags")
+        } else {
+            None
+        };
+
+        if let Some(code) = code {
+            warn!("Rejecting miner setup: {}", code);
+            let err = SetupConnErr {
+                flags: msg.flags & mining_flags::REQUIRES_WORK_SELECTION,
+                code: code.into(),
+            };
+            return Ok(vec![Sv2Frame::new(
+                sv2_messages::msg_types::SETUP_CONN_ERR, COMMON_EXT, err.serialize()?,
+            )]);
+        }
+
+        self.setup_done = true;
+        let ok = SetupConnOk { used_ver: SV2_MAX_VERSION, flags: 0 };
+        Ok(vec![Sv2Frame::new(sv2_messages::msg_types::SETUP_CONN_OK, COMMON_EXT, ok.serialize()?)])
+    }
+
+    fn open_standard(&mut self, msg: OpenStandardChannel) -> Result<Vec<Sv2Frame>> {
+        let Some(seq) = self.next_channel() else {
+            return open_err(msg.req_id, "max-channels-reached");
+        };
+        let id = u32::from(seq);
+        let target = self.channel_target(&msg.max_target);
+
+        let mut extranonce = self.channel_prefix(seq).to_vec();
+        extranonce.resize(CB_EXTRANONCE_LEN, 0);
+
+        info!("Standard channel {} opened for {}", id, msg.user);
+
+        let ok = OpenStandardChannelOk {
+            req_id: msg.req_id,
+            channel_id: id,
+            target: target.into(),
+            extranonce_prefix: extranonce.clone().into(),
+            group_channel_id: 0,
+        };
+        let mut out = vec![Sv2Frame::new(
+            msg_types::OPEN_STANDARD_CHANNEL_OK, MINING_EXT, ok.serialize()?,
+        )];
+
+        let ch = Channel::new(false, target, self.share_difficulty, extranonce);
+        self.channels.insert(id, ch);
+        out.extend(self.current_work(id)?);
+        Ok(out)
+    }
+
+    fn open_extended(&mut self, msg: OpenExtendedChannel) -> Result<Vec<Sv2Frame>> {
+        let room = CB_EXTRANONCE_LEN - CHANNEL_PREFIX_LEN;
+        if msg.min_extranonce_size as usize > room {
+            warn!("Miner wants {} extranonce bytes, we have {}", msg.min_extranonce_size, room);
+            return open_err(msg.req_id, "unsupported-min-extranonce-size");
+        }
+
+        let Some(seq) = self.next_channel() else {
+            return open_err(msg.req_id, "max-channels-reached");
+        };
+        let id = u32::from(seq);
+        let target = self.channel_target(&msg.max_target);
+        let prefix = self.channel_prefix(seq).to_vec();
+
+        info!("Extended channel {} opened for {}", id, msg.user);
+
+        let ok = OpenExtendedChannelOk {
+            req_id: msg.req_id,
+            channel_id: id,
+            target: target.into(),
+            extranonce_size: room as u16,
+            extranonce_prefix: prefix.clone().into(),
+        };
+        let mut out = vec![Sv2Frame::new(
+            msg_types::OPEN_EXTENDED_CHANNEL_OK, MINING_EXT, ok.serialize()?,
+        )];
+
+        let ch = Channel::new(true, target, self.share_difficulty, prefix);
+        self.channels.insert(id, ch);
+        out.extend(self.current_work(id)?);
+        Ok(out)
+    }
+
+    /// Ids are never reused, and the extranonce prefix only has room for
+    /// 16 bits of them, so a connection runs out after 65535 channels
+    fn next_channel(&mut self) -> Option<u16> {
+        self.channel_seq = self.channel_seq.checked_add(1)?;
+        Some(self.channel_seq)
+    }
+
+    /// Extranonce bytes unique to a channel across all connections
+    fn channel_prefix(&self, channel_id: u16) -> [u8; CHANNEL_PREFIX_LEN] {
+        let mut p = [0u8; CHANNEL_PREFIX_LEN];
+        p[..2].copy_from_slice(&self.conn_id.to_be_bytes());
+        p[2..].copy_from_slice(&channel_id.to_be_bytes());
+        p
+    }
+
+    /// Our share target, or the miner's if that one is harder
+    fn channel_target(&self, max_target: &U256) -> [u8; 32] {
+        if work::le_lte(&max_target.0, &self.share_target) {
+            max_target.0
+        } else {
+            self.share_target
+        }
+    }
+
+    /// Latest job and prev hash for a freshly opened channel
+    fn current_work(&self, channel_id: u32) -> Result<Vec<Sv2Frame>> {
+        let Some((&job_id, job)) = self.jobs.last_key_value() else {
+            return Ok(Vec::new());
+        };
+        let ch = &self.channels[&channel_id];
+        Ok(vec![
+            job_frame(channel_id, ch, job_id, job, true)?,
+            prev_hash_frame(channel_id, job_id, job)?,
+        ])
+    }
+
+    /// Record a new job and announce it on every channel
+    fn on_job(&mut self, job: Arc<MiningJob>) -> Result<Vec<Sv2Frame>> {
+        self.job_seq = self.job_seq.wrapping_add(1);
+        let job_id = self.job_seq;
+
+        // Work on the old tip is worthless once the prev hash moves
+        let new_tip = self.prev_hash != Some(job.prev_hash);
+        if new_tip {
+            self.jobs.clear();
+            self.prev_hash = Some(job.prev_hash);
+        }
+        self.jobs.insert(job_id, job.clone());
+        while self.jobs.len() > MAX_JOBS {
+            self.jobs.pop_first();
+        }
+
+        debug!("Job {} for template {} (new tip: {})", job_id, job.tpl_id, new_tip);
+
+        let mut out = Vec::new();
+        for (&id, ch) in &self.channels {
+            out.push(job_frame(id, ch, job_id, &job, new_tip)?);
+            if new_tip {
+                out.push(prev_hash_frame(id, job_id, &job)?);
+            }
+        }
+        Ok(out)
+    }
+
+    /// Validate a share against its channel target. `extranonce` is the
+    /// miner's part, empty for standard channels.
+    fn check_share(
+        &self,
+        channel_id: u32,
+        job_id: u32,
+        nonce: u32,
+        ntime: u32,
+        version: u32,
+        extranonce: &[u8],
+    ) -> std::result::Result<Accepted, &'static str> {
+        let ch = self.channels.get(&channel_id).ok_or("invalid-channel-id")?;
+        let job = self.jobs.get(&job_id).ok_or("invalid-job-id")?;
+
+        if (version ^ job.version) & !VERSION_ROLLING_MASK != 0 {
+            return Err("invalid-version");
+        }
+
+        let mut full = ch.extranonce_prefix.clone();
+        full.extend_from_slice(extranonce);
+        check_work(job, &ch.target, full, version, ntime, nonce)
+    }
+
+    fn share_reply(
+        &mut self,
+        channel_id: u32,
+        seq: u32,
+        res: std::result::Result<Accepted, &'static str>,
+        now: Instant,
+    ) -> Result<Vec<Sv2Frame>> {
+        let ext = MINING_EXT | CHANNEL_BIT;
+        match res {
+            Ok(accepted) => {
+                debug!("Share accepted: channel={}, seq={}", channel_id, seq);
+                publish(&self.bus, accepted);
+                let ok = SubmitSharesOk { channel_id, last_seq: seq, accepted: 1, shares_sum: 1 };
+                let mut out = vec![Sv2Frame::new(msg_types::SUBMIT_SHARES_OK, ext, ok.serialize()?)];
+                out.extend(self.retarget(channel_id, now)?);
+                Ok(out)
+            }
+            Err(code) => {
+                debug!("Share rejected: channel={}, seq={}, {}", channel_id, seq, code);
+                let err = SubmitSharesErr { channel_id, seq, code: code.into() };
+                Ok(vec![Sv2Frame::new(msg_types::SUBMIT_SHARES_ERR, ext, err.serialize()?)])
+            }
+        }
+    }
+
+    /// Move a channel's difficulty towards `shares_per_min`, returning the
+    /// SetTarget for it if it changed enough to be worth one
+    fn retarget(&mut self, channel_id: u32, now: Instant) -> Result<Option<Sv2Frame>> {
+        if self.shares_per_min <= 0.0 {
+            return Ok(None);
+        }
+        let Some(ch) = self.channels.get_mut(&channel_id) else {
+            return Ok(None);
+        };
+
+        ch.shares += 1;
+        let secs = now.duration_since(ch.since).as_secs_f64();
+        if secs < RETARGET_SECS && ch.shares < RETARGET_SHARES {
+            return Ok(None);
+        }
+
+        let rate = f64::from(ch.shares) * 60.0 / secs.max(1.0);
+        let factor = (rate / self.shares_per_min).clamp(0.25, 4.0);
+        ch.shares = 0;
+        ch.since = now;
+        if (0.5..=2.0).contains(&factor) {
+            return Ok(None);
+        }
+
+        // Never easier than configured or than the miner asked for
+        let difficulty = (ch.difficulty * factor).max(self.share_difficulty);
+        let target = work::target_from_difficulty(difficulty);
+        let target = if work::le_lte(&target, &ch.max_target) { target } else { ch.max_target };
+        if target == ch.target {
+            return Ok(None);
+        }
+
+        info!("Channel {} difficulty {} -> {}", channel_id, ch.difficulty, difficulty);
+        ch.difficulty = difficulty;
+        ch.target = target;
+        let msg = SetTarget { channel_id, max_target: target.into() };
+        Ok(Some(Sv2Frame::new(msg_types::SET_TARGET, MINING_EXT | CHANNEL_BIT, msg.serialize()?)))
+    }
+}
+
+fn open_err(req_id: u32, code: &str) -> Result<Vec<Sv2Frame>> {
+    let err = OpenChannelErr { req_id, code: code.into() };
+    Ok(vec![Sv2Frame::new(msg_types::OPEN_CHANNEL_ERR, MINING_EXT, err.serialize()?)])
+}
+
+/// Job message for one channel. Future jobs carry no `min_ntime` and wait
+/// for a SetNewPrevHash.
+fn job_frame(
+    channel_id: u32,
+    ch: &Channel,
+    job_id: u32,
+    job: &MiningJob,
+    future: bool,
+) -> Result<Sv2Frame> {
+    let min_ntime = OPTION(if future { None } else { Some(job.time) });
+    let ext = MINING_EXT | CHANNEL_BIT;
+
+    if ch.extended {
+        let msg = NewExtendedJob {
+            channel_id,
+            job_id,
+            min_ntime,
+            version: job.version,
+            version_rolling: true,
+            merkle_path: job.merkle_path.iter().copied().map(U256).collect(),
+            cb_prefix: job.cb_prefix.clone().into(),
+            cb_suffix: job.cb_suffix.clone().into(),
+        };
+        return Ok(Sv2Frame::new(msg_types::NEW_EXTENDED_JOB, ext, msg.serialize()?));
+    }
+
+    let mut cb = job.cb_prefix.clone();
+    cb.extend_from_slice(&ch.extranonce_prefix);
+    cb.extend_from_slice(&job.cb_suffix);
+    let root = work::merkle_root_from_path(work::sha256d(&cb), &job.merkle_path);
+
+    let msg = NewMiningJob {
+        channel_id,
+        job_id,
+        min_ntime,
+        version: job.version,
+        merkle_root: root.into(),
+    };
+    Ok(Sv2Frame::new(msg_types::NEW_MINING_JOB, ext, msg.serialize()?))
+}
+
+fn prev_hash_frame(channel_id: u32, job_id: u32, job: &MiningJob) -> Result<Sv2Frame> {
+    let msg = SetNewPrevHash {
+        channel_id,
+        job_id,
+        prev_hash: job.prev_hash.into(),
+        min_ntime: job.time,
+        bits: job.bits,
+    };
+    Ok(Sv2Frame::new(msg_types::SET_NEW_PREV_HASH, MINING_EXT | CHANNEL_BIT, msg.serialize()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Share;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn test_job() -> MiningJob {
+        MiningJob {
+            tpl_id: 5,
+            version: 0x20000000,
+            prev_hash: [7; 32],
+            bits: 0x207fffff,
+            time: 1_700_000_000,
+            min_time: 1_699_999_000,
+            cb_prefix: vec![1, 2, 3],
+            cb_suffix: vec![4, 5, 6],
+            extranonce_len: CB_EXTRANONCE_LEN,
+            extranonce_prefix: Vec::new(),
+            merkle_path: vec![[9; 32]],
+            txs: Arc::new(Vec::new()),
+            cb_witness: false,
+            solo: false,
+        }
+    }
+
+    fn session_with_channel(extended: bool) -> Session {
+        let (bus, _) = broadcast::channel(8);
+        let mut s = Session::new(1, bus, 0.0, 0.0);
+        s.setup_done = true;
+        let prefix_len = if extended { CHANNEL_PREFIX_LEN } else { CB_EXTRANONCE_LEN };
+        let ch = Channel::new(extended, [0xFF; 32], 0.0, vec![0; prefix_len]);
+        s.channels.insert(1, Channel { target: work::target_from_difficulty(1.0), ..ch });
+        s
+    }
+
+    #[test]
+    fn test_job_announcement() {
+        let mut s = session_with_channel(true);
+        let job = Arc::new(test_job());
+
+        // First job on a tip: future job plus prev hash
+        let out = s.on_job(job.clone()).unwrap();
+        assert_eq!(out.len(), 2);
+        assert_eq!(out[0].mtype, msg_types::NEW_EXTENDED_JOB);
+        assert_eq!(out[1].mtype, msg_types::SET_NEW_PREV_HASH);
+        assert!(NewExtendedJob::parse(&out[0].payload).unwrap().min_ntime.0.is_none());
+
+        // Same tip: a plain job usable right away
+        let out = s.on_job(job).unwrap();
+        assert_eq!(out.len(), 1);
+        assert_eq!(NewExtendedJob::parse(&out[0].payload).unwrap().min_ntime.0, Some(1_700_000_000));
+
+        let mut next = test_job();
+        next.prev_hash = [8; 32];
+        s.on_job(Arc::new(next)).unwrap();
+        assert_eq!(s.jobs.len(), 1);
+    }
+
+    #[test]
+    fn test_share_checks() {
+        let mut s = session_with_channel(true);
+        s.on_job(Arc::new(test_job())).unwrap();
+        let job_id = s.job_seq;
+        let en = [0u8; CB_EXTRANONCE_LEN - CHANNEL_PREFIX_LEN];
+
+        assert_eq!(s.check_share(2, job_id, 0, 0, 0x20000000, &en), Err("invalid-channel-id"));
+        assert_eq!(s.check_share(1, 99, 0, 0, 0x20000000, &en), Err("invalid-job-id"));
+        assert_eq!(s.check_share(1, job_id, 0, 0, 0x20000001, &en), Err("invalid-version"));
+        assert_eq!(s.check_share(1, job_id, 0, 0, 0x20000000, &en[1..]), Err("invalid-extranonce-size"));
+
+        // Rolled version bits are fine; a random nonce won't reach diff 1
+        let ntime = 1_700_000_000;
+        assert_eq!(s.check_share(1, job_id, 0, ntime, 0x3FFFE000, &en), Err("difficulty-too-low"));
+
+        // Header times the node would refuse the block for
+        assert_eq!(s.check_share(1, job_id, 0, 1_699_998_999, 0x20000000, &en), Err("time-too-old"));
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
+        assert_eq!(s.check_share(1, job_id, 0, now + 3 * 3600, 0x20000000, &en), Err("time-too-new"));
+        assert_eq!(s.check_share(1, job_id, 0, now + 3600, 0x20000000, &en), Err("difficulty-too-low"));
+
+        // With an easy target any nonce passes and the share is reported
+        s.channels.get_mut(&1).unwrap().target = [0xFF; 32];
+        let (share, _) = s.check_share(1, job_id, 42, 1_700_000_001, 0x20000000, &en).unwrap();
+        assert_eq!(share.tpl_id, 5);
+        assert_eq!(share.extranonce.len(), CB_EXTRANONCE_LEN);
+    }
+
+    #[test]
+    fn test_channel_prefix_clear_of_sv1() {
+        let (bus, _) = broadcast::channel(8);
+        let mut s = Session::new(0x0102, bus, 1.0, 0.0);
+        s.setup_done = true;
+        s.open_standard(OpenStandardChannel {
+            req_id: 1,
+            user: "miner".into(),
+            hashrate: F32(1e12),
+            max_target: U256([0xFF; 32]),
+        })
+        .unwrap();
+
+        // 01020000 is what an SV1 connection with this id mines under
+        assert!(!s.channels.contains_key(&0));
+        assert_eq!(&s.channels[&1].extranonce_prefix[..CHANNEL_PREFIX_LEN], [1, 2, 0, 1]);
+    }
+
+    #[test]
+    fn test_channel_ids_run_out() {
+        let (bus, _) = broadcast::channel(8);
+        let mut s = Session::new(1, bus, 1.0, 0.0);
+        s.setup_done = true;
+        let open = || OpenStandardChannel {
+            req_id: 3,
+            user: "miner".into(),
+            hashrate: F32(1e12),
+            max_target: U256([0xFF; 32]),
+        };
+
+        s.channel_seq = u16::MAX - 1;
+        let out = s.open_standard(open()).unwrap();
+        assert_eq!(out[0].mtype, msg_types::OPEN_STANDARD_CHANNEL_OK);
+        assert_eq!(&s.channels[&0xFFFF].extranonce_prefix[..CHANNEL_PREFIX_LEN], [0, 1, 0xFF, 0xFF]);
+
+        // Channel 65536 would share channel 0's extranonce prefix
+        let out = s.open_standard(open()).unwrap();
+        assert_eq!(out[0].mtype, msg_types::OPEN_CHANNEL_ERR);
+        assert_eq!(s.channels.len(), 1);
+    }
+
+    #[test]
+    fn test_retarget() {
+        let (bus, _) = broadcast::channel(64);
+        let mut s = Session::new(1, bus, 1.0, 6.0);
+        let t0 = Instant::now();
+        let diff1 = work::target_from_difficulty(1.0);
+        s.channels.insert(1, Channel { since: t0, ..Channel::new(true, diff1, 1.0, Vec::new()) });
+
+        let accepted = || {
+            let share = Share {
+                tpl_id: 5,
+                version: 0,
+                ntime: 0,
+                nonce: 0,
+                extranonce: Vec::new(),
+                hash: [0; 32],
+                solo: false,
+            };
+            Ok((share, None))
+        };
+
+        // 30 shares in 10s is 30x too fast, so the difficulty goes up 4x
+        let mut out = Vec::new();
+        for i in 0..RETARGET_SHARES {
+            let now = t0 + Duration::from_secs(10) * (i + 1) / RETARGET_SHARES;
+            out = s.share_reply(1, i, accepted(), now).unwrap();
+        }
+        assert_eq!(out.len(), 2);
+        assert_eq!(out[1].mtype, msg_types::SET_TARGET);
+        let set = SetTarget::parse(&out[1].payload).unwrap();
+        assert_eq!(set.max_target.0, work::target_from_difficulty(4.0));
+        assert_eq!(s.channels[&1].target, set.max_target.0);
+
+        // Far too slow now, but never easier than configured
+        let t1 = t0 + Duration::from_secs(100);
+        let out = s.share_reply(1, 99, accepted(), t1).unwrap();
+        assert_eq!(SetTarget::parse(&out[1].payload).unwrap().max_target.0, diff1);
+
+        // Near the wanted rate nothing is sent
+        for i in 0..3 {
+            let now = t1 + Duration::from_secs(10 * (i + 1));
+            assert_eq!(s.share_reply(1, 100 + i as u32, accepted(), now).unwrap().len(), 1);
+        }
+    }
+
+    #[test]
+    fn test_setup_rejects_work_selection() {
+        let (bus, _) = broadcast::channel(8);
+        let mut s = Session::new(1, bus, 0.0, 0.0);
+        let setup = |flags| SetupConn {
+            protocol: PROTO_MINING,
+            min_ver: 2,
+            max_ver: 2,
+            flags,
+            host: "127.0.0.1".into(),
+            port: 34255,
+            vendor: "test".into(),
+            hw_ver: "".into(),
+            firmware: "".into(),
+            device_id: "".into(),
+        };
+
+        let out = s.on_setup(&setup(mining_flags::REQUIRES_WORK_SELECTION).serialize().unwrap()).unwrap();
+        assert_eq!(out[0].mtype, sv2_messages::msg_types::SETUP_CONN_ERR);
+        assert!(!s.setup_done);
+
+        let out = s.on_setup(&setup(0).serialize().unwrap()).unwrap();
+        assert_eq!(out[0].mtype, sv2_messages::msg_types::SETUP_CONN_OK);
+        assert!(s.setup_done);
+    }
+}
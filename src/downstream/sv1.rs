@@ -0,0 +1,385 @@
+//! Stratum V1 translator for legacy miners
+//!
+//! Line-delimited JSON-RPC over plain TCP. Jobs are the same
+//! `MiningJob`s served to SV2 channels: `coinb1`/`coinb2` are the coinbase
+//! parts around the extranonce, extranonce1 is our per-connection prefix
+//! and the miner rolls the rest as extranonce2.
+
+use futures::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, watch};
+use tokio_util::codec::{Framed, LinesCodec};
+use tracing::{debug, info, warn};
+
+use crate::common::{Event, MiningJob, Result, Sv2Error};
+use crate::pool::sv2_messages::CB_EXTRANONCE_LEN;
+use crate::pool::work;
+use super::{check_work, publish, Accepted, CHANNEL_PREFIX_LEN, MAX_JOBS, VERSION_ROLLING_MASK};
+
+const MAX_LINE: usize = 16 * 1024;
+
+pub(super) struct Sv1Session {
+    bus: broadcast::Sender<Event>,
+    difficulty: f64,
+    target: [u8; 32],
+    extranonce1: [u8; CHANNEL_PREFIX_LEN],
+    subscribed: bool,
+    authorized: bool,
+    /// Version bits the miner may roll, zero until `mining.configure`
+    version_mask: u32,
+    jobs: BTreeMap<u32, Arc<MiningJob>>,
+    job_seq: u32,
+    prev_hash: Option<[u8; 32]>,
+}
+
+impl Sv1Session {
+    pub(super) fn new(conn_id: u16, bus: broadcast::Sender<Event>, difficulty: f64) -> Self {
+        // conn_id ‖ 0x0000 is the prefix an SV2 channel 0 would get. SV2
+        // sessions number channels from 1 and never wrap back to 0, and
+        // conn ids are unique among live connections, so no SV2 channel
+        // can share it.
+        let mut extranonce1 = [0u8; CHANNEL_PREFIX_LEN];
+        extranonce1[..2].copy_from_slice(&conn_id.to_be_bytes());
+
+        Self {
+            bus,
+            difficulty,
+            target: work::target_from_difficulty(difficulty),
+            extranonce1,
+            subscribed: false,
+            authorized: false,
+            version_mask: 0,
+            jobs: BTreeMap::new(),
+            job_seq: 0,
+            prev_hash: None,
+        }
+    }
+
+    pub(super) async fn run(
+        mut self,
+        stream: TcpStream,
+        mut jobs: watch::Receiver<Option<Arc<MiningJob>>>,
+    ) -> Result<()> {
+        let mut framed = Framed::new(stream, LinesCodec::new_with_max_length(MAX_LINE));
+
+        let current = jobs.borrow_and_update().clone();
+        if let Some(job) = current {
+            self.on_job(job);
+        }
+
+        loop {
+            tokio::select! {
+                res = framed.next() => {
+                    let Some(line) = res else { return Ok(()) };
+                    let line = line.map_err(|e| Sv2Error::Framing(e.to_string()))?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    for out in self.handle_line(&line)? {
+                        framed.send(out.to_string()).await
+                            .map_err(|e| Sv2Error::Framing(e.to_string()))?;
+                    }
+                }
+
+                res = jobs.changed() => {
+                    if res.is_err() {
+                        return Ok(());
+                    }
+                    let current = jobs.borrow_and_update().clone();
+                    if let Some(job) = current {
+                        for out in self.on_job(job) {
+                            framed.send(out.to_string()).await
+                                .map_err(|e| Sv2Error::Framing(e.to_string()))?;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handle one request, returning the response and any notifications
+    fn handle_line(&mut self, line: &str) -> Result<Vec<Value>> {
+        let req: Value = serde_json::from_str(line)
+            .map_err(|e| Sv2Error::Serialization(format!("sv1 request: {}", e)))?;
+
+        let id = req.get("id").cloned().unwrap_or(Value::Null);
+        let method = req.get("method").and_then(Value::as_str).unwrap_or("");
+        let params = req.get("params").and_then(Value::as_array).cloned().unwrap_or_default();
+
+        debug!("SV1 request: {}", method);
+
+        match method {
+            "mining.subscribe" => {
+                self.subscribed = true;
+                let result = json!([
+                    [["mining.set_difficulty", "1"], ["mining.notify", "1"]],
+                    hex::encode(self.extranonce1),
+                    CB_EXTRANONCE_LEN - CHANNEL_PREFIX_LEN,
+                ]);
+                Ok(vec![response(id, result)])
+            }
+            "mining.authorize" => {
+                let user = params.first().and_then(Value::as_str).unwrap_or("");
+                info!("SV1 worker authorized: {}", user);
+
+                let first = !self.authorized;
+                self.authorized = true;
+
+                let mut out = vec![response(id, json!(true))];
+                if first {
+                    out.push(notification("mining.set_difficulty", json!([self.difficulty])));
+                    if let Some((&job_id, job)) = self.jobs.last_key_value() {
+                        out.push(self.notify(job_id, job, true));
+                    }
+                }
+                Ok(out)
+            }
+            "mining.configure" => Ok(vec![response(id, self.configure(&params))]),
+            "mining.submit" => Ok(vec![self.submit(id, &params)]),
+            "mining.extranonce.subscribe" => Ok(vec![response(id, json!(true))]),
+            other => {
+                warn!("Unsupported SV1 method: {}", other);
+                Ok(vec![error(id, 20, "Unsupported method")])
+            }
+        }
+    }
+
+    /// Negotiate extensions; only version rolling is supported
+    fn configure(&mut self, params: &[Value]) -> Value {
+        let exts: Vec<&str> = params
+            .first()
+            .and_then(Value::as_array)
+            .map(|a| a.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+
+        let mut result = serde_json::Map::new();
+        for ext in exts {
+            if ext != "version-rolling" {
+                result.insert(ext.to_string(), json!(false));
+                continue;
+            }
+
+            let requested = params
+                .get(1)
+                .and_then(|p| p.get("version-rolling.mask"))
+                .and_then(Value::as_str)
+                .and_then(|m| u32::from_str_radix(m, 16).ok())
+                .unwrap_or(u32::MAX);
+
+            self.version_mask = requested & VERSION_ROLLING_MASK;
+            result.insert("version-rolling".into(), json!(true));
+            result.insert(
+                "version-rolling.mask".into(),
+                json!(format!("{:08x}", self.version_mask)),
+            );
+        }
+
+        Value::Object(result)
+    }
+
+    fn submit(&self, id: Value, params: &[Value]) -> Value {
+        if !self.authorized {
+            return error(id, 24, "Unauthorized worker");
+        }
+
+        let res = self.check_submit(params);
+        match res {
+            Ok(accepted) => {
+                debug!("SV1 share accepted: nonce={:08x}", accepted.0.nonce);
+                publish(&self.bus, accepted);
+                response(id, json!(true))
+            }
+            Err("invalid-job-id") => error(id, 21, "Job not found"),
+            Err("difficulty-too-low") => error(id, 23, "Low difficulty share"),
+            Err(code) => error(id, 20, code),
+        }
+    }
+
+    /// `[worker, job_id, extranonce2, ntime, nonce, version_bits?]`
+    fn check_submit(&self, params: &[Value]) -> std::result::Result<Accepted, &'static str> {
+        let field = |i: usize| params.get(i).and_then(Value::as_str);
+        let hex_u32 = |i: usize| field(i).and_then(|s| u32::from_str_radix(s, 16).ok());
+
+        let job_id = field(1)
+            .and_then(|s| u32::from_str_radix(s, 16).ok())
+            .ok_or("invalid-job-id")?;
+        let job = self.jobs.get(&job_id).ok_or("invalid-job-id")?;
+
+        let extranonce2 = field(2)
+            .and_then(|s| hex::decode(s).ok())
+            .ok_or("invalid-extranonce")?;
+        let ntime = hex_u32(3).ok_or("invalid-ntime")?;
+        let nonce = hex_u32(4).ok_or("invalid-nonce")?;
+
+        let version = match params.get(5) {
+            None => job.version,
+            Some(_) => {
+                let bits = hex_u32(5).ok_or("invalid-version")?;
+                if bits & !self.version_mask != 0 {
+                    return Err("invalid-version");
+                }
+                (job.version & !self.version_mask) | bits
+            }
+        };
+
+        let mut extranonce = self.extranonce1.to_vec();
+        extranonce.extend_from_slice(&extranonce2);
+
+        check_work(job, &self.target, extranonce, version, ntime, nonce)
+    }
+
+    /// Record a new job and build the notify for it
+    fn on_job(&mut self, job: Arc<MiningJob>) -> Vec<Value> {
+        self.job_seq = self.job_seq.wrapping_add(1);
+        let job_id = self.job_seq;
+
+        let clean = self.prev_hash != Some(job.prev_hash);
+        if clean {
+            self.jobs.clear();
+            self.prev_hash = Some(job.prev_hash);
+        }
+        self.jobs.insert(job_id, job.clone());
+        while self.jobs.len() > MAX_JOBS {
+            self.jobs.pop_first();
+        }
+
+        if !self.subscribed || !self.authorized {
+            return Vec::new();
+        }
+        vec![self.notify(job_id, &job, clean)]
+    }
+
+    fn notify(&self, job_id: u32, job: &MiningJob, clean: bool) -> Value {
+        let branches: Vec<String> = job.merkle_path.iter().map(hex::encode).collect();
+        notification("mining.notify", json!([
+            format!("{:x}", job_id),
+            sv1_prev_hash(&job.prev_hash),
+            hex::encode(&job.cb_prefix),
+            hex::encode(&job.cb_suffix),
+            branches,
+            format!("{:08x}", job.version),
+            format!("{:08x}", job.bits),
+            format!("{:08x}", job.time),
+            clean,
+        ]))
+    }
+}
+
+/// SV1 sends the prev hash as eight 32-bit words, each byte-swapped
+fn sv1_prev_hash(prev_hash: &[u8; 32]) -> String {
+    let mut out = [0u8; 32];
+    for (dst, src) in out.chunks_mut(4).zip(prev_hash.chunks(4)) {
+        dst.copy_from_slice(src);
+        dst.reverse();
+    }
+    hex::encode(out)
+}
+
+fn response(id: Value, result: Value) -> Value {
+    json!({ "id": id, "result": result, "error": null })
+}
+
+fn error(id: Value, code: i32, msg: &str) -> Value {
+    json!({ "id": id, "result": null, "error": [code, msg, null] })
+}
+
+fn notification(method: &str, params: Value) -> Value {
+    json!({ "id": null, "method": method, "params": params })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_job() -> MiningJob {
+        MiningJob {
+            tpl_id: 3,
+            version: 0x20000000,
+            prev_hash: [7; 32],
+            bits: 0x1d00ffff,
+            time: 1_700_000_000,
+            min_time: 1_699_999_000,
+            cb_prefix: vec![1, 2, 3],
+            cb_suffix: vec![4, 5, 6],
+            extranonce_len: CB_EXTRANONCE_LEN,
+            extranonce_prefix: Vec::new(),
+            merkle_path: vec![[9; 32]],
+            txs: Arc::new(Vec::new()),
+            cb_witness: false,
+            solo: false,
+        }
+    }
+
+    fn call(s: &mut Sv1Session, method: &str, params: Value) -> Vec<Value> {
+        let req = json!({ "id": 1, "method": method, "params": params });
+        s.handle_line(&req.to_string()).unwrap()
+    }
+
+    #[test]
+    fn test_subscribe_authorize_notify() {
+        let (bus, _) = broadcast::channel(8);
+        let mut s = Sv1Session::new(0x0102, bus, 1.0);
+        s.on_job(Arc::new(test_job()));
+
+        let out = call(&mut s, "mining.subscribe", json!(["cgminer/4.11"]));
+        assert_eq!(out[0]["result"][1], "01020000");
+        assert_eq!(out[0]["result"][2], 8);
+
+        let out = call(&mut s, "mining.authorize", json!(["worker", "x"]));
+        assert_eq!(out.len(), 3);
+        assert_eq!(out[1]["method"], "mining.set_difficulty");
+        assert_eq!(out[2]["method"], "mining.notify");
+        assert_eq!(out[2]["params"][2], "010203");
+        assert_eq!(out[2]["params"][8], true);
+
+        // Same tip: no clean_jobs
+        let out = s.on_job(Arc::new(test_job()));
+        assert_eq!(out[0]["params"][0], "2");
+        assert_eq!(out[0]["params"][8], false);
+    }
+
+    #[test]
+    fn test_configure_and_submit() {
+        let (bus, mut rx) = broadcast::channel(8);
+        let mut s = Sv1Session::new(1, bus, 1.0);
+        s.on_job(Arc::new(test_job()));
+        call(&mut s, "mining.subscribe", json!([]));
+        call(&mut s, "mining.authorize", json!(["worker", "x"]));
+
+        let out = call(&mut s, "mining.configure", json!([
+            ["version-rolling"], { "version-rolling.mask": "ffffffff" }
+        ]));
+        assert_eq!(out[0]["result"]["version-rolling.mask"], "1fffe000");
+
+        let submit = |s: &mut Sv1Session, job: &str, vb: &str| {
+            call(s, "mining.submit", json!(["worker", job, "0000000000000000", "6553f100", "0000002a", vb]))
+        };
+
+        assert_eq!(submit(&mut s, "9", "00000000")[0]["error"][0], 21);
+        assert_eq!(submit(&mut s, "1", "00000001")[0]["error"][1], "invalid-version");
+        assert_eq!(submit(&mut s, "1", "00002000")[0]["error"][0], 23);
+        let old = call(&mut s, "mining.submit", json!(["worker", "1", "0000000000000000", "00000000", "0000002a"]));
+        assert_eq!(old[0]["error"][1], "time-too-old");
+
+        s.target = [0xFF; 32];
+        assert_eq!(submit(&mut s, "1", "00002000")[0]["result"], true);
+        match rx.try_recv().unwrap() {
+            Event::Share(share) => {
+                assert_eq!(share.version, 0x20002000);
+                assert_eq!(share.nonce, 0x2a);
+                assert_eq!(&share.extranonce[..4], &[0, 1, 0, 0]);
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_sv1_prev_hash() {
+        let mut h = [0u8; 32];
+        h[..4].copy_from_slice(&[1, 2, 3, 4]);
+        assert!(sv1_prev_hash(&h).starts_with("04030201"));
+    }
+}
@@ -1,11 +1,16 @@
 mod common;
+mod downstream;
 mod node;
 mod pool;
 mod ui;
 
 use common::{Event, CoinbaseOut, Sv2Error, Result};
-use node::{BitcoinNode, BitcoinRpcConfig};
-use pool::{PoolClient, PoolConnConfig};
+use downstream::{DownstreamConfig, MiningServer};
+use node::consensus::{COINBASE_SIGOPS_RESERVE, MAX_BLOCK_SIGOPS_COST};
+use node::rules::{RuleSet, TxRulesConfig};
+use node::selection::{SelectionPolicy, SelectorConfig};
+use node::{BitcoinNode, BitcoinRpcConfig, TemplateProvider, TpConfig};
+use pool::{MiningUpstream, PoolClient, PoolConnConfig, SoloConfig, SoloFallback};
 use ui::Dashboard;
 
 use config::Config;
@@ -19,15 +24,40 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 struct AppConfig {
     bitcoin_node: BitcoinRpcConfig,
     pool: PoolConnConfig,
+    #[serde(default)]
+    template_provider: Option<TpConfig>,
+    #[serde(default)]
+    downstream: Option<DownstreamConfig>,
     jdc: JdcConfig,
     logging: LoggingConfig,
 }
 
+/// Where block templates come from
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum TemplateSource {
+    /// Poll `getblocktemplate` on the Bitcoin node
+    #[default]
+    Rpc,
+    /// Subscribe to an SV2 Template Provider
+    TemplateProvider,
+}
+
 #[derive(Debug, Deserialize)]
 struct JdcConfig {
+    #[serde(default)]
+    template_source: TemplateSource,
     coinbase_outputs: Vec<CoinbaseOutputConfig>,
+    /// sat/vB
     min_fee_rate: f64,
-    max_template_size: usize,
+    /// Weight budget for template transactions
+    max_template_size: u64,
+    #[serde(default)]
+    tx_selection: SelectorConfig,
+    #[serde(default)]
+    tx_rules: TxRulesConfig,
+    #[serde(default)]
+    solo_fallback: SoloConfig,
 }
 
 #[derive(Debug, Deserialize)]
@@ -59,17 +89,44 @@ async fn main() -> Result<()> {
     // Using broadcast channel for fanout pattern (one-to-many)
     let (tx, _) = broadcast::channel::<Event>(100);
 
-    // Spawn Node Actor
-    let node_actor = BitcoinNode::new(
-        config.bitcoin_node.clone(),
-        tx.clone(),
-        coinbase_outputs.clone(),
-    );
-    let node_handle = tokio::spawn(async move {
-        if let Err(e) = node_actor.run().await {
-            error!("Node actor error: {}", e);
+    // Spawn template source actor
+    let node_handle = match config.jdc.template_source {
+        TemplateSource::Rpc => {
+            let node_actor = BitcoinNode::new(
+                config.bitcoin_node.clone(),
+                tx.clone(),
+                tx.subscribe(),
+                coinbase_outputs.clone(),
+                SelectionPolicy {
+                    min_fee_rate: config.jdc.min_fee_rate,
+                    max_weight: config.jdc.max_template_size,
+                    max_sigops: MAX_BLOCK_SIGOPS_COST - COINBASE_SIGOPS_RESERVE,
+                },
+                config.jdc.tx_selection.build(),
+                RuleSet::from_config(&config.jdc.tx_rules)?,
+            );
+            tokio::spawn(async move {
+                if let Err(e) = node_actor.run().await {
+                    error!("Node actor error: {}", e);
+                }
+            })
         }
-    });
+        TemplateSource::TemplateProvider => {
+            let tp_cfg = config.template_provider.clone().ok_or_else(|| Sv2Error::Config(
+                config::ConfigError::Message(
+                    "template_source = \"template_provider\" needs a [template_provider] section".into()
+                )
+            ))?;
+            let tp_actor = TemplateProvider::new(
+                tp_cfg, tx.clone(), tx.subscribe(), coinbase_outputs.clone(),
+            );
+            tokio::spawn(async move {
+                if let Err(e) = tp_actor.run().await {
+                    error!("Template Provider error: {}", e);
+                }
+            })
+        }
+    };
 
     // Spawn Pool Actor
     let pool_actor = PoolClient::new(
@@ -83,6 +140,41 @@ async fn main() -> Result<()> {
         }
     });
 
+    // Spawn the solo fallback if enabled; it needs somewhere to pay to
+    if config.jdc.solo_fallback.enabled {
+        if coinbase_outputs.is_empty() {
+            return Err(Sv2Error::Config(config::ConfigError::Message(
+                "[jdc.solo_fallback] needs coinbase_outputs to mine to".into()
+            )));
+        }
+        let solo = SoloFallback::new(config.jdc.solo_fallback.clone(), tx.clone(), tx.subscribe());
+        tokio::spawn(async move {
+            if let Err(e) = solo.run().await {
+                error!("Solo fallback error: {}", e);
+            }
+        });
+    }
+
+    // Spawn upstream mining channel if the pool has a mining endpoint
+    if config.pool.mining_address.is_some() {
+        let upstream = MiningUpstream::new(config.pool.clone(), tx.clone(), tx.subscribe());
+        tokio::spawn(async move {
+            if let Err(e) = upstream.run().await {
+                error!("Mining upstream error: {}", e);
+            }
+        });
+    }
+
+    // Spawn downstream mining server if configured
+    if let Some(ds_cfg) = config.downstream.clone() {
+        let server = MiningServer::new(ds_cfg, tx.clone(), tx.subscribe());
+        tokio::spawn(async move {
+            if let Err(e) = server.run().await {
+                error!("Mining server error: {}", e);
+            }
+        });
+    }
+
     // Spawn UI Actor (runs in main thread for terminal control)
     let ui_actor = Dashboard::new(tx.subscribe());
     let ui_result = ui_actor.run().await;
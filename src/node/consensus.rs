@@ -0,0 +1,188 @@
+//! Consensus parameters the JDC needs: the block subsidy, block limits and
+//! the difficulty schedule per network
+
+use bitcoincore_rpc::bitcoin;
+use serde::{Deserialize, Serialize};
+
+use crate::pool::work;
+
+const COIN: u64 = 100_000_000;
+
+pub const MAX_BLOCK_SIGOPS_COST: u64 = 80_000;
+/// Sigop cost left for the coinbase, as Bitcoin Core does
+pub const COINBASE_SIGOPS_RESERVE: u64 = 400;
+
+/// Blocks between difficulty adjustments
+pub const RETARGET_INTERVAL: u64 = 2016;
+const TARGET_TIMESPAN: u64 = 14 * 24 * 60 * 60;
+/// Testnet allows a minimum-difficulty block after this long without one
+pub const MIN_DIFFICULTY_GAP: u64 = 20 * 60;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    #[default]
+    Mainnet,
+    Testnet,
+    Signet,
+    Regtest,
+}
+
+impl Network {
+    /// Blocks between subsidy halvings
+    pub fn halving_interval(self) -> u64 {
+        match self {
+            Network::Regtest => 150,
+            _ => 210_000,
+        }
+    }
+
+    /// Easiest allowed target, compact
+    pub fn pow_limit_bits(self) -> u32 {
+        match self {
+            Network::Mainnet | Network::Testnet => 0x1d00ffff,
+            Network::Signet => 0x1e0377ae,
+            Network::Regtest => 0x207fffff,
+        }
+    }
+
+    pub fn retargets(self) -> bool {
+        self != Network::Regtest
+    }
+
+    /// Whether the testnet minimum-difficulty rule applies
+    pub fn min_difficulty_blocks(self) -> bool {
+        self == Network::Testnet
+    }
+}
+
+impl From<bitcoin::Network> for Network {
+    fn from(n: bitcoin::Network) -> Self {
+        match n {
+            bitcoin::Network::Testnet => Network::Testnet,
+            bitcoin::Network::Signet => Network::Signet,
+            bitcoin::Network::Regtest => Network::Regtest,
+            _ => Network::Mainnet,
+        }
+    }
+}
+
+/// New coins a block at `height` may create
+pub fn block_subsidy(height: u64, network: Network) -> u64 {
+    let halvings = height / network.halving_interval();
+    if halvings >= 64 {
+        return 0;
+    }
+    (50 * COIN) >> halvings
+}
+
+/// Check a template's coinbase value against subsidy plus fees and return
+/// the value to build the coinbase with. On a mismatch the lower one wins:
+/// claiming too much makes the block invalid, claiming too little doesn't.
+pub fn coinbase_value(height: u64, network: Network, fees: u64, template_value: u64) -> (u64, bool) {
+    let expected = block_subsidy(height, network) + fees;
+    (template_value.min(expected), template_value == expected)
+}
+
+/// `bits` for the first block of a new period, from the last period's
+/// `bits` and the time it took
+pub fn retarget(bits: u32, timespan: u64, network: Network) -> u32 {
+    let timespan = timespan.clamp(TARGET_TIMESPAN / 4, TARGET_TIMESPAN * 4);
+
+    let mut limbs = to_limbs(&work::target_from_bits(bits));
+    let overflow = mul_limbs(&mut limbs, timespan);
+    div_limbs(&mut limbs, TARGET_TIMESPAN);
+
+    let limit = to_limbs(&work::target_from_bits(network.pow_limit_bits()));
+    if overflow || limbs.iter().rev().cmp(limit.iter().rev()) == std::cmp::Ordering::Greater {
+        return network.pow_limit_bits();
+    }
+    bits_from_target(&limbs)
+}
+
+fn to_limbs(le: &[u8; 32]) -> [u64; 4] {
+    std::array::from_fn(|i| u64::from_le_bytes(le[i * 8..i * 8 + 8].try_into().unwrap()))
+}
+
+/// Whether the product overflowed
+fn mul_limbs(limbs: &mut [u64; 4], m: u64) -> bool {
+    let mut carry = 0u128;
+    for l in limbs.iter_mut() {
+        let v = *l as u128 * m as u128 + carry;
+        *l = v as u64;
+        carry = v >> 64;
+    }
+    carry != 0
+}
+
+fn div_limbs(limbs: &mut [u64; 4], d: u64) {
+    let mut rem = 0u128;
+    for l in limbs.iter_mut().rev() {
+        let v = (rem << 64) | *l as u128;
+        *l = (v / d as u128) as u64;
+        rem = v % d as u128;
+    }
+}
+
+/// Compact encoding, rounding down like Bitcoin Core's `GetCompact`
+fn bits_from_target(limbs: &[u64; 4]) -> u32 {
+    let bits = limbs
+        .iter()
+        .enumerate()
+        .rev()
+        .find(|(_, &l)| l != 0)
+        .map_or(0, |(i, l)| i as u32 * 64 + 64 - l.leading_zeros());
+    let mut size = bits.div_ceil(8);
+
+    // Top three bytes of the target
+    let byte = |n: u32| (limbs[(n / 8) as usize] >> ((n % 8) * 8)) as u8 as u32;
+    let mut mant = (0..3)
+        .filter_map(|k| (size + k).checked_sub(3).filter(|&n| n < size).map(|n| byte(n) << (8 * k)))
+        .sum::<u32>();
+
+    // The mantissa is signed; keep it positive
+    if mant & 0x0080_0000 != 0 {
+        mant >>= 8;
+        size += 1;
+    }
+    mant | size << 24
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subsidy_schedule() {
+        assert_eq!(block_subsidy(0, Network::Mainnet), 5_000_000_000);
+        assert_eq!(block_subsidy(209_999, Network::Mainnet), 5_000_000_000);
+        assert_eq!(block_subsidy(840_000, Network::Mainnet), 312_500_000);
+        assert_eq!(block_subsidy(1_050_000, Network::Testnet), 156_250_000);
+        assert_eq!(block_subsidy(64 * 210_000, Network::Mainnet), 0);
+
+        assert_eq!(block_subsidy(149, Network::Regtest), 5_000_000_000);
+        assert_eq!(block_subsidy(150, Network::Regtest), 2_500_000_000);
+        assert_eq!(block_subsidy(64 * 150, Network::Regtest), 0);
+    }
+
+    #[test]
+    fn test_coinbase_value() {
+        assert_eq!(coinbase_value(840_000, Network::Mainnet, 1_000, 312_501_000), (312_501_000, true));
+        // A template claiming too much is capped
+        assert_eq!(coinbase_value(840_000, Network::Mainnet, 1_000, 5_000_001_000), (312_501_000, false));
+        assert_eq!(coinbase_value(840_000, Network::Mainnet, 1_000, 312_500_000), (312_500_000, false));
+    }
+
+    #[test]
+    fn test_retarget() {
+        // Bitcoin Core's pow_tests
+        assert_eq!(retarget(0x1d00ffff, 1233061996 - 1231006505, Network::Mainnet), 0x1d00ffff);
+        assert_eq!(retarget(0x1c05a3f4, 1279297671 - 1279008237, Network::Mainnet), 0x1c0168fd);
+        assert_eq!(retarget(0x1c387f6f, 1269211443 - 1263163443, Network::Mainnet), 0x1d00e1fd);
+        assert_eq!(retarget(0x1b0404cb, TARGET_TIMESPAN, Network::Mainnet), 0x1b0404cb);
+
+        // Halving the target shifts the mantissa's top bit out
+        assert_eq!(retarget(0x1d00ffff, TARGET_TIMESPAN / 2, Network::Mainnet), 0x1c7fff80);
+        assert_eq!(retarget(0x207fffff, TARGET_TIMESPAN * 4, Network::Regtest), 0x207fffff);
+    }
+}
@@ -0,0 +1,406 @@
+//! Local mempool mirror
+//!
+//! Keeps a copy of the node's mempool between polls and builds templates
+//! from it, so selection isn't limited to what `getblocktemplate` picked.
+//! The first sync reads `getrawmempool true`; after that only new txids
+//! are looked up with `getmempoolentry`, and each body is fetched once
+//! with `getrawtransaction`.
+
+use bitcoincore_rpc::bitcoin::BlockHash;
+use bitcoincore_rpc::{json::GetBlockHeaderResult, Client, RpcApi};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::debug;
+
+use super::consensus::{self, Network, MIN_DIFFICULTY_GAP, RETARGET_INTERVAL};
+use super::{Template, TxEntry};
+use crate::common::{Result, Sv2Error};
+use crate::pool::tx::Transaction;
+
+const SAT_PER_BTC: f64 = 100_000_000.0;
+
+/// Version with no deployment bits signalled
+const BLOCK_VERSION: u32 = 0x2000_0000;
+
+const OP_CHECKSIG: u8 = 0xac;
+const OP_CHECKSIGVERIFY: u8 = 0xad;
+const OP_CHECKMULTISIG: u8 = 0xae;
+const OP_CHECKMULTISIGVERIFY: u8 = 0xaf;
+const OP_1: u8 = 0x51;
+const OP_16: u8 = 0x60;
+const WITNESS_SCALE: u64 = 4;
+
+/// `getrawmempool true` / `getmempoolentry`, the parts we use
+#[derive(Debug, Deserialize)]
+struct MempoolEntry {
+    fees: EntryFees,
+    #[serde(default)]
+    depends: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EntryFees {
+    /// BTC
+    base: f64,
+}
+
+#[derive(Debug, Clone)]
+struct MirrorTx {
+    data: Arc<str>,
+    fee: u64,
+    weight: u64,
+    sigops: u64,
+    depends: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct MempoolMirror {
+    txs: HashMap<String, MirrorTx>,
+    synced: bool,
+}
+
+impl MempoolMirror {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sync with the node and build a template on its tip. `None` when the
+    /// tip moved during the sync, as the mirror may then hold transactions
+    /// that aren't valid on the tip we'd build on.
+    pub(super) fn refresh(&mut self, client: &Client, network: Network) -> Result<Option<Template>> {
+        let tip = client.get_best_block_hash()?;
+        let (added, removed) = self.sync(client)?;
+        debug!("Mempool mirror: {} txs, +{} -{}", self.txs.len(), added, removed);
+
+        if client.get_best_block_hash()? != tip {
+            return Ok(None);
+        }
+        self.template(client, network, tip).map(Some)
+    }
+
+    /// Returns the number of transactions added and removed
+    fn sync(&mut self, client: &Client) -> Result<(usize, usize)> {
+        let before = self.txs.len();
+        let mut added = 0;
+
+        if !self.synced {
+            let all: HashMap<String, MempoolEntry> =
+                client.call("getrawmempool", &[serde_json::json!(true)])?;
+            self.txs.retain(|txid, _| all.contains_key(txid));
+            let removed = before - self.txs.len();
+
+            for (txid, entry) in all {
+                if !self.txs.contains_key(&txid) && self.insert(client, txid, entry) {
+                    added += 1;
+                }
+            }
+            self.synced = true;
+            return Ok((added, removed));
+        }
+
+        let ids: Vec<String> = client.call("getrawmempool", &[serde_json::json!(false)])?;
+        let live: HashSet<&String> = ids.iter().collect();
+        self.txs.retain(|txid, _| live.contains(txid));
+        let removed = before - self.txs.len();
+
+        let fresh: Vec<String> = ids.iter().filter(|t| !self.txs.contains_key(*t)).cloned().collect();
+        for txid in fresh {
+            // Gone since the listing
+            let Ok(entry) = client.call::<MempoolEntry>("getmempoolentry", &[serde_json::json!(&txid)]) else {
+                continue;
+            };
+            if self.insert(client, txid, entry) {
+                added += 1;
+            }
+        }
+        Ok((added, removed))
+    }
+
+    /// Fetch and add one transaction; false if it's gone or unreadable
+    fn insert(&mut self, client: &Client, txid: String, entry: MempoolEntry) -> bool {
+        let Ok(data) = client.call::<String>("getrawtransaction", &[serde_json::json!(txid)]) else {
+            return false;
+        };
+        let parsed = hex::decode(&data)
+            .map_err(|e| Sv2Error::Codec(e.to_string()))
+            .and_then(|raw| Transaction::parse(&raw));
+        let tx = match parsed {
+            Ok(tx) => tx,
+            Err(e) => {
+                debug!("Skipping mempool tx {}: {}", txid, e);
+                return false;
+            }
+        };
+
+        self.txs.insert(txid, MirrorTx {
+            data: data.into(),
+            fee: (entry.fees.base * SAT_PER_BTC).round() as u64,
+            weight: tx.weight() as u64,
+            sigops: sigop_cost(&tx),
+            depends: entry.depends,
+        });
+        true
+    }
+
+    /// Every transaction, parents before children, as GBT would list them
+    fn entries(&self) -> Vec<TxEntry> {
+        fn depth(txid: &str, txs: &HashMap<String, MirrorTx>, memo: &mut HashMap<String, usize>) -> usize {
+            if let Some(&d) = memo.get(txid) {
+                return d;
+            }
+            let d = txs[txid]
+                .depends
+                .iter()
+                .filter(|p| txs.contains_key(*p))
+                .map(|p| depth(p, txs, memo) + 1)
+                .max()
+                .unwrap_or(0);
+            memo.insert(txid.to_string(), d);
+            d
+        }
+
+        let mut memo = HashMap::new();
+        let mut order: Vec<(usize, &String)> =
+            self.txs.keys().map(|t| (depth(t, &self.txs, &mut memo), t)).collect();
+        order.sort_unstable();
+
+        let index: HashMap<&String, usize> = order.iter().enumerate().map(|(i, (_, t))| (*t, i)).collect();
+        order
+            .iter()
+            .map(|(_, txid)| {
+                let tx = &self.txs[*txid];
+                TxEntry {
+                    data: tx.data.clone(),
+                    txid: (*txid).clone(),
+                    fee: Some(tx.fee),
+                    depends: tx.depends.iter().filter_map(|p| index.get(p)).map(|i| i + 1).collect(),
+                    weight: tx.weight,
+                    sigops: tx.sigops,
+                }
+            })
+            .collect()
+    }
+
+    fn template(&self, client: &Client, network: Network, tip: BlockHash) -> Result<Template> {
+        let header = client.get_block_header_info(&tip)?;
+        let height = header.height as u64 + 1;
+        let median_time = header.median_time.unwrap_or(header.time) as u64;
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let min_time = median_time + 1;
+        let cur_time = now.max(min_time);
+
+        let bits = next_bits(client, &header, network, cur_time)?;
+
+        let txs = self.entries();
+        let fees: u64 = txs.iter().filter_map(|tx| tx.fee).sum();
+
+        Ok(Template {
+            version: BLOCK_VERSION,
+            prev_hash: tip.to_string(),
+            txs,
+            coinbase_val: consensus::block_subsidy(height, network) + fees,
+            min_time,
+            cur_time,
+            bits: format!("{:08x}", bits),
+            height,
+            witness_commitment: None,
+        })
+    }
+}
+
+/// `bits` for the block after `tip`, as `GetNextWorkRequired` works it out
+fn next_bits(client: &Client, tip: &GetBlockHeaderResult, network: Network, time: u64) -> Result<u32> {
+    let parse = |h: &GetBlockHeaderResult| {
+        u32::from_str_radix(&h.bits, 16).map_err(|_| Sv2Error::Serialization(format!("block bits {}", h.bits)))
+    };
+    let height = tip.height as u64 + 1;
+    let bits = parse(tip)?;
+
+    if !network.retargets() {
+        return Ok(bits);
+    }
+
+    if height % RETARGET_INTERVAL == 0 {
+        let first = client.get_block_header_info(&client.get_block_hash(height - RETARGET_INTERVAL)?)?;
+        let timespan = (tip.time as u64).saturating_sub(first.time as u64);
+        return Ok(consensus::retarget(bits, timespan, network));
+    }
+
+    if network.min_difficulty_blocks() {
+        let limit = network.pow_limit_bits();
+        if time > tip.time as u64 + MIN_DIFFICULTY_GAP {
+            return Ok(limit);
+        }
+        // The last block that wasn't a minimum-difficulty one
+        let mut header = tip.clone();
+        while parse(&header)? == limit && header.height as u64 % RETARGET_INTERVAL != 0 {
+            let Some(prev) = header.previous_block_hash else { break };
+            header = client.get_block_header_info(&prev)?;
+        }
+        return parse(&header);
+    }
+
+    Ok(bits)
+}
+
+/// Opcodes of `script` with their push data, up to the end or the first
+/// malformed push
+fn ops(script: &[u8]) -> impl Iterator<Item = (u8, &[u8])> + '_ {
+    let mut pos = 0;
+    std::iter::from_fn(move || {
+        let op = *script.get(pos)?;
+        pos += 1;
+        let (len, skip) = match op {
+            0x01..=0x4b => (op as usize, 0),
+            0x4c => (*script.get(pos)? as usize, 1),
+            0x4d => (u16::from_le_bytes(script.get(pos..pos + 2)?.try_into().ok()?) as usize, 2),
+            0x4e => (u32::from_le_bytes(script.get(pos..pos + 4)?.try_into().ok()?) as usize, 4),
+            _ => (0, 0),
+        };
+        pos += skip;
+        let data = script.get(pos..pos + len)?;
+        pos += len;
+        Some((op, data))
+    })
+}
+
+/// Bitcoin Core's `GetSigOpCount`. Inaccurate counting charges every
+/// multisig the maximum of 20 keys.
+fn count_sigops(script: &[u8], accurate: bool) -> u64 {
+    let mut n = 0;
+    let mut last = 0xff;
+    for (op, _) in ops(script) {
+        n += match op {
+            OP_CHECKSIG | OP_CHECKSIGVERIFY => 1,
+            OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY if accurate && (OP_1..=OP_16).contains(&last) => {
+                (last - OP_1 + 1) as u64
+            }
+            OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY => 20,
+            _ => 0,
+        };
+        last = op;
+    }
+    n
+}
+
+/// The last push of a push-only script
+fn last_push(script: &[u8]) -> Option<&[u8]> {
+    let mut last = None;
+    for (op, data) in ops(script) {
+        if op > OP_16 {
+            return None;
+        }
+        last = Some(data);
+    }
+    last
+}
+
+/// Sigop cost of a witness, guessing the spend type from its shape
+fn witness_sigops(witness: &[Vec<u8>]) -> u64 {
+    // An annex marks a taproot spend, which has no sigop cost
+    let items = match witness {
+        [rest @ .., annex] if !rest.is_empty() && annex.first() == Some(&0x50) => rest,
+        _ => witness,
+    };
+    match items {
+        [] => 0,
+        // P2WPKH
+        [_, pubkey] if pubkey.len() == 33 => 1,
+        // Taproot key path
+        [sig] if sig.len() == 64 || sig.len() == 65 => 0,
+        // Taproot script path: the last item is a control block
+        [_, .., cb] if cb.len() >= 33 && (cb.len() - 33) % 32 == 0 && cb[0] & 0xfe == 0xc0 => 0,
+        // P2WSH witness script
+        [.., script] => count_sigops(script, true),
+    }
+}
+
+/// Sigop cost without knowing the prevouts, so an upper bound: any
+/// push-only scriptSig is taken for a P2SH redeem script and witness
+/// spends are told apart by shape
+fn sigop_cost(tx: &Transaction) -> u64 {
+    let legacy: u64 = tx.inputs.iter().map(|i| count_sigops(&i.script_sig, false)).sum::<u64>()
+        + tx.outputs.iter().map(|o| count_sigops(&o.script_pubkey, false)).sum::<u64>();
+
+    let p2sh: u64 = tx
+        .inputs
+        .iter()
+        .filter_map(|i| last_push(&i.script_sig))
+        .map(|redeem| count_sigops(redeem, true))
+        .sum();
+
+    let witness: u64 = tx.inputs.iter().map(|i| witness_sigops(&i.witness)).sum();
+
+    (legacy + p2sh) * WITNESS_SCALE + witness
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::tx::{TxIn, TxOut};
+
+    fn input(script_sig: Vec<u8>, witness: Vec<Vec<u8>>) -> TxIn {
+        TxIn { prev_txid: [0; 32], prev_vout: 0, script_sig, sequence: 0xffff_ffff, witness }
+    }
+
+    fn mirror_tx(fee: u64, depends: &[&str]) -> MirrorTx {
+        MirrorTx {
+            data: "".into(),
+            fee,
+            weight: 400,
+            sigops: 0,
+            depends: depends.iter().map(|d| d.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_sigops() {
+        // 2-of-3 bare multisig output, P2PKH output
+        let multisig = [&[0x52][..], &[0x21], &[2; 33], &[0x21], &[2; 33], &[0x21], &[2; 33], &[0x53, OP_CHECKMULTISIG]].concat();
+        let p2pkh = [&[0x76, 0xa9, 0x14][..], &[0; 20], &[0x88, OP_CHECKSIG]].concat();
+        assert_eq!(count_sigops(&multisig, false), 20);
+        assert_eq!(count_sigops(&multisig, true), 3);
+
+        // P2SH-wrapped 2-of-3, P2WPKH, taproot key path
+        let redeem_push = [&[0x4c, multisig.len() as u8][..], &multisig].concat();
+        let tx = Transaction {
+            version: 2,
+            inputs: vec![
+                input([&[0x00, 0x47][..], &[0x30; 0x47], &redeem_push].concat(), vec![]),
+                input(vec![], vec![vec![0x30; 71], vec![2; 33]]),
+                input(vec![], vec![vec![1; 64]]),
+            ],
+            outputs: vec![
+                TxOut { value: 0, script_pubkey: multisig.clone() },
+                TxOut { value: 0, script_pubkey: p2pkh },
+            ],
+            locktime: 0,
+        };
+        // Legacy 20 + 1 and redeem script 3, scaled, plus one P2WPKH
+        assert_eq!(sigop_cost(&tx), (21 + 3) * 4 + 1);
+
+        // A P2WSH script counts accurately, a taproot script path doesn't count
+        assert_eq!(witness_sigops(&[vec![], vec![0x30; 71], multisig.clone()]), 3);
+        assert_eq!(witness_sigops(&[vec![1; 64], multisig, [&[0xc0][..], &[2; 32]].concat()]), 0);
+    }
+
+    #[test]
+    fn test_entries_order() {
+        let mut mirror = MempoolMirror::new();
+        mirror.txs.insert("cc".into(), mirror_tx(3, &["bb"]));
+        mirror.txs.insert("bb".into(), mirror_tx(2, &["aa", "confirmed"]));
+        mirror.txs.insert("aa".into(), mirror_tx(1, &[]));
+        mirror.txs.insert("dd".into(), mirror_tx(4, &["aa"]));
+
+        let entries = mirror.entries();
+        let txids: Vec<&str> = entries.iter().map(|e| e.txid.as_str()).collect();
+        assert_eq!(txids, vec!["aa", "bb", "dd", "cc"]);
+        // 1-based, like GBT, and only parents still in the mempool
+        assert_eq!(entries[1].depends, vec![1]);
+        assert_eq!(entries[3].depends, vec![2]);
+        assert_eq!(entries[3].fee, Some(3));
+    }
+}
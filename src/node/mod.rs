@@ -1,11 +1,28 @@
+pub mod consensus;
+pub mod mempool;
+pub mod rules;
+pub mod selection;
+pub mod td_messages;
+pub mod template_provider;
+
+pub use template_provider::{TemplateProvider, TpConfig};
+
+use bitcoincore_rpc::bitcoin::{hashes::Hash, BlockHash};
 use bitcoincore_rpc::{Auth, Client, RpcApi};
 use serde::{Deserialize, Serialize};
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::broadcast;
+use tokio::task::{self, JoinHandle};
 use tokio::time;
 use tracing::{debug, error, info, warn};
 
-use crate::common::{Event, CoinbaseOut, Sv2Error, Result};
+use crate::common::{Event, CoinbaseOut, PoolCoinbase, Solution, Sv2Error, Result, TplHeader, TplSnapshot};
+use consensus::Network;
+use mempool::MempoolMirror;
+use rules::RuleSet;
+use selection::{AncestorFeerate, DropReason, SelectionPolicy, TxSelector};
+use template_provider::coinbase_reserve;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BitcoinRpcConfig {
@@ -13,15 +30,40 @@ pub struct BitcoinRpcConfig {
     pub rpc_user: String,
     pub rpc_password: String,
     pub poll_interval: u64,
+    /// Fee rate floor (sat/vB) on top of `[jdc].min_fee_rate`; the
+    /// stricter one applies
+    #[serde(default)]
     pub min_fee_rate: f64,
+    #[serde(default)]
+    pub template_mode: TemplateMode,
+}
+
+/// Where RPC templates come from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TemplateMode {
+    /// The node's `getblocktemplate`
+    #[default]
+    Gbt,
+    /// Our own, from a local mirror of the node's mempool
+    Mempool,
 }
 
 pub struct BitcoinNode {
     cfg: BitcoinRpcConfig,
-    rpc: Option<Client>,
+    rpc: Option<Arc<Client>>,
     bus: broadcast::Sender<Event>,
+    bus_rx: broadcast::Receiver<Event>,
     outputs: Vec<CoinbaseOut>,
-    last_height: u64,
+    policy: SelectionPolicy,
+    /// The pool's coinbase requirements, once it has sent them
+    pool_cb: Option<PoolCoinbase>,
+    selector: Box<dyn TxSelector>,
+    rules: RuleSet,
+    mirror: Option<MempoolMirror>,
+    network: Network,
+    /// Best block as of the last poll
+    tip: Option<BlockHash>,
     tpl_seq: u64,
 }
 
@@ -29,14 +71,40 @@ impl BitcoinNode {
     pub fn new(
         cfg: BitcoinRpcConfig,
         bus: broadcast::Sender<Event>,
+        bus_rx: broadcast::Receiver<Event>,
         outputs: Vec<CoinbaseOut>,
+        policy: SelectionPolicy,
+        selector: Box<dyn TxSelector>,
+        rules: RuleSet,
     ) -> Self {
+        let policy = SelectionPolicy {
+            min_fee_rate: policy.min_fee_rate.max(cfg.min_fee_rate),
+            ..policy
+        };
+
+        // A mempool isn't ordered for mining the way a template is
+        let selector = match cfg.template_mode {
+            TemplateMode::Mempool if selector.name() == "gbt" => {
+                info!("Mempool mirror: selecting by ancestor fee rate");
+                Box::new(AncestorFeerate)
+            }
+            _ => selector,
+        };
+        let mirror = (cfg.template_mode == TemplateMode::Mempool).then(MempoolMirror::new);
+
         Self {
             cfg,
             rpc: None,
             bus,
+            bus_rx,
             outputs,
-            last_height: 0,
+            policy,
+            pool_cb: None,
+            selector,
+            rules,
+            mirror,
+            network: Network::default(),
+            tip: None,
             tpl_seq: 0,
         }
     }
@@ -54,15 +122,63 @@ impl BitcoinNode {
         info!("Connected to {}", self.cfg.rpc_url);
 
         let mut ticker = time::interval(Duration::from_secs(self.cfg.poll_interval));
+        // RPC work of the poll in progress, off the runtime so a mempool
+        // sync never holds up block submission
+        let mut polling: Option<PollTask> = None;
 
         loop {
             tokio::select! {
-                _ = ticker.tick() => {
-                    if let Err(e) = self.poll_template().await {
+                _ = ticker.tick(), if polling.is_none() => {
+                    match self.start_poll() {
+                        Ok(task) => polling = Some(task),
+                        Err(e) => {
+                            error!("Template poll error: {}", e);
+                            let _ = self.bus.send(Event::TemplateErr(e.to_string()));
+                        }
+                    }
+                }
+
+                res = async { polling.as_mut().unwrap().await }, if polling.is_some() => {
+                    polling = None;
+                    let polled = match res {
+                        Ok((mirror, polled)) => {
+                            self.mirror = mirror;
+                            polled
+                        }
+                        Err(e) => {
+                            // The mirror went down with the task; start over
+                            if self.cfg.template_mode == TemplateMode::Mempool {
+                                self.mirror = Some(MempoolMirror::new());
+                            }
+                            Err(Sv2Error::InvalidState(format!("template poll task: {}", e)))
+                        }
+                    };
+                    if let Err(e) = self.on_polled(polled) {
                         error!("Template poll error: {}", e);
                         let _ = self.bus.send(Event::TemplateErr(e.to_string()));
                     }
                 }
+
+                ev = self.bus_rx.recv() => {
+                    match ev {
+                        Ok(Event::BlockFound(sol)) => {
+                            if let Err(e) = self.start_submit(sol) {
+                                error!("submitblock failed: {}", e);
+                            }
+                        }
+                        Ok(Event::PoolCoinbase(cb)) => {
+                            self.pool_cb = Some(cb);
+                        }
+                        Ok(Event::Shutdown) | Err(broadcast::error::RecvError::Closed) => {
+                            info!("Bitcoin RPC handler shutting down");
+                            return Ok(());
+                        }
+                        Err(broadcast::error::RecvError::Lagged(n)) => {
+                            warn!("Bitcoin RPC handler missed {} events", n);
+                        }
+                        _ => {}
+                    }
+                }
             }
         }
     }
@@ -78,26 +194,47 @@ impl BitcoinNode {
         
         info!("Chain: {}, height: {}", chain.chain, chain.blocks);
 
-        self.last_height = chain.blocks;
-        self.rpc = Some(client);
+        self.network = chain.chain.into();
+        self.tip = Some(chain.best_block_hash);
+        self.rpc = Some(Arc::new(client));
         Ok(())
     }
 
-    async fn poll_template(&mut self) -> Result<()> {
-        let client = self.rpc.as_ref().ok_or_else(|| {
+    /// The selection policy less the coinbase space the pool wants kept free
+    fn policy(&self) -> SelectionPolicy {
+        let Some(pool) = &self.pool_cb else {
+            return self.policy;
+        };
+        let c = coinbase_reserve(&self.outputs, Some(pool));
+        SelectionPolicy {
+            max_weight: self.policy.max_weight.saturating_sub(4 * c.max_extra_size as u64),
+            max_sigops: self.policy.max_sigops.saturating_sub(c.max_extra_sigops as u64),
+            ..self.policy
+        }
+    }
+
+    /// Run the poll's RPC calls on the blocking pool. The mirror goes with
+    /// them and comes back with the result.
+    fn start_poll(&mut self) -> Result<PollTask> {
+        let client = self.rpc.clone().ok_or_else(|| {
             Sv2Error::PoolConnection("RPC not ready".into())
         })?;
+        let mut mirror = self.mirror.take();
+        let network = self.network;
 
-        let chain = client.get_blockchain_info()?;
-        let h = chain.blocks;
-
-        if h > self.last_height {
-            info!("New block at height {}", h);
-            self.last_height = h;
-        }
+        Ok(task::spawn_blocking(move || {
+            let polled = poll(&client, mirror.as_mut(), network);
+            (mirror, polled)
+        }))
+    }
 
-        let tpl = match self.fetch_template() {
-            Ok(t) => t,
+    fn on_polled(&mut self, polled: Result<Option<Template>>) -> Result<()> {
+        let tpl = match polled {
+            Ok(Some(tpl)) => tpl,
+            Ok(None) => {
+                debug!("Tip moved during mempool sync, retrying next poll");
+                return Ok(());
+            }
             Err(e) => {
                 warn!("Template fetch failed: {}", e);
                 return Err(e);
@@ -106,47 +243,122 @@ impl BitcoinNode {
 
         debug!("Template: height={}, txs={}", tpl.height, tpl.txs.len());
 
-        let fees: u64 = tpl.txs.iter().filter_map(|tx| tx.fee).sum();
+        let report = self.rules.apply(&tpl.txs)?;
+        let hits: Vec<String> = report
+            .hits
+            .iter()
+            .filter(|(_, n)| *n > 0)
+            .map(|(rule, n)| format!("{}={}", rule, n))
+            .collect();
+        if !hits.is_empty() {
+            info!("Rule hits: {}", hits.join(", "));
+        }
 
-        let _ = self.bus.send(Event::NewTemplate {
-            height: tpl.height,
-            txs: tpl.txs.len(),
-            fees,
-        });
+        let sel = selection::run(&tpl.txs, self.selector.as_ref(), &report.verdicts, &self.policy());
+        for (i, reason) in &sel.dropped {
+            debug!("Dropped tx {}: {}", tpl.txs[*i].txid, reason);
+        }
+        if !sel.dropped.is_empty() {
+            info!(
+                "Selected {}/{} txs ({}), weight={}: dropped {} by rules, {} not selected, {} below fee rate, {} over weight, {} over sigops, {} missing parents",
+                sel.kept.len(),
+                tpl.txs.len(),
+                self.selector.name(),
+                sel.weight,
+                sel.count(|r| matches!(r, DropReason::Rule(_))),
+                sel.count(|r| matches!(r, DropReason::NotSelected)),
+                sel.count(|r| matches!(r, DropReason::LowFeeRate(_))),
+                sel.count(|r| matches!(r, DropReason::OverWeight)),
+                sel.count(|r| matches!(r, DropReason::OverSigops)),
+                sel.count(|r| matches!(r, DropReason::MissingParent(_))),
+            );
+        }
 
         self.tpl_seq += 1;
-        let tpl_id = self.tpl_seq;
+        let snapshot = tpl.snapshot(self.tpl_seq, self.network, &sel.kept)?;
 
-        let raw_txs: Vec<Vec<u8>> = tpl
-            .txs
-            .iter()
-            .filter_map(|tx| hex::decode(&tx.data).ok())
-            .collect();
+        // The tip is the one this template builds on, so the two always
+        // agree. Compared by hash so a reorg to a chain of the same height
+        // counts.
+        let tip = BlockHash::from_byte_array(snapshot.header.prev_hash);
+        if self.tip != Some(tip) {
+            info!("New tip {} at height {}", tip, snapshot.height - 1);
+            self.tip = Some(tip);
+            let _ = self.bus.send(Event::NewPrevHash {
+                height: snapshot.height,
+                prev_hash: snapshot.header.prev_hash,
+            });
+        }
+
+        let _ = self.bus.send(Event::NewTemplate {
+            height: snapshot.height,
+            txs: snapshot.txs.len(),
+            fees: snapshot.fees,
+        });
 
         let _ = self.bus.send(Event::DeclareJob {
-            tpl_id,
+            template: Arc::new(snapshot),
             outputs: self.outputs.clone(),
-            txs: raw_txs,
         });
 
         Ok(())
     }
 
-    fn fetch_template(&self) -> Result<Template> {
-        let client = self.rpc.as_ref().ok_or_else(|| {
+    /// Submit on the blocking pool so a slow node never stalls the loop.
+    /// The outcome is logged from there.
+    fn start_submit(&self, sol: Solution) -> Result<()> {
+        let client = self.rpc.clone().ok_or_else(|| {
             Sv2Error::PoolConnection("RPC not ready".into())
         })?;
 
-        let raw: serde_json::Value = client.call(
-            "getblocktemplate",
-            &[serde_json::json!({ "rules": ["segwit"] })],
-        )?;
+        task::spawn_blocking(move || {
+            if let Err(e) = submit_block(&client, &sol) {
+                error!("submitblock failed: {}", e);
+            }
+        });
+        Ok(())
+    }
+}
+
+fn submit_block(client: &Client, sol: &Solution) -> Result<()> {
+    info!("Submitting block for template {} to node", sol.tpl_id);
 
-        let tpl: Template = serde_json::from_value(raw)
-            .map_err(|e| Sv2Error::Serialization(e.to_string()))?;
+    // null on acceptance, otherwise a BIP22 rejection reason
+    let res: serde_json::Value = client.call(
+        "submitblock",
+        &[serde_json::json!(hex::encode(&sol.block))],
+    )?;
 
-        Ok(tpl)
+    match res.as_str() {
+        None => info!("Block accepted by node"),
+        Some(reason) => warn!("Block rejected by node: {}", reason),
     }
+    Ok(())
+}
+
+/// A poll running on the blocking pool; the mirror comes back with its
+/// result
+type PollTask = JoinHandle<(Option<MempoolMirror>, Result<Option<Template>>)>;
+
+/// A template on the node's tip, from the mirror if there is one. `None`
+/// when the tip moved during a mempool sync.
+fn poll(client: &Client, mirror: Option<&mut MempoolMirror>, network: Network) -> Result<Option<Template>> {
+    match mirror {
+        Some(mirror) => mirror.refresh(client, network),
+        None => fetch_template(client).map(Some),
+    }
+}
+
+fn fetch_template(client: &Client) -> Result<Template> {
+    let raw: serde_json::Value = client.call(
+        "getblocktemplate",
+        &[serde_json::json!({ "rules": ["segwit"] })],
+    )?;
+
+    let tpl: Template = serde_json::from_value(raw)
+        .map_err(|e| Sv2Error::Serialization(e.to_string()))?;
+
+    Ok(tpl)
 }
 
 #[derive(Debug, Deserialize)]
@@ -158,22 +370,163 @@ struct Template {
     txs: Vec<TxEntry>,
     #[serde(rename = "coinbasevalue")]
     coinbase_val: u64,
-    target: String,
     #[serde(rename = "mintime")]
     min_time: u64,
     #[serde(rename = "curtime")]
     cur_time: u64,
     bits: String,
     height: u64,
+    #[serde(rename = "default_witness_commitment", default)]
+    witness_commitment: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
-struct TxEntry {
-    data: String,
-    txid: String,
-    hash: String,
-    fee: Option<u64>,
+impl Template {
+    /// Decode everything a declaration needs in one go, so a template with
+    /// any bad field is dropped whole. Only the transactions at `keep` make
+    /// it into the snapshot.
+    fn snapshot(&self, id: u64, network: Network, keep: &[usize]) -> Result<TplSnapshot> {
+        let bad = |what: &str| Sv2Error::Serialization(format!("template {}", what));
+
+        let mut prev_hash = [0u8; 32];
+        hex::decode_to_slice(&self.prev_hash, &mut prev_hash).map_err(|_| bad("previousblockhash"))?;
+        prev_hash.reverse();
+
+        let txs = keep
+            .iter()
+            .map(|&i| hex::decode(self.txs[i].data.as_bytes()).map_err(|_| bad("transaction data")))
+            .collect::<Result<Vec<_>>>()?;
+
+        // GBT's commitment covers its full transaction list, in its order, only
+        let witness_commitment = match &self.witness_commitment {
+            Some(h) if keep.iter().copied().eq(0..self.txs.len()) => {
+                Some(hex::decode(h).map_err(|_| bad("default_witness_commitment"))?)
+            }
+            _ => None,
+        };
+
+        let all_fees: u64 = self.txs.iter().filter_map(|tx| tx.fee).sum();
+        let fees: u64 = keep.iter().filter_map(|&i| self.txs[i].fee).sum();
+        let (value, consistent) =
+            consensus::coinbase_value(self.height, network, all_fees, self.coinbase_val);
+        if !consistent {
+            warn!(
+                "Template coinbasevalue {} isn't subsidy plus fees at height {}, using {}",
+                self.coinbase_val, self.height, value
+            );
+        }
+        let coinbase_value = value.saturating_sub(all_fees - fees);
+
+        Ok(TplSnapshot {
+            id,
+            height: self.height,
+            header: TplHeader {
+                version: self.version,
+                prev_hash,
+                bits: u32::from_str_radix(&self.bits, 16).map_err(|_| bad("bits"))?,
+                time: self.cur_time as u32,
+            },
+            min_time: self.min_time as u32,
+            txs: Arc::new(txs),
+            fees,
+            coinbase_value,
+            witness_commitment,
+        })
+    }
+}
+
+/// A `getblocktemplate` transaction
+#[derive(Debug, Clone, Deserialize)]
+pub struct TxEntry {
+    /// Hex, shared so views of the list are cheap to build
+    pub data: Arc<str>,
+    pub txid: String,
+    pub fee: Option<u64>,
+    /// 1-based indexes of in-template parents
     #[serde(default)]
-    depends: Vec<usize>,
-    weight: u64,
+    pub depends: Vec<usize>,
+    pub weight: u64,
+    /// Sigop cost
+    #[serde(default)]
+    pub sigops: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gbt(tx_data: &str) -> Template {
+        serde_json::from_value(serde_json::json!({
+            "version": 0x20000000,
+            "previousblockhash": format!("{}01", "00".repeat(31)),
+            "transactions": [{
+                "data": tx_data, "txid": "", "hash": "", "fee": 1000, "weight": 400,
+            }],
+            "coinbasevalue": 312_501_000u64,
+            "mintime": 1_700_000_000u64,
+            "curtime": 1_700_000_100u64,
+            "bits": "1d00ffff",
+            "height": 840_000,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let s = gbt("abcd").snapshot(7, Network::Mainnet, &[0]).unwrap();
+        assert_eq!(s.id, 7);
+        assert_eq!(s.height, 840_000);
+        assert_eq!(s.header.prev_hash[0], 0x01);
+        assert_eq!(s.header.bits, 0x1d00ffff);
+        assert_eq!(s.header.time, 1_700_000_100);
+        assert_eq!(s.min_time, 1_700_000_000);
+        assert_eq!(*s.txs, vec![vec![0xab, 0xcd]]);
+        assert_eq!((s.fees, s.coinbase_value), (1000, 312_501_000));
+        assert_eq!(s.witness_commitment, None);
+
+        // One bad transaction drops the whole template
+        assert!(gbt("xyz").snapshot(8, Network::Mainnet, &[0]).is_err());
+
+        // Dropped transactions take their fees with them
+        let s = gbt("abcd").snapshot(9, Network::Mainnet, &[]).unwrap();
+        assert!(s.txs.is_empty());
+        assert_eq!((s.fees, s.coinbase_value), (0, 312_500_000));
+    }
+
+    #[test]
+    fn test_tip_from_template() {
+        let (bus, mut rx) = broadcast::channel(16);
+        let cfg = BitcoinRpcConfig {
+            rpc_url: String::new(),
+            rpc_user: String::new(),
+            rpc_password: String::new(),
+            poll_interval: 1,
+            min_fee_rate: 0.0,
+            template_mode: TemplateMode::Gbt,
+        };
+        let policy = SelectionPolicy { min_fee_rate: 0.0, max_weight: 4_000_000, max_sigops: 80_000 };
+        let mut node = BitcoinNode::new(
+            cfg, bus.clone(), bus.subscribe(), Vec::new(), policy,
+            Box::new(selection::GbtOrder), RuleSet::default(),
+        );
+
+        node.on_polled(Ok(Some(gbt("abcd")))).unwrap();
+        let Ok(Event::NewPrevHash { height, prev_hash }) = rx.try_recv() else {
+            panic!("no NewPrevHash");
+        };
+        assert_eq!(height, 840_000);
+        assert!(matches!(rx.try_recv(), Ok(Event::NewTemplate { .. })));
+        let Ok(Event::DeclareJob { template, .. }) = rx.try_recv() else {
+            panic!("no DeclareJob");
+        };
+        assert_eq!(template.header.prev_hash, prev_hash);
+
+        // Same tip, only the template goes out
+        node.on_polled(Ok(Some(gbt("abcd")))).unwrap();
+        assert!(matches!(rx.try_recv(), Ok(Event::NewTemplate { .. })));
+
+        // Tip moved during a mempool sync: nothing to announce
+        while rx.try_recv().is_ok() {}
+        node.on_polled(Ok(None)).unwrap();
+        assert!(rx.try_recv().is_err());
+    }
 }
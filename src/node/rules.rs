@@ -0,0 +1,365 @@
+//! Transaction filtering rules
+//!
+//! Operator policy applied to template transactions ahead of selection:
+//! deny lists by txid, output script, address and output type, and an
+//! allow list of txids that go in regardless of strategy and fee rate.
+//! Rules only mark transactions; `selection::select` keeps the template
+//! consistent by dropping descendants of anything denied, and forcing a
+//! transaction forces its ancestors too.
+
+use bitcoincore_rpc::bitcoin::{address::NetworkUnchecked, Address};
+use serde::Deserialize;
+use std::collections::HashSet;
+use tracing::warn;
+
+use super::TxEntry;
+use crate::common::{Result, Sv2Error};
+use crate::pool::tx::Transaction;
+
+/// `[jdc.tx_rules]`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TxRulesConfig {
+    /// txids as shown by block explorers
+    pub deny_txids: Vec<String>,
+    /// scriptPubKeys, hex
+    pub deny_scripts: Vec<String>,
+    pub deny_addresses: Vec<String>,
+    /// Output types, see `OutputType`
+    pub deny_output_types: Vec<OutputType>,
+    /// Largest OP_RETURN script (bytes) to accept
+    pub max_op_return_size: Option<usize>,
+    /// Ordinals-style `OP_FALSE OP_IF "ord"` witness envelopes
+    pub deny_inscriptions: bool,
+    /// txids to include whatever the strategy and fee rate say
+    pub force_txids: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputType {
+    P2pk,
+    P2pkh,
+    P2sh,
+    /// Bare multisig
+    P2ms,
+    P2wpkh,
+    P2wsh,
+    P2tr,
+    OpReturn,
+    Nonstandard,
+}
+
+impl OutputType {
+    pub fn of(script: &[u8]) -> Self {
+        match script {
+            [0x6a, ..] => OutputType::OpReturn,
+            [0x76, 0xa9, 0x14, .., 0x88, 0xac] if script.len() == 25 => OutputType::P2pkh,
+            [0xa9, 0x14, .., 0x87] if script.len() == 23 => OutputType::P2sh,
+            [0x00, 0x14, ..] if script.len() == 22 => OutputType::P2wpkh,
+            [0x00, 0x20, ..] if script.len() == 34 => OutputType::P2wsh,
+            [0x51, 0x20, ..] if script.len() == 34 => OutputType::P2tr,
+            [0x21, .., 0xac] if script.len() == 35 => OutputType::P2pk,
+            [0x41, .., 0xac] if script.len() == 67 => OutputType::P2pk,
+            [m @ 0x51..=0x60, .., n @ 0x51..=0x60, 0xae] if m <= n => OutputType::P2ms,
+            _ => OutputType::Nonstandard,
+        }
+    }
+}
+
+/// What the rules say about one transaction
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Verdict {
+    #[default]
+    Pass,
+    /// Excluded by the named rule
+    Deny(&'static str),
+    /// Must go in, on the allow list itself or as an ancestor of one
+    Force,
+}
+
+pub const DENY_TXID: &str = "deny_txids";
+pub const DENY_SCRIPT: &str = "deny_scripts";
+pub const DENY_ADDRESS: &str = "deny_addresses";
+pub const DENY_OUTPUT_TYPE: &str = "deny_output_types";
+pub const OP_RETURN_SIZE: &str = "max_op_return_size";
+pub const INSCRIPTION: &str = "deny_inscriptions";
+pub const FORCE_TXID: &str = "force_txids";
+
+const RULES: [&str; 7] = [
+    DENY_TXID,
+    DENY_SCRIPT,
+    DENY_ADDRESS,
+    DENY_OUTPUT_TYPE,
+    OP_RETURN_SIZE,
+    INSCRIPTION,
+    FORCE_TXID,
+];
+
+/// `OP_FALSE OP_IF OP_PUSHBYTES_3 "ord"`
+const ORD_ENVELOPE: [u8; 6] = [0x00, 0x63, 0x03, b'o', b'r', b'd'];
+
+#[derive(Debug, Default)]
+pub struct RuleReport {
+    /// One per template transaction
+    pub verdicts: Vec<Verdict>,
+    /// Transactions each rule matched directly, in `RULES` order
+    pub hits: Vec<(&'static str, usize)>,
+}
+
+#[derive(Debug, Default)]
+pub struct RuleSet {
+    deny_txids: HashSet<String>,
+    deny_scripts: HashSet<Vec<u8>>,
+    deny_addresses: HashSet<Vec<u8>>,
+    deny_output_types: HashSet<OutputType>,
+    max_op_return_size: Option<usize>,
+    deny_inscriptions: bool,
+    force_txids: HashSet<String>,
+}
+
+impl RuleSet {
+    pub fn from_config(cfg: &TxRulesConfig) -> Result<Self> {
+        let bad = |msg: String| Sv2Error::Config(config::ConfigError::Message(msg));
+
+        let txids = |list: &[String], rule: &str| {
+            list.iter()
+                .map(|t| {
+                    let t = t.to_ascii_lowercase();
+                    match hex::decode(&t) {
+                        Ok(b) if b.len() == 32 => Ok(t),
+                        _ => Err(bad(format!("{}: bad txid {}", rule, t))),
+                    }
+                })
+                .collect::<Result<HashSet<_>>>()
+        };
+
+        let deny_scripts = cfg
+            .deny_scripts
+            .iter()
+            .map(|s| hex::decode(s).map_err(|e| bad(format!("{}: bad script {}: {}", DENY_SCRIPT, s, e))))
+            .collect::<Result<_>>()?;
+
+        // The script is the same whichever network the address is for
+        let deny_addresses = cfg
+            .deny_addresses
+            .iter()
+            .map(|a| {
+                a.parse::<Address<NetworkUnchecked>>()
+                    .map(|a| a.assume_checked().script_pubkey().to_bytes())
+                    .map_err(|e| bad(format!("{}: bad address {}: {}", DENY_ADDRESS, a, e)))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            deny_txids: txids(&cfg.deny_txids, DENY_TXID)?,
+            deny_scripts,
+            deny_addresses,
+            deny_output_types: cfg.deny_output_types.iter().copied().collect(),
+            max_op_return_size: cfg.max_op_return_size,
+            deny_inscriptions: cfg.deny_inscriptions,
+            force_txids: txids(&cfg.force_txids, FORCE_TXID)?,
+        })
+    }
+
+    /// Whether there is anything to check, so templates needn't be parsed
+    pub fn is_empty(&self) -> bool {
+        self.deny_txids.is_empty()
+            && self.deny_scripts.is_empty()
+            && self.deny_addresses.is_empty()
+            && self.deny_output_types.is_empty()
+            && self.max_op_return_size.is_none()
+            && !self.deny_inscriptions
+            && self.force_txids.is_empty()
+    }
+
+    /// Check every transaction. A bad transaction fails the whole template,
+    /// as it would when building the declaration.
+    pub fn apply(&self, txs: &[TxEntry]) -> Result<RuleReport> {
+        let mut report = RuleReport {
+            verdicts: vec![Verdict::Pass; txs.len()],
+            hits: RULES.iter().map(|&r| (r, 0)).collect(),
+        };
+        if self.is_empty() {
+            return Ok(report);
+        }
+
+        let mut forced = Vec::new();
+        for (i, entry) in txs.iter().enumerate() {
+            let txid = entry.txid.to_ascii_lowercase();
+            if self.force_txids.contains(&txid) {
+                report.hit(FORCE_TXID);
+                forced.push(i);
+                continue;
+            }
+
+            let raw = hex::decode(entry.data.as_bytes())
+                .map_err(|_| Sv2Error::Serialization(format!("template transaction {}", entry.txid)))?;
+            let tx = Transaction::parse(&raw)?;
+
+            if let Some(rule) = self.check(&txid, &tx) {
+                report.hit(rule);
+                report.verdicts[i] = Verdict::Deny(rule);
+            }
+        }
+
+        // Ancestors of a forced transaction are forced too, denied or not.
+        // GBT lists parents first, so walking backwards reaches them all.
+        for &i in &forced {
+            report.verdicts[i] = Verdict::Force;
+        }
+        for i in (0..txs.len()).rev() {
+            if report.verdicts[i] != Verdict::Force {
+                continue;
+            }
+            for p in txs[i].depends.iter().map(|d| d.wrapping_sub(1)).filter(|&p| p < i) {
+                if let Verdict::Deny(rule) = report.verdicts[p] {
+                    warn!("Forcing tx {} denied by {}: ancestor of {}", txs[p].txid, rule, txs[i].txid);
+                }
+                report.verdicts[p] = Verdict::Force;
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// The first deny rule `tx` breaks
+    fn check(&self, txid: &str, tx: &Transaction) -> Option<&'static str> {
+        if self.deny_txids.contains(txid) {
+            return Some(DENY_TXID);
+        }
+
+        for out in &tx.outputs {
+            let script = &out.script_pubkey;
+            if self.deny_scripts.contains(script) {
+                return Some(DENY_SCRIPT);
+            }
+            if self.deny_addresses.contains(script) {
+                return Some(DENY_ADDRESS);
+            }
+            let kind = OutputType::of(script);
+            if self.deny_output_types.contains(&kind) {
+                return Some(DENY_OUTPUT_TYPE);
+            }
+            if kind == OutputType::OpReturn && self.max_op_return_size.is_some_and(|max| script.len() > max) {
+                return Some(OP_RETURN_SIZE);
+            }
+        }
+
+        let envelope = |item: &Vec<u8>| item.windows(ORD_ENVELOPE.len()).any(|w| w == ORD_ENVELOPE);
+        if self.deny_inscriptions && tx.inputs.iter().any(|i| i.witness.iter().any(envelope)) {
+            return Some(INSCRIPTION);
+        }
+
+        None
+    }
+}
+
+impl RuleReport {
+    fn hit(&mut self, rule: &'static str) {
+        if let Some((_, n)) = self.hits.iter_mut().find(|(r, _)| *r == rule) {
+            *n += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::tx::{TxIn, TxOut};
+
+    const P2PKH: &str = "76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac";
+    /// The address for `P2PKH`
+    const ADDRESS: &str = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+
+    fn entry(txid: u8, outputs: &[&[u8]], witness: Vec<Vec<u8>>, depends: Vec<usize>) -> TxEntry {
+        let tx = Transaction {
+            version: 2,
+            inputs: vec![TxIn {
+                prev_txid: [txid; 32],
+                prev_vout: 0,
+                script_sig: Vec::new(),
+                sequence: 0xffff_ffff,
+                witness,
+            }],
+            outputs: outputs
+                .iter()
+                .map(|s| TxOut { value: 1000, script_pubkey: s.to_vec() })
+                .collect(),
+            locktime: 0,
+        };
+        TxEntry {
+            data: hex::encode(tx.serialize()).into(),
+            txid: hex::encode([txid; 32]),
+            fee: Some(1000),
+            depends,
+            weight: 400,
+            sigops: 0,
+        }
+    }
+
+    #[test]
+    fn test_output_types() {
+        let p2pkh = hex::decode(P2PKH).unwrap();
+        assert_eq!(OutputType::of(&p2pkh), OutputType::P2pkh);
+        assert_eq!(OutputType::of(&[&[0x00, 0x14][..], &[0; 20]].concat()), OutputType::P2wpkh);
+        assert_eq!(OutputType::of(&[&[0x51, 0x20][..], &[0; 32]].concat()), OutputType::P2tr);
+        assert_eq!(OutputType::of(&[0x6a, 0x01, 0x00]), OutputType::OpReturn);
+        let bare = [&[0x51, 0x21][..], &[2; 33], &[0x51, 0xae]].concat();
+        assert_eq!(OutputType::of(&bare), OutputType::P2ms);
+        assert_eq!(OutputType::of(&[0x51]), OutputType::Nonstandard);
+    }
+
+    #[test]
+    fn test_rules() {
+        let cfg = TxRulesConfig {
+            deny_txids: vec![hex::encode([1; 32])],
+            deny_addresses: vec![ADDRESS.into()],
+            deny_output_types: vec![OutputType::P2ms],
+            max_op_return_size: Some(42),
+            deny_inscriptions: true,
+            force_txids: vec![hex::encode([9; 32]).to_uppercase()],
+            ..Default::default()
+        };
+        let rules = RuleSet::from_config(&cfg).unwrap();
+
+        let p2pkh = hex::decode(P2PKH).unwrap();
+        let envelope = [&[0x20][..], &[7; 32], &[0xac], &ORD_ENVELOPE, &[0x68]].concat();
+        let ok: &[u8] = &[0x00, 0x14, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let txs = [
+            entry(1, &[ok], vec![], vec![]),
+            entry(2, &[ok, &p2pkh], vec![], vec![]),
+            entry(3, &[&[0x6a; 43]], vec![], vec![]),
+            entry(4, &[&[0x6a; 42]], vec![], vec![]),
+            entry(5, &[ok], vec![envelope, vec![0xc0; 33]], vec![]),
+            entry(6, &[ok], vec![], vec![]),
+            entry(7, &[&p2pkh], vec![], vec![6]),    // denied, but the forced tx needs it
+            entry(9, &[ok], vec![], vec![7]),
+        ];
+        let report = rules.apply(&txs).unwrap();
+
+        assert_eq!(report.verdicts, vec![
+            Verdict::Deny(DENY_TXID),
+            Verdict::Deny(DENY_ADDRESS),
+            Verdict::Deny(OP_RETURN_SIZE),
+            Verdict::Pass,
+            Verdict::Deny(INSCRIPTION),
+            Verdict::Force,
+            Verdict::Force,
+            Verdict::Force,
+        ]);
+        let hits = |rule| report.hits.iter().find(|(r, _)| *r == rule).unwrap().1;
+        assert_eq!(hits(DENY_ADDRESS), 2);
+        assert_eq!(hits(FORCE_TXID), 1);
+        assert_eq!(hits(DENY_OUTPUT_TYPE), 0);
+    }
+
+    #[test]
+    fn test_bad_config() {
+        let cfg = TxRulesConfig { deny_addresses: vec!["1notanaddress".into()], ..Default::default() };
+        assert!(RuleSet::from_config(&cfg).is_err());
+        let cfg = TxRulesConfig { force_txids: vec!["abcd".into()], ..Default::default() };
+        assert!(RuleSet::from_config(&cfg).is_err());
+        assert!(RuleSet::from_config(&TxRulesConfig::default()).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,658 @@
+//! Transaction selection
+//!
+//! Sits between `getblocktemplate` and the declaration. Filtering rules
+//! mark transactions as denied or forced, a `TxSelector` strategy picks
+//! and orders the rest, then the policy pass drops those paying less than
+//! the minimum fee rate and caps the total weight. The policy pass only
+//! keeps a child once all of its parents are in, so strategies must list
+//! parents before children.
+
+use serde::Deserialize;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fmt;
+
+use super::rules::Verdict;
+use super::TxEntry;
+
+/// A block-building strategy
+pub trait TxSelector: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Template indexes to include within `max_weight`, parents before
+    /// children
+    fn select(&self, txs: &[TxEntry], max_weight: u64) -> Vec<usize>;
+}
+
+/// `[jdc.tx_selection]`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(tag = "strategy", rename_all = "snake_case")]
+pub enum SelectorConfig {
+    /// Take the node's template as-is
+    #[default]
+    Gbt,
+    /// Greedy by ancestor package fee rate
+    AncestorFeerate,
+    /// Most fees over dependency clusters within the weight budget
+    Knapsack {
+        /// Weight buckets the budget is split into; more is finer and slower
+        #[serde(default = "default_knapsack_buckets")]
+        buckets: u64,
+    },
+}
+
+fn default_knapsack_buckets() -> u64 {
+    1000
+}
+
+impl SelectorConfig {
+    pub fn build(&self) -> Box<dyn TxSelector> {
+        match *self {
+            SelectorConfig::Gbt => Box::new(GbtOrder),
+            SelectorConfig::AncestorFeerate => Box::new(AncestorFeerate),
+            SelectorConfig::Knapsack { buckets } => Box::new(Knapsack { buckets }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SelectionPolicy {
+    /// sat/vB
+    pub min_fee_rate: f64,
+    /// Weight budget for the non-coinbase transactions
+    pub max_weight: u64,
+    /// Sigop cost budget for the non-coinbase transactions
+    pub max_sigops: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DropReason {
+    /// Left out by the strategy
+    NotSelected,
+    /// Denied by the named filtering rule
+    Rule(&'static str),
+    /// Fee rate in sat/vB
+    LowFeeRate(f64),
+    /// 0-based template index of a parent that isn't in
+    MissingParent(usize),
+    OverWeight,
+    OverSigops,
+}
+
+impl fmt::Display for DropReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DropReason::NotSelected => f.write_str("not selected"),
+            DropReason::Rule(r) => write!(f, "denied by {}", r),
+            DropReason::LowFeeRate(r) => write!(f, "fee rate {:.2} sat/vB", r),
+            DropReason::MissingParent(i) => write!(f, "parent #{} not included", i),
+            DropReason::OverWeight => f.write_str("over weight budget"),
+            DropReason::OverSigops => f.write_str("over sigop budget"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Selection {
+    /// Template indexes kept, in block order
+    pub kept: Vec<usize>,
+    pub dropped: Vec<(usize, DropReason)>,
+    pub weight: u64,
+    pub sigops: u64,
+}
+
+impl Selection {
+    pub fn count(&self, f: impl Fn(&DropReason) -> bool) -> usize {
+        self.dropped.iter().filter(|(_, r)| f(r)).count()
+    }
+}
+
+/// Run `selector` over what the rules allow, then apply `policy`. Forced
+/// transactions go first and the strategy gets what weight they leave.
+pub fn run(txs: &[TxEntry], selector: &dyn TxSelector, verdicts: &[Verdict], policy: &SelectionPolicy) -> Selection {
+    let verdict = |i: usize| verdicts.get(i).copied().unwrap_or_default();
+
+    let mut order: Vec<usize> = (0..txs.len()).filter(|&i| verdict(i) == Verdict::Force).collect();
+    let forced_weight: u64 = order.iter().map(|&i| txs[i].weight).sum();
+
+    // The strategy only sees candidates, so it doesn't spend the budget or
+    // its time on anything the policy pass would drop for rule reasons or
+    // the fee rate floor. Forced parents are in already and fall out of
+    // `depends`.
+    let mut view = Vec::new();
+    let mut index = vec![None; txs.len()];
+    let mut blocked = vec![false; txs.len()];
+    let mut low = Vec::new();
+    for (i, tx) in txs.iter().enumerate() {
+        match verdict(i) {
+            Verdict::Force => continue,
+            Verdict::Deny(_) => {
+                blocked[i] = true;
+                continue;
+            }
+            Verdict::Pass => {}
+        }
+        if parents(tx).any(|p| blocked.get(p).copied().unwrap_or(true)) {
+            blocked[i] = true;
+            continue;
+        }
+        if fee_rate(tx) < policy.min_fee_rate {
+            blocked[i] = true;
+            low.push(i);
+            continue;
+        }
+        let depends = parents(tx).filter_map(|p| index[p]).map(|j: usize| j + 1).collect();
+        index[i] = Some(view.len());
+        view.push(TxEntry { depends, ..tx.clone() });
+    }
+
+    let map: Vec<usize> = (0..txs.len()).filter(|&i| index[i].is_some()).collect();
+    let budget = policy.max_weight.saturating_sub(forced_weight);
+    order.extend(selector.select(&view, budget).into_iter().filter_map(|j| map.get(j).copied()));
+    // Listed so the policy pass reports them for their fee rate
+    order.extend(low);
+
+    select(txs, &order, verdicts, policy)
+}
+
+/// Apply `policy` to the transactions at `order`, in that order. Forced
+/// ones skip the fee rate floor, denied ones are dropped, and anything
+/// `order` leaves out is dropped as not selected or for its parent.
+pub fn select(txs: &[TxEntry], order: &[usize], verdicts: &[Verdict], policy: &SelectionPolicy) -> Selection {
+    let verdict = |i: usize| verdicts.get(i).copied().unwrap_or_default();
+    let mut sel = Selection::default();
+    let mut kept = vec![false; txs.len()];
+    let mut seen = vec![false; txs.len()];
+
+    for &i in order {
+        let Some(tx) = txs.get(i) else { continue };
+        if std::mem::replace(&mut seen[i], true) {
+            continue;
+        }
+
+        if let Verdict::Deny(rule) = verdict(i) {
+            sel.dropped.push((i, DropReason::Rule(rule)));
+            continue;
+        }
+
+        let missing = parents(tx).find(|&p| !kept.get(p).copied().unwrap_or(false));
+        if let Some(p) = missing {
+            sel.dropped.push((i, DropReason::MissingParent(p)));
+            continue;
+        }
+
+        let rate = fee_rate(tx);
+        if rate < policy.min_fee_rate && verdict(i) != Verdict::Force {
+            sel.dropped.push((i, DropReason::LowFeeRate(rate)));
+            continue;
+        }
+
+        if sel.weight + tx.weight > policy.max_weight {
+            sel.dropped.push((i, DropReason::OverWeight));
+            continue;
+        }
+
+        if sel.sigops + tx.sigops > policy.max_sigops {
+            sel.dropped.push((i, DropReason::OverSigops));
+            continue;
+        }
+
+        kept[i] = true;
+        sel.weight += tx.weight;
+        sel.sigops += tx.sigops;
+        sel.kept.push(i);
+    }
+
+    for (i, tx) in txs.iter().enumerate().filter(|&(i, _)| !seen[i]) {
+        let reason = match verdict(i) {
+            Verdict::Deny(rule) => DropReason::Rule(rule),
+            _ => match parents(tx).find(|&p| !kept.get(p).copied().unwrap_or(false)) {
+                Some(p) => DropReason::MissingParent(p),
+                None => DropReason::NotSelected,
+            },
+        };
+        sel.dropped.push((i, reason));
+    }
+
+    sel
+}
+
+/// 0-based parent indexes; GBT `depends` are 1-based
+fn parents(tx: &TxEntry) -> impl Iterator<Item = usize> + '_ {
+    tx.depends.iter().map(|d| d.wrapping_sub(1))
+}
+
+fn fee(tx: &TxEntry) -> u64 {
+    tx.fee.unwrap_or(0)
+}
+
+/// sat/vB
+fn fee_rate(tx: &TxEntry) -> f64 {
+    let vsize = tx.weight.div_ceil(4).max(1);
+    fee(tx) as f64 / vsize as f64
+}
+
+/// Everything, in the node's order
+pub struct GbtOrder;
+
+impl TxSelector for GbtOrder {
+    fn name(&self) -> &'static str {
+        "gbt"
+    }
+
+    fn select(&self, txs: &[TxEntry], _max_weight: u64) -> Vec<usize> {
+        (0..txs.len()).collect()
+    }
+}
+
+/// Repeatedly take the transaction whose not-yet-included ancestor package
+/// pays the best fee rate and still fits, ancestors first. Packages sit in
+/// a max-heap by that rate; taking one updates only its descendants'
+/// packages, the way Core's block assembler tracks modified entries.
+pub struct AncestorFeerate;
+
+impl TxSelector for AncestorFeerate {
+    fn name(&self) -> &'static str {
+        "ancestor_feerate"
+    }
+
+    fn select(&self, txs: &[TxEntry], max_weight: u64) -> Vec<usize> {
+        let ancestors = ancestor_sets(txs);
+        let mut descendants = vec![Vec::new(); txs.len()];
+        for (i, set) in ancestors.iter().enumerate() {
+            for &a in set.iter().filter(|&&a| a != i) {
+                descendants[a].push(i);
+            }
+        }
+
+        // Fee and weight of each transaction's package of not-yet-included
+        // ancestors, itself among them
+        let mut packages: Vec<Package> = ancestors
+            .iter()
+            .enumerate()
+            .map(|(i, set)| Package {
+                tx: i,
+                fee: set.iter().map(|&a| fee(&txs[a])).sum(),
+                weight: set.iter().map(|&a| txs[a].weight).sum(),
+            })
+            .collect();
+        let mut heap: BinaryHeap<Package> = packages.iter().copied().collect();
+        let mut included = vec![false; txs.len()];
+        let mut order = Vec::new();
+        let mut weight = 0u64;
+
+        while let Some(pkg) = heap.pop() {
+            // Stale: taken since, or its package shrank and was pushed again
+            if included[pkg.tx] || pkg != packages[pkg.tx] {
+                continue;
+            }
+            // Only an ancestor going in makes it smaller, and that pushes it
+            // again
+            if weight + pkg.weight > max_weight {
+                continue;
+            }
+
+            let mut modified = Vec::new();
+            for &a in &ancestors[pkg.tx] {
+                if std::mem::replace(&mut included[a], true) {
+                    continue;
+                }
+                order.push(a);
+                for &d in descendants[a].iter().filter(|&&d| !included[d]) {
+                    packages[d].fee -= fee(&txs[a]);
+                    packages[d].weight -= txs[a].weight;
+                    modified.push(d);
+                }
+            }
+            weight += pkg.weight;
+
+            modified.sort_unstable();
+            modified.dedup();
+            heap.extend(modified.into_iter().filter(|&d| !included[d]).map(|d| packages[d]));
+        }
+
+        order
+    }
+}
+
+/// A transaction's not-yet-included ancestor package, ordered by fee rate,
+/// ties to the earlier transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Package {
+    tx: usize,
+    fee: u64,
+    weight: u64,
+}
+
+impl Ord for Package {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // fee / weight against other.fee / other.weight
+        let rate = self.fee as u128 * other.weight as u128;
+        let other_rate = other.fee as u128 * self.weight as u128;
+        rate.cmp(&other_rate)
+            .then_with(|| other.tx.cmp(&self.tx))
+            .then_with(|| other.weight.cmp(&self.weight))
+    }
+}
+
+impl PartialOrd for Package {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Each transaction with all of its ancestors, in template order. GBT lists
+/// parents first, so every parent's set is complete before its children's.
+fn ancestor_sets(txs: &[TxEntry]) -> Vec<Vec<usize>> {
+    let mut sets: Vec<Vec<usize>> = Vec::with_capacity(txs.len());
+
+    for (i, tx) in txs.iter().enumerate() {
+        let mut set = vec![i];
+        for p in parents(tx).filter(|&p| p < i) {
+            set.extend_from_slice(&sets[p]);
+        }
+        set.sort_unstable();
+        set.dedup();
+        sets.push(set);
+    }
+    sets
+}
+
+/// Clusters the exact knapsack pass runs over; the rest are taken greedily
+const KNAPSACK_WINDOW: usize = 256;
+
+/// 0/1 knapsack on fees over dependency clusters, so related transactions
+/// go in or stay out together. Cluster weights are rounded up to whole
+/// buckets, which keeps the pick within budget at some cost in precision.
+/// Clusters are taken by fee rate up to where the block fills, and the
+/// exact pass only decides the `KNAPSACK_WINDOW` around that point, so
+/// memory stays bounded however large the mempool.
+pub struct Knapsack {
+    pub buckets: u64,
+}
+
+impl TxSelector for Knapsack {
+    fn name(&self) -> &'static str {
+        "knapsack"
+    }
+
+    fn select(&self, txs: &[TxEntry], max_weight: u64) -> Vec<usize> {
+        let cluster_of = clusters(txs);
+        let n = cluster_of.iter().map(|&c| c + 1).max().unwrap_or(0);
+
+        let mut fees = vec![0u64; n];
+        let mut weights = vec![0u64; n];
+        for (i, &c) in cluster_of.iter().enumerate() {
+            fees[c] += fee(&txs[i]);
+            weights[c] += txs[i].weight;
+        }
+
+        let bucket = max_weight.div_ceil(self.buckets.max(1)).max(1);
+        let cap = (max_weight / bucket) as usize;
+        let cost: Vec<usize> = weights.iter().map(|w| w.div_ceil(bucket) as usize).collect();
+
+        // Best fee rate first, ties to the earlier cluster
+        let mut ranked: Vec<usize> = (0..n).collect();
+        ranked.sort_by(|&a, &b| {
+            let (ra, rb) = (fees[a] as u128 * weights[b] as u128, fees[b] as u128 * weights[a] as u128);
+            rb.cmp(&ra).then(a.cmp(&b))
+        });
+
+        // Where a greedy fill stops; the exact pass gets the clusters
+        // either side of it
+        let mut filled = 0;
+        let mut used = 0;
+        while filled < n && used + cost[ranked[filled]] <= cap {
+            used += cost[ranked[filled]];
+            filled += 1;
+        }
+        let start = filled.saturating_sub(KNAPSACK_WINDOW / 2);
+        let end = (start + KNAPSACK_WINDOW).min(n);
+
+        let mut chosen = vec![false; n];
+        let mut room = cap;
+        for &k in &ranked[..start] {
+            chosen[k] = true;
+            room -= cost[k];
+        }
+
+        // best[b]: most fees within b buckets; take[w][b]: window cluster w
+        // is in it
+        let window = &ranked[start..end];
+        let mut best = vec![0u64; room + 1];
+        let mut take = vec![vec![false; room + 1]; window.len()];
+        for (w, &k) in window.iter().enumerate().filter(|&(_, &k)| cost[k] <= room) {
+            for b in (cost[k]..=room).rev() {
+                let with = best[b - cost[k]] + fees[k];
+                if with > best[b] {
+                    best[b] = with;
+                    take[w][b] = true;
+                }
+            }
+        }
+
+        let mut b = room;
+        for (w, &k) in window.iter().enumerate().rev() {
+            if take[w][b] {
+                chosen[k] = true;
+                b -= cost[k];
+                room -= cost[k];
+            }
+        }
+
+        // Whatever room the window leaves goes to the rest
+        for &k in &ranked[end..] {
+            if cost[k] <= room {
+                chosen[k] = true;
+                room -= cost[k];
+            }
+        }
+
+        (0..txs.len()).filter(|&i| chosen[cluster_of[i]]).collect()
+    }
+}
+
+/// Cluster id per transaction; transactions linked through `depends` share
+/// one. Ids are numbered in order of first appearance.
+fn clusters(txs: &[TxEntry]) -> Vec<usize> {
+    fn root(up: &mut [usize], mut i: usize) -> usize {
+        while up[i] != i {
+            up[i] = up[up[i]];
+            i = up[i];
+        }
+        i
+    }
+
+    let mut up: Vec<usize> = (0..txs.len()).collect();
+    for (i, tx) in txs.iter().enumerate() {
+        for p in parents(tx).filter(|&p| p < txs.len()) {
+            let (a, b) = (root(&mut up, i), root(&mut up, p));
+            up[a] = b;
+        }
+    }
+
+    let mut ids = vec![usize::MAX; txs.len()];
+    let mut next = 0;
+    (0..txs.len())
+        .map(|i| {
+            let r = root(&mut up, i);
+            if ids[r] == usize::MAX {
+                ids[r] = next;
+                next += 1;
+            }
+            ids[r]
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tx(fee: u64, weight: u64, depends: Vec<usize>) -> TxEntry {
+        TxEntry {
+            data: "".into(),
+            txid: String::new(),
+            fee: Some(fee),
+            depends,
+            weight,
+            sigops: 0,
+        }
+    }
+
+    fn all(txs: &[TxEntry]) -> Vec<usize> {
+        (0..txs.len()).collect()
+    }
+
+    #[test]
+    fn test_fee_rate_and_parents() {
+        let txs = [
+            tx(100, 400, vec![]),     // 1 sat/vB
+            tx(50, 400, vec![]),      // 0.5 sat/vB
+            tx(1000, 400, vec![2]),   // child of the cheap one
+            tx(1000, 400, vec![1]),   // child of the good one
+            tx(1000, 400, vec![3]),   // grandchild through a dropped parent
+        ];
+        let policy = SelectionPolicy { min_fee_rate: 1.0, max_weight: 4_000_000, max_sigops: 80_000 };
+        let sel = select(&txs, &all(&txs), &[], &policy);
+
+        assert_eq!(sel.kept, vec![0, 3]);
+        assert_eq!(sel.dropped, vec![
+            (1, DropReason::LowFeeRate(0.5)),
+            (2, DropReason::MissingParent(1)),
+            (4, DropReason::MissingParent(2)),
+        ]);
+        assert_eq!(sel.weight, 800);
+        assert_eq!(sel.count(|r| matches!(r, DropReason::MissingParent(_))), 2);
+
+        // A child ordered ahead of its parent doesn't get in
+        let sel = select(&txs, &[3, 0], &[], &policy);
+        assert_eq!(sel.kept, vec![0]);
+        assert_eq!(sel.dropped[0], (3, DropReason::MissingParent(0)));
+        assert_eq!(sel.count(|r| *r == DropReason::NotSelected), 1);
+        assert_eq!(sel.dropped[2], (2, DropReason::MissingParent(1)));
+    }
+
+    #[test]
+    fn test_weight_cap() {
+        let txs = [tx(1000, 600, vec![]), tx(1000, 600, vec![]), tx(1000, 400, vec![]), tx(1000, 400, vec![2])];
+        let sel = select(&txs, &all(&txs), &[], &SelectionPolicy { min_fee_rate: 0.0, max_weight: 1000, max_sigops: 80_000 });
+
+        // The second doesn't fit, a smaller one after it still does
+        assert_eq!(sel.kept, vec![0, 2]);
+        assert_eq!(sel.dropped, vec![(1, DropReason::OverWeight), (3, DropReason::MissingParent(1))]);
+
+        let txs = [TxEntry { sigops: 60_000, ..tx(1000, 400, vec![]) }, TxEntry { sigops: 30_000, ..tx(1000, 400, vec![]) }];
+        let sel = select(&txs, &all(&txs), &[], &SelectionPolicy { min_fee_rate: 0.0, max_weight: 1000, max_sigops: 80_000 });
+        assert_eq!(sel.kept, vec![0]);
+        assert_eq!(sel.dropped, vec![(1, DropReason::OverSigops)]);
+    }
+
+    #[test]
+    fn test_ancestor_feerate() {
+        let txs = [
+            tx(100, 400, vec![]),     // 1 sat/vB
+            tx(0, 400, vec![]),       // free parent...
+            tx(2000, 400, vec![2]),   // ...paid for by its child, 10 sat/vB together
+            tx(500, 400, vec![]),     // 5 sat/vB
+        ];
+        assert_eq!(AncestorFeerate.select(&txs, 4_000_000), vec![1, 2, 3, 0]);
+        assert_eq!(AncestorFeerate.select(&txs, 800), vec![1, 2]);
+        // The package doesn't fit, the next best does
+        assert_eq!(AncestorFeerate.select(&txs, 500), vec![3]);
+
+        let txs = [
+            tx(0, 400, vec![]),       // free parent of two
+            tx(4000, 400, vec![1]),   // 20 sat/vB with it
+            tx(1200, 400, vec![1]),   // 6 sat/vB with it, 12 once it's in
+            tx(1000, 400, vec![]),    // 10 sat/vB
+        ];
+        // The second child's package shrinks when the first takes the parent
+        assert_eq!(AncestorFeerate.select(&txs, 4_000_000), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_knapsack() {
+        let txs = [
+            tx(900, 600, vec![]),
+            tx(500, 400, vec![]),
+            tx(500, 200, vec![]),
+            tx(100, 200, vec![2]),
+        ];
+        // Clusters {0} 900/600, {1, 3} 600/600 and {2} 500/200
+        let knapsack = Knapsack { buckets: 1000 };
+        assert_eq!(knapsack.select(&txs, 800), vec![0, 2]);
+        assert_eq!(knapsack.select(&txs, 1200), vec![0, 1, 3]);
+        // Coarse buckets round {2} up to a full 600
+        assert_eq!(Knapsack { buckets: 2 }.select(&txs, 1200), vec![0, 1, 3]);
+        assert_eq!(GbtOrder.select(&txs, 0), all(&txs));
+    }
+
+    #[test]
+    fn test_knapsack_large() {
+        // Far more clusters than the exact pass takes: singles at varied
+        // rates and parent-child pairs where the child pays for both
+        let txs: Vec<TxEntry> = (0..5000u64)
+            .map(|i| match i % 5 {
+                3 => tx(0, 600, vec![]),
+                4 => tx(200 + i % 997 * 7, 400, vec![i as usize]),
+                _ => tx(100 + i * 7919 % 5003, 400 + i % 7 * 100, vec![]),
+            })
+            .collect();
+        let max_weight = 400_000;
+
+        // 100-weight buckets, so no cluster is rounded up
+        let picked = Knapsack { buckets: 4000 }.select(&txs, max_weight);
+        let weight: u64 = picked.iter().map(|&i| txs[i].weight).sum();
+        assert!(weight <= max_weight);
+        assert!(weight > max_weight * 99 / 100);
+
+        // Parents first
+        let mut seen = vec![false; txs.len()];
+        for &i in &picked {
+            assert!(parents(&txs[i]).all(|p| seen[p]));
+            seen[i] = true;
+        }
+
+        // At least what the greedy package pick gets
+        let fees = |order: &[usize]| order.iter().map(|&i| fee(&txs[i])).sum::<u64>();
+        assert!(fees(&picked) >= fees(&AncestorFeerate.select(&txs, max_weight)));
+    }
+
+    #[test]
+    fn test_rule_verdicts() {
+        let txs = [
+            tx(0, 400, vec![]),       // forced despite paying nothing
+            tx(1000, 400, vec![1]),
+            tx(1000, 400, vec![]),    // denied...
+            tx(1000, 400, vec![3]),   // ...and so is its child
+            tx(1000, 400, vec![]),
+        ];
+        let verdicts = [Verdict::Force, Verdict::Pass, Verdict::Deny("deny_txids"), Verdict::Pass, Verdict::Pass];
+        let policy = SelectionPolicy { min_fee_rate: 1.0, max_weight: 1200, max_sigops: 80_000 };
+
+        let sel = run(&txs, &AncestorFeerate, &verdicts, &policy);
+        assert_eq!(sel.kept, vec![0, 1, 4]);
+        assert_eq!(sel.dropped, vec![(2, DropReason::Rule("deny_txids")), (3, DropReason::MissingParent(2))]);
+
+        // The strategy's budget is what the forced transactions leave
+        let sel = run(&txs, &Knapsack { buckets: 1000 }, &verdicts, &SelectionPolicy { max_weight: 800, ..policy });
+        assert_eq!(sel.kept, vec![0, 1]);
+
+        // Below the floor never reaches the strategy, so it can't take the
+        // budget from what the policy pass would keep
+        let txs = [tx(300, 4000, vec![]), tx(200, 400, vec![]), tx(1000, 400, vec![1])];
+        let sel = run(&txs, &Knapsack { buckets: 1000 }, &[], &SelectionPolicy { max_weight: 4000, ..policy });
+        assert_eq!(sel.kept, vec![1]);
+        assert_eq!(sel.dropped, vec![(0, DropReason::LowFeeRate(0.3)), (2, DropReason::MissingParent(0))]);
+    }
+
+    #[test]
+    fn test_selector_config() {
+        let cfg: SelectorConfig = toml::from_str("strategy = \"knapsack\"").unwrap();
+        assert_eq!(cfg, SelectorConfig::Knapsack { buckets: 1000 });
+        let cfg: SelectorConfig = toml::from_str("strategy = \"ancestor_feerate\"").unwrap();
+        assert_eq!(cfg.build().name(), "ancestor_feerate");
+        assert!(toml::from_str::<SelectorConfig>("strategy = \"random\"").is_err());
+    }
+}
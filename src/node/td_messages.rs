@@ -0,0 +1,159 @@
+//! Stratum V2 Template Distribution Protocol Messages
+
+use crate::pool::binary::*;
+
+pub mod msg_types {
+    pub const COINBASE_OUTPUT_CONSTRAINTS: u8 = 0x70;
+    pub const NEW_TEMPLATE: u8 = 0x71;
+    pub const SET_NEW_PREV_HASH: u8 = 0x72;
+    pub const REQUEST_TX_DATA: u8 = 0x73;
+    pub const REQUEST_TX_DATA_OK: u8 = 0x74;
+    pub const REQUEST_TX_DATA_ERR: u8 = 0x75;
+    pub const SUBMIT_SOLUTION: u8 = 0x76;
+}
+
+pub const TD_EXT: u16 = 0x0000;
+pub const PROTO_TD: u8 = 2;
+
+// ============================================================================
+// CoinbaseOutputConstraints (0x70)
+// ============================================================================
+
+sv2_message! {
+    pub struct CbOutputConstraints {
+        pub max_extra_size: u32,
+        pub max_extra_sigops: u16,
+    }
+}
+
+// ============================================================================
+// NewTemplate (0x71)
+// ============================================================================
+
+sv2_message! {
+    pub struct NewTemplate {
+        pub template_id: u64,
+        pub future: bool,
+        pub version: u32,
+        pub cb_tx_version: u32,
+        pub cb_prefix: B0_255,
+        pub cb_sequence: u32,
+        pub cb_value_remaining: u64,
+        pub cb_outputs_count: u32,
+        pub cb_outputs: B0_64K,
+        pub cb_locktime: u32,
+        pub merkle_path: Seq0_255<U256>,
+    }
+}
+
+// ============================================================================
+// SetNewPrevHash (0x72)
+// ============================================================================
+
+sv2_message! {
+    pub struct SetNewPrevHash {
+        pub template_id: u64,
+        pub prev_hash: U256,
+        pub timestamp: u32,
+        pub bits: u32,
+        pub target: U256,
+    }
+}
+
+// ============================================================================
+// RequestTransactionData (0x73)
+// ============================================================================
+
+sv2_message! {
+    pub struct RequestTxData {
+        pub template_id: u64,
+    }
+}
+
+// ============================================================================
+// RequestTransactionDataSuccess (0x74)
+// ============================================================================
+
+sv2_message! {
+    pub struct RequestTxDataOk {
+        pub template_id: u64,
+        pub excess_data: B0_64K,
+        pub txs: Seq0_64K<B0_16M>,
+    }
+}
+
+// ============================================================================
+// RequestTransactionDataError (0x75)
+// ============================================================================
+
+sv2_message! {
+    pub struct RequestTxDataErr {
+        pub template_id: u64,
+        pub code: STR0_255,
+    }
+}
+
+// ============================================================================
+// SubmitSolution (0x76)
+// ============================================================================
+
+sv2_message! {
+    pub struct SubmitSolution {
+        pub template_id: u64,
+        pub version: u32,
+        pub timestamp: u32,
+        pub nonce: u32,
+        pub coinbase_tx: B0_64K,
+    }
+}
+
+/// Read the BIP34 height from the start of a coinbase scriptSig
+pub fn script_height(script: &[u8]) -> Option<u64> {
+    let op = *script.first()?;
+    match op {
+        0x00 => Some(0),
+        0x51..=0x60 => Some((op - 0x50) as u64),
+        1..=8 => {
+            let n = op as usize;
+            let bytes = script.get(1..1 + n)?;
+            let mut buf = [0u8; 8];
+            buf[..n].copy_from_slice(bytes);
+            Some(u64::from_le_bytes(buf))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_template_roundtrip() {
+        let tpl = NewTemplate {
+            template_id: 42,
+            future: true,
+            version: 0x20000000,
+            cb_tx_version: 2,
+            cb_prefix: vec![0x03, 0x40, 0x0D, 0x03].into(),
+            cb_sequence: 0xFFFF_FFFF,
+            cb_value_remaining: 312_500_000,
+            cb_outputs_count: 0,
+            cb_outputs: B0_64K::default(),
+            cb_locktime: 0,
+            merkle_path: vec![U256([1; 32])].into(),
+        };
+        let buf = tpl.serialize().unwrap();
+        assert_eq!(NewTemplate::parse(&buf).unwrap(), tpl);
+        assert_eq!(script_height(&tpl.cb_prefix), Some(200_000));
+    }
+
+    #[test]
+    fn test_script_height() {
+        assert_eq!(script_height(&[0x00]), Some(0));
+        assert_eq!(script_height(&[0x51]), Some(1));
+        assert_eq!(script_height(&[0x01, 0x7F]), Some(127));
+        assert_eq!(script_height(&[0x03, 0x01]), None);
+        assert_eq!(script_height(&[]), None);
+    }
+}
@@ -0,0 +1,437 @@
+//! Template Provider client - Stratum V2 Template Distribution Protocol
+//!
+//! Alternative to polling `getblocktemplate`: a Template Provider pushes
+//! `NewTemplate`/`SetNewPrevHash` over its own Noise connection and we
+//! fetch the transactions with `RequestTransactionData`. The resulting
+//! events are the same ones `BitcoinNode` emits. Blocks found on one of
+//! its templates go back to the TP as `SubmitSolution`.
+
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::broadcast;
+use tokio_util::codec::Framed;
+use tracing::{debug, error, info, warn};
+
+use crate::common::{Event, CoinbaseOut, PoolCoinbase, Solution, Sv2Error, Result, TplHeader, TplSnapshot};
+use crate::pool::binary::{Sv2Decode, Sv2Encode, Sv2Reader, B0_16M};
+use crate::pool::codec::{Sv2Frame, Sv2NoiseCodec};
+use crate::pool::noise;
+use crate::pool::sv2_messages::{
+    self, split_host_port, SetupConn, SetupConnErr, SetupConnOk, COMMON_EXT, SV2_MAX_VERSION,
+    SV2_MIN_VERSION,
+};
+use crate::pool::tx::read_compact_size;
+use super::consensus::{self, Network};
+use super::td_messages::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TpConfig {
+    pub address: String,
+    /// Base58-check authority key that must sign the TP's Noise static key
+    #[serde(default)]
+    pub authority_pubkey: Option<String>,
+    /// Chain the TP serves, used to split the coinbase value into subsidy
+    /// and fees
+    #[serde(default)]
+    pub network: Network,
+}
+
+pub struct TemplateProvider {
+    cfg: TpConfig,
+    bus: broadcast::Sender<Event>,
+    bus_rx: broadcast::Receiver<Event>,
+    outputs: Vec<CoinbaseOut>,
+    pool_cb: Option<PoolCoinbase>,
+    /// SetupConnection went through on the current connection
+    ready: bool,
+    templates: HashMap<u64, NewTemplate>,
+    prev_hash: Option<SetNewPrevHash>,
+}
+
+impl TemplateProvider {
+    pub fn new(
+        cfg: TpConfig,
+        bus: broadcast::Sender<Event>,
+        bus_rx: broadcast::Receiver<Event>,
+        outputs: Vec<CoinbaseOut>,
+    ) -> Self {
+        Self {
+            cfg,
+            bus,
+            bus_rx,
+            outputs,
+            pool_cb: None,
+            ready: false,
+            templates: HashMap::new(),
+            prev_hash: None,
+        }
+    }
+
+    pub async fn run(mut self) -> Result<()> {
+        info!("Starting Template Provider client");
+
+        let addr = self.cfg.address.clone();
+        split_host_port(&addr)?;
+
+        let authority = match self.cfg.authority_pubkey.as_deref().map(str::trim) {
+            None | Some("") => {
+                warn!("No TP authority_pubkey configured, TP identity is NOT verified");
+                None
+            }
+            Some(s) => Some(noise::parse_authority_key(s)?),
+        };
+
+        loop {
+            info!("Connecting to TP {}", addr);
+
+            match self.session(&addr, authority).await {
+                Ok(true) => return Ok(()),
+                Ok(false) => warn!("TP closed connection"),
+                Err(e) => {
+                    error!("TP error: {}", e);
+                    let _ = self.bus.send(Event::TemplateErr(e.to_string()));
+                }
+            }
+
+            let _ = self.bus.send(Event::NodeDown);
+            self.ready = false;
+            self.templates.clear();
+            self.prev_hash = None;
+
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        }
+    }
+
+    async fn session(
+        &mut self,
+        addr: &str,
+        authority: Option<secp256k1::XOnlyPublicKey>,
+    ) -> Result<bool> {
+        let mut stream = TcpStream::connect(addr).await?;
+        let codec = noise::initiator_handshake(&mut stream, authority).await?;
+        info!("TP encrypted channel ready");
+
+        let mut framed = Framed::new(stream, Sv2NoiseCodec::new(codec));
+        framed.send(self.setup_frame(addr)?).await?;
+
+        loop {
+            tokio::select! {
+                frame = framed.next() => {
+                    let Some(frame) = frame else {
+                        return Ok(false);
+                    };
+                    for out in self.handle_msg(frame?)? {
+                        framed.send(out).await?;
+                    }
+                }
+
+                ev = self.bus_rx.recv() => {
+                    match ev {
+                        Ok(Event::BlockFound(sol)) => {
+                            if let Some(out) = self.submit_solution(&sol)? {
+                                framed.send(out).await?;
+                            }
+                        }
+                        Ok(Event::PoolCoinbase(cb)) => {
+                            self.pool_cb = Some(cb);
+                            if self.ready {
+                                framed.send(self.constraints_frame()?).await?;
+                            }
+                        }
+                        Ok(Event::Shutdown) | Err(broadcast::error::RecvError::Closed) => {
+                            return Ok(true);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    fn constraints_frame(&self) -> Result<Sv2Frame> {
+        let c = coinbase_reserve(&self.outputs, self.pool_cb.as_ref());
+        info!("Coinbase constraints: size={}, sigops={}", c.max_extra_size, c.max_extra_sigops);
+        Ok(Sv2Frame::new(msg_types::COINBASE_OUTPUT_CONSTRAINTS, TD_EXT, c.serialize()?))
+    }
+
+    fn setup_frame(&self, addr: &str) -> Result<Sv2Frame> {
+        let (host, port) = split_host_port(addr)?;
+        let msg = SetupConn {
+            protocol: PROTO_TD,
+            min_ver: SV2_MIN_VERSION,
+            max_ver: SV2_MAX_VERSION,
+            flags: 0,
+            host: host.into(),
+            port,
+            vendor: "sv2-jdc".into(),
+            hw_ver: "".into(),
+            firmware: env!("CARGO_PKG_VERSION").into(),
+            device_id: "".into(),
+        };
+        Ok(Sv2Frame::new(sv2_messages::msg_types::SETUP_CONN, COMMON_EXT, msg.serialize()?))
+    }
+
+    /// Handle one TP message, returning any replies to send
+    fn handle_msg(&mut self, frame: Sv2Frame) -> Result<Vec<Sv2Frame>> {
+        debug!("TP msg: type=0x{:02X}, len={}", frame.mtype, frame.payload.len());
+
+        match frame.mtype {
+            sv2_messages::msg_types::SETUP_CONN_OK => {
+                let msg = SetupConnOk::parse(&frame.payload)?;
+                info!("TP setup done: version={}", msg.used_ver);
+                let _ = self.bus.send(Event::NodeUp);
+
+                self.ready = true;
+                Ok(vec![self.constraints_frame()?])
+            }
+            sv2_messages::msg_types::SETUP_CONN_ERR => {
+                let msg = SetupConnErr::parse(&frame.payload)?;
+                Err(Sv2Error::PoolConnection(format!("TP setup rejected: {}", msg.code)))
+            }
+            msg_types::NEW_TEMPLATE => {
+                let msg = NewTemplate::parse(&frame.payload)?;
+                Ok(self.on_new_template(msg)?.into_iter().collect())
+            }
+            msg_types::SET_NEW_PREV_HASH => {
+                let msg = SetNewPrevHash::parse(&frame.payload)?;
+                Ok(self.on_prev_hash(msg)?.into_iter().collect())
+            }
+            msg_types::REQUEST_TX_DATA_OK => {
+                let msg = RequestTxDataOk::parse(&frame.payload)?;
+                self.on_tx_data(msg);
+                Ok(Vec::new())
+            }
+            msg_types::REQUEST_TX_DATA_ERR => {
+                let msg = RequestTxDataErr::parse(&frame.payload)?;
+                warn!("TP has no tx data for template {}: {}", msg.template_id, msg.code);
+                let _ = self.bus.send(Event::TemplateErr(format!("tx data: {}", msg.code)));
+                Ok(Vec::new())
+            }
+            other => {
+                warn!("Unknown TP msg type: 0x{:02X}", other);
+                Ok(Vec::new())
+            }
+        }
+    }
+
+    fn on_new_template(&mut self, msg: NewTemplate) -> Result<Option<Sv2Frame>> {
+        let id = msg.template_id;
+        let future = msg.future;
+        debug!("NewTemplate: id={}, future={}", id, future);
+
+        self.templates.insert(id, msg);
+
+        // A non-future template builds on the current tip, so it can be
+        // used right away
+        if !future && self.prev_hash.is_some() {
+            return Ok(Some(request_tx_data(id)?));
+        }
+        Ok(None)
+    }
+
+    fn on_prev_hash(&mut self, msg: SetNewPrevHash) -> Result<Option<Sv2Frame>> {
+        let id = msg.template_id;
+        info!("New prev hash for template {}", id);
+
+        // Templates older than the one activated here build on a stale tip
+        self.templates.retain(|&k, _| k >= id);
+
+        let moved = self.prev_hash.as_ref().map_or(true, |p| p.prev_hash != msg.prev_hash);
+        let height = self.templates.get(&id).and_then(|t| script_height(&t.cb_prefix));
+        if let (true, Some(height)) = (moved, height) {
+            let _ = self.bus.send(Event::NewPrevHash { height, prev_hash: msg.prev_hash.0 });
+        }
+        self.prev_hash = Some(msg);
+
+        if self.templates.contains_key(&id) {
+            Ok(Some(request_tx_data(id)?))
+        } else {
+            warn!("SetNewPrevHash for unknown template {}", id);
+            Ok(None)
+        }
+    }
+
+    fn on_tx_data(&mut self, msg: RequestTxDataOk) {
+        let Some(tpl) = self.templates.get(&msg.template_id) else {
+            warn!("Tx data for unknown template {}", msg.template_id);
+            return;
+        };
+        let Some(prev) = &self.prev_hash else {
+            warn!("Tx data for template {} before any prev hash", msg.template_id);
+            return;
+        };
+
+        let height = match script_height(&tpl.cb_prefix) {
+            Some(h) => h,
+            None => {
+                warn!("Template {} has no BIP34 height", msg.template_id);
+                return;
+            }
+        };
+
+        let fees = tpl
+            .cb_value_remaining
+            .saturating_sub(consensus::block_subsidy(height, self.cfg.network));
+        let txs: Vec<Vec<u8>> = msg.txs.into_inner().into_iter().map(B0_16M::into_inner).collect();
+
+        debug!("Template: height={}, txs={}", height, txs.len());
+
+        let _ = self.bus.send(Event::NewTemplate {
+            height,
+            txs: txs.len(),
+            fees,
+        });
+
+        let snapshot = TplSnapshot {
+            id: msg.template_id,
+            height,
+            header: TplHeader {
+                version: tpl.version,
+                prev_hash: prev.prev_hash.0,
+                bits: prev.bits,
+                time: prev.timestamp.max(noise::unix_now()),
+            },
+            min_time: prev.timestamp,
+            txs: Arc::new(txs),
+            fees,
+            coinbase_value: tpl.cb_value_remaining,
+            witness_commitment: find_witness_commitment(&tpl.cb_outputs, tpl.cb_outputs_count),
+        };
+
+        let _ = self.bus.send(Event::DeclareJob {
+            template: Arc::new(snapshot),
+            outputs: self.outputs.clone(),
+        });
+    }
+
+    fn submit_solution(&self, sol: &Solution) -> Result<Option<Sv2Frame>> {
+        if !self.templates.contains_key(&sol.tpl_id) {
+            warn!("Solution for unknown template {}, not submitting to TP", sol.tpl_id);
+            return Ok(None);
+        }
+
+        info!("Submitting solution for template {} to TP", sol.tpl_id);
+        let msg = SubmitSolution {
+            template_id: sol.tpl_id,
+            version: sol.header.version,
+            timestamp: sol.header.time,
+            nonce: sol.nonce,
+            coinbase_tx: sol.coinbase.clone().into(),
+        };
+        Ok(Some(Sv2Frame::new(msg_types::SUBMIT_SOLUTION, TD_EXT, msg.serialize()?)))
+    }
+}
+
+fn request_tx_data(template_id: u64) -> Result<Sv2Frame> {
+    let msg = RequestTxData { template_id };
+    Ok(Sv2Frame::new(msg_types::REQUEST_TX_DATA, TD_EXT, msg.serialize()?))
+}
+
+/// The witness commitment script among the TP's coinbase outputs
+fn find_witness_commitment(outputs: &[u8], count: u32) -> Option<Vec<u8>> {
+    let mut r = Sv2Reader::new(outputs);
+    let mut found = None;
+
+    for _ in 0..count {
+        r.take(8).ok()?;
+        let len = read_compact_size(&mut r).ok()? as usize;
+        let script = r.take(len).ok()?;
+        // BIP141: the last matching output is the commitment
+        if script.len() >= 38 && script[..6] == [0x6A, 0x24, 0xAA, 0x21, 0xA9, 0xED] {
+            found = Some(script.to_vec());
+        }
+    }
+    found
+}
+
+/// Space and sigops our coinbase outputs need on top of the TP's template
+fn output_constraints(outputs: &[CoinbaseOut]) -> CbOutputConstraints {
+    let mut size = 0usize;
+    let mut sigops = 0u16;
+
+    for o in outputs {
+        let len = o.script_pubkey.len();
+        let varint = if len < 0xFD { 1 } else { 3 };
+        size += 8 + varint + len;
+
+        // Legacy sigops are weighted x4 against the block sigop budget
+        for &op in &o.script_pubkey {
+            match op {
+                0xAC | 0xAD => sigops += 4,
+                0xAE | 0xAF => sigops += 80,
+                _ => {}
+            }
+        }
+    }
+
+    CbOutputConstraints {
+        max_extra_size: size as u32,
+        max_extra_sigops: sigops,
+    }
+}
+
+/// Room to keep free in templates for our coinbase outputs and, once it has
+/// said what it wants, the pool's: its outputs plus the output space it
+/// allows
+pub(super) fn coinbase_reserve(
+    outputs: &[CoinbaseOut],
+    pool: Option<&PoolCoinbase>,
+) -> CbOutputConstraints {
+    let Some(pool) = pool else {
+        return output_constraints(outputs);
+    };
+
+    let all: Vec<CoinbaseOut> = pool.outputs.iter().chain(outputs).cloned().collect();
+    let c = output_constraints(&all);
+    CbOutputConstraints {
+        max_extra_size: c.max_extra_size.max(pool.max_extra),
+        max_extra_sigops: c.max_extra_sigops,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_constraints() {
+        let p2pkh = hex::decode("76a914000000000000000000000000000000000000000088ac").unwrap();
+        let p2wpkh = hex::decode("00140000000000000000000000000000000000000000").unwrap();
+        let c = output_constraints(&[
+            CoinbaseOut { value: 0, script_pubkey: p2pkh },
+            CoinbaseOut { value: 0, script_pubkey: p2wpkh },
+        ]);
+        assert_eq!(c.max_extra_size, (8 + 1 + 25) + (8 + 1 + 22));
+        assert_eq!(c.max_extra_sigops, 4);
+
+        // The pool's outputs count too, and its allowance is reserved in full
+        let pool = PoolCoinbase {
+            outputs: vec![CoinbaseOut { value: 0, script_pubkey: vec![0x51, 0xAC] }],
+            max_extra: 100,
+        };
+        let c = coinbase_reserve(&[], Some(&pool));
+        assert_eq!((c.max_extra_size, c.max_extra_sigops), (100, 4));
+        let c = coinbase_reserve(&[CoinbaseOut { value: 0, script_pubkey: vec![0; 300] }], Some(&pool));
+        assert_eq!(c.max_extra_size, (8 + 1 + 2) + (8 + 3 + 300));
+    }
+
+    #[test]
+    fn test_find_witness_commitment() {
+        let commit = [&[0x6A, 0x24, 0xAA, 0x21, 0xA9, 0xED][..], &[0x11; 32]].concat();
+        let mut outs = Vec::new();
+        outs.extend_from_slice(&0u64.to_le_bytes());
+        outs.push(1);
+        outs.push(0x51);
+        outs.extend_from_slice(&0u64.to_le_bytes());
+        outs.push(commit.len() as u8);
+        outs.extend_from_slice(&commit);
+
+        assert_eq!(find_witness_commitment(&outs, 2), Some(commit));
+        assert_eq!(find_witness_commitment(&outs, 1), None);
+        assert_eq!(find_witness_commitment(&outs[..20], 2), None);
+    }
+}
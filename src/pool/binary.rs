@@ -0,0 +1,557 @@
+//! SV2 binary primitives
+//!
+//! Every wire type implements `Sv2Encode`/`Sv2Decode`, so a message is just
+//! an ordered list of fields (see `sv2_message!`). Decoding is strict: short
+//! buffers, oversize lengths, bad booleans, invalid UTF-8 and trailing bytes
+//! are all errors.
+
+// Type names follow the spec's
+#![allow(non_camel_case_types, clippy::upper_case_acronyms)]
+
+use std::ops::Deref;
+
+use crate::common::{Sv2Error, Result};
+
+pub trait Sv2Encode {
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<()>;
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.encode(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+pub trait Sv2Decode: Sized {
+    fn decode(r: &mut Sv2Reader<'_>) -> Result<Self>;
+
+    /// Decode a whole payload, rejecting trailing bytes
+    fn parse(data: &[u8]) -> Result<Self> {
+        let mut r = Sv2Reader::new(data);
+        let v = Self::decode(&mut r)?;
+        if r.remaining() != 0 {
+            return Err(Sv2Error::Codec(format!("{} trailing bytes", r.remaining())));
+        }
+        Ok(v)
+    }
+}
+
+pub struct Sv2Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Sv2Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    pub fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.remaining() < n {
+            return Err(Sv2Error::Codec(
+                format!("need {} bytes at offset {}, have {}", n, self.pos, self.remaining())
+            ));
+        }
+        let out = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(out)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut out = [0u8; N];
+        out.copy_from_slice(self.take(N)?);
+        Ok(out)
+    }
+}
+
+// ============================================================================
+// Fixed-size integers
+// ============================================================================
+
+macro_rules! sv2_int {
+    ($($t:ty),*) => {$(
+        impl Sv2Encode for $t {
+            fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+                buf.extend_from_slice(&self.to_le_bytes());
+                Ok(())
+            }
+        }
+
+        impl Sv2Decode for $t {
+            fn decode(r: &mut Sv2Reader<'_>) -> Result<Self> {
+                Ok(<$t>::from_le_bytes(r.array()?))
+            }
+        }
+    )*};
+}
+
+sv2_int!(u8, u16, u32, u64);
+
+impl Sv2Encode for bool {
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+        buf.push(*self as u8);
+        Ok(())
+    }
+}
+
+impl Sv2Decode for bool {
+    fn decode(r: &mut Sv2Reader<'_>) -> Result<Self> {
+        match u8::decode(r)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            v => Err(Sv2Error::Codec(format!("bad BOOL {}", v))),
+        }
+    }
+}
+
+/// F32, compared bitwise so messages carrying it can stay `Eq`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct F32(pub f32);
+
+impl PartialEq for F32 {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.to_bits() == other.0.to_bits()
+    }
+}
+
+impl Eq for F32 {}
+
+impl Sv2Encode for F32 {
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+        buf.extend_from_slice(&self.0.to_le_bytes());
+        Ok(())
+    }
+}
+
+impl Sv2Decode for F32 {
+    fn decode(r: &mut Sv2Reader<'_>) -> Result<Self> {
+        Ok(Self(f32::from_le_bytes(r.array()?)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct U24(pub u32);
+
+impl U24 {
+    pub const MAX: u32 = 0xFF_FFFF;
+}
+
+impl Sv2Encode for U24 {
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+        if self.0 > Self::MAX {
+            return Err(Sv2Error::Codec(format!("U24 overflow: {}", self.0)));
+        }
+        buf.extend_from_slice(&self.0.to_le_bytes()[..3]);
+        Ok(())
+    }
+}
+
+impl Sv2Decode for U24 {
+    fn decode(r: &mut Sv2Reader<'_>) -> Result<Self> {
+        let b: [u8; 3] = r.array()?;
+        Ok(Self(u32::from_le_bytes([b[0], b[1], b[2], 0])))
+    }
+}
+
+/// SHORT_TX_ID: the low 48 bits of a SipHash-2-4 of the wtxid
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ShortTxId(pub u64);
+
+impl ShortTxId {
+    pub const MAX: u64 = 0xFFFF_FFFF_FFFF;
+}
+
+impl Sv2Encode for ShortTxId {
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+        if self.0 > Self::MAX {
+            return Err(Sv2Error::Codec(format!("SHORT_TX_ID overflow: {}", self.0)));
+        }
+        buf.extend_from_slice(&self.0.to_le_bytes()[..6]);
+        Ok(())
+    }
+}
+
+impl Sv2Decode for ShortTxId {
+    fn decode(r: &mut Sv2Reader<'_>) -> Result<Self> {
+        let b: [u8; 6] = r.array()?;
+        Ok(Self(u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], 0, 0])))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct U256(pub [u8; 32]);
+
+impl From<[u8; 32]> for U256 {
+    fn from(v: [u8; 32]) -> Self {
+        Self(v)
+    }
+}
+
+impl Deref for U256 {
+    type Target = [u8; 32];
+    fn deref(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl Sv2Encode for U256 {
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+        buf.extend_from_slice(&self.0);
+        Ok(())
+    }
+}
+
+impl Sv2Decode for U256 {
+    fn decode(r: &mut Sv2Reader<'_>) -> Result<Self> {
+        Ok(Self(r.array()?))
+    }
+}
+
+// ============================================================================
+// Length-prefixed byte strings
+// ============================================================================
+
+fn put_len(buf: &mut Vec<u8>, len: usize, width: usize) {
+    buf.extend_from_slice(&(len as u32).to_le_bytes()[..width]);
+}
+
+fn get_len(r: &mut Sv2Reader<'_>, width: usize) -> Result<usize> {
+    let mut b = [0u8; 4];
+    b[..width].copy_from_slice(r.take(width)?);
+    Ok(u32::from_le_bytes(b) as usize)
+}
+
+macro_rules! sv2_bytes {
+    ($name:ident, $width:expr, $max:expr) => {
+        #[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+        pub struct $name(pub Vec<u8>);
+
+        impl $name {
+            pub const MAX: usize = $max;
+
+            // Not every width's contents get taken out
+            #[allow(dead_code)]
+            pub fn into_inner(self) -> Vec<u8> {
+                self.0
+            }
+        }
+
+        impl From<Vec<u8>> for $name {
+            fn from(v: Vec<u8>) -> Self {
+                Self(v)
+            }
+        }
+
+        impl From<&[u8]> for $name {
+            fn from(v: &[u8]) -> Self {
+                Self(v.to_vec())
+            }
+        }
+
+        impl Deref for $name {
+            type Target = Vec<u8>;
+            fn deref(&self) -> &Vec<u8> {
+                &self.0
+            }
+        }
+
+        impl Sv2Encode for $name {
+            fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+                if self.0.len() > Self::MAX {
+                    return Err(Sv2Error::Codec(format!(
+                        "{} too long: {} > {}", stringify!($name), self.0.len(), Self::MAX
+                    )));
+                }
+                put_len(buf, self.0.len(), $width);
+                buf.extend_from_slice(&self.0);
+                Ok(())
+            }
+        }
+
+        impl Sv2Decode for $name {
+            fn decode(r: &mut Sv2Reader<'_>) -> Result<Self> {
+                let len = get_len(r, $width)?;
+                if len > Self::MAX {
+                    return Err(Sv2Error::Codec(format!(
+                        "{} too long: {} > {}", stringify!($name), len, Self::MAX
+                    )));
+                }
+                Ok(Self(r.take(len)?.to_vec()))
+            }
+        }
+    };
+}
+
+sv2_bytes!(B0_32, 1, 32);
+sv2_bytes!(B0_255, 1, 255);
+sv2_bytes!(B0_64K, 2, 0xFFFF);
+sv2_bytes!(B0_16M, 3, 0xFF_FFFF);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct STR0_255(pub String);
+
+impl From<String> for STR0_255 {
+    fn from(v: String) -> Self {
+        Self(v)
+    }
+}
+
+impl From<&str> for STR0_255 {
+    fn from(v: &str) -> Self {
+        Self(v.into())
+    }
+}
+
+impl Deref for STR0_255 {
+    type Target = str;
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for STR0_255 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Sv2Encode for STR0_255 {
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+        B0_255(self.0.as_bytes().to_vec()).encode(buf)
+    }
+}
+
+impl Sv2Decode for STR0_255 {
+    fn decode(r: &mut Sv2Reader<'_>) -> Result<Self> {
+        let raw = B0_255::decode(r)?;
+        String::from_utf8(raw.0)
+            .map(Self)
+            .map_err(|e| Sv2Error::Codec(format!("STR0_255: {}", e)))
+    }
+}
+
+// ============================================================================
+// Sequences
+// ============================================================================
+
+macro_rules! sv2_seq {
+    ($name:ident, $width:expr, $max:expr) => {
+        #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+        pub struct $name<T>(pub Vec<T>);
+
+        impl<T> $name<T> {
+            pub const MAX: usize = $max;
+
+            // Not every width's contents get taken out
+            #[allow(dead_code)]
+            pub fn into_inner(self) -> Vec<T> {
+                self.0
+            }
+        }
+
+        impl<T> Default for $name<T> {
+            fn default() -> Self {
+                Self(Vec::new())
+            }
+        }
+
+        impl<T> From<Vec<T>> for $name<T> {
+            fn from(v: Vec<T>) -> Self {
+                Self(v)
+            }
+        }
+
+        impl<T> FromIterator<T> for $name<T> {
+            fn from_iter<I: IntoIterator<Item = T>>(it: I) -> Self {
+                Self(it.into_iter().collect())
+            }
+        }
+
+        impl<T> Deref for $name<T> {
+            type Target = Vec<T>;
+            fn deref(&self) -> &Vec<T> {
+                &self.0
+            }
+        }
+
+        impl<T: Sv2Encode> Sv2Encode for $name<T> {
+            fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+                if self.0.len() > Self::MAX {
+                    return Err(Sv2Error::Codec(format!(
+                        "{} too long: {} > {}", stringify!($name), self.0.len(), Self::MAX
+                    )));
+                }
+                put_len(buf, self.0.len(), $width);
+                for item in &self.0 {
+                    item.encode(buf)?;
+                }
+                Ok(())
+            }
+        }
+
+        impl<T: Sv2Decode> Sv2Decode for $name<T> {
+            fn decode(r: &mut Sv2Reader<'_>) -> Result<Self> {
+                let cnt = get_len(r, $width)?;
+                // Every element is at least one byte; refuse absurd counts
+                // before allocating
+                if cnt > r.remaining() {
+                    return Err(Sv2Error::Codec(format!(
+                        "{} count {} exceeds {} remaining bytes",
+                        stringify!($name), cnt, r.remaining()
+                    )));
+                }
+                let mut out = Vec::with_capacity(cnt);
+                for _ in 0..cnt {
+                    out.push(T::decode(r)?);
+                }
+                Ok(Self(out))
+            }
+        }
+    };
+}
+
+sv2_seq!(Seq0_255, 1, 255);
+sv2_seq!(Seq0_64K, 2, 0xFFFF);
+
+/// OPTION[T]: a sequence of zero or one element
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct OPTION<T>(pub Option<T>);
+
+impl<T> From<Option<T>> for OPTION<T> {
+    fn from(v: Option<T>) -> Self {
+        Self(v)
+    }
+}
+
+impl<T> Deref for OPTION<T> {
+    type Target = Option<T>;
+    fn deref(&self) -> &Option<T> {
+        &self.0
+    }
+}
+
+impl<T: Sv2Encode> Sv2Encode for OPTION<T> {
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+        match &self.0 {
+            None => buf.push(0),
+            Some(v) => {
+                buf.push(1);
+                v.encode(buf)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T: Sv2Decode> Sv2Decode for OPTION<T> {
+    fn decode(r: &mut Sv2Reader<'_>) -> Result<Self> {
+        match u8::decode(r)? {
+            0 => Ok(Self(None)),
+            1 => Ok(Self(Some(T::decode(r)?))),
+            n => Err(Sv2Error::Codec(format!("OPTION with {} elements", n))),
+        }
+    }
+}
+
+// ============================================================================
+// Message definitions
+// ============================================================================
+
+/// Declare a message as an ordered field list; encoding and decoding follow
+/// field order.
+macro_rules! sv2_message {
+    (
+        $(#[$meta:meta])*
+        pub struct $name:ident {
+            $($(#[$fmeta:meta])* pub $field:ident : $ty:ty),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct $name {
+            $($(#[$fmeta])* pub $field: $ty),*
+        }
+
+        impl $crate::pool::binary::Sv2Encode for $name {
+            fn encode(&self, buf: &mut Vec<u8>) -> $crate::common::Result<()> {
+                $($crate::pool::binary::Sv2Encode::encode(&self.$field, buf)?;)*
+                Ok(())
+            }
+        }
+
+        impl $crate::pool::binary::Sv2Decode for $name {
+            fn decode(
+                r: &mut $crate::pool::binary::Sv2Reader<'_>,
+            ) -> $crate::common::Result<Self> {
+                Ok(Self {
+                    $($field: $crate::pool::binary::Sv2Decode::decode(r)?),*
+                })
+            }
+        }
+    };
+}
+
+pub(crate) use sv2_message;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip<T: Sv2Encode + Sv2Decode + PartialEq + std::fmt::Debug>(v: T) -> Vec<u8> {
+        let buf = v.serialize().unwrap();
+        assert_eq!(T::parse(&buf).unwrap(), v);
+        buf
+    }
+
+    #[test]
+    fn test_primitives() {
+        assert_eq!(roundtrip(0x0102u16), vec![0x02, 0x01]);
+        assert_eq!(roundtrip(U24(0x030201)), vec![0x01, 0x02, 0x03]);
+        assert!(U24(0x0100_0000).serialize().is_err());
+        assert_eq!(roundtrip(ShortTxId(0x060504030201)), vec![1, 2, 3, 4, 5, 6]);
+        assert!(ShortTxId(1 << 48).serialize().is_err());
+        assert_eq!(roundtrip(true), vec![1]);
+        assert!(bool::parse(&[2]).is_err());
+        assert_eq!(roundtrip(U256([7; 32])).len(), 32);
+        assert_eq!(roundtrip(F32(1.0)), 1.0f32.to_le_bytes().to_vec());
+    }
+
+    #[test]
+    fn test_length_prefixes() {
+        assert_eq!(roundtrip(B0_255(vec![9, 9])), vec![2, 9, 9]);
+        assert_eq!(roundtrip(B0_64K(vec![9])), vec![1, 0, 9]);
+        assert_eq!(roundtrip(B0_16M(vec![9])), vec![1, 0, 0, 9]);
+        assert_eq!(roundtrip(STR0_255::from("ab")), vec![2, b'a', b'b']);
+
+        assert!(B0_32(vec![0; 33]).serialize().is_err());
+        assert!(B0_32::parse(&[33]).is_err());
+        assert!(B0_255(vec![0; 256]).serialize().is_err());
+        assert!(STR0_255::parse(&[1, 0xFF]).is_err());
+    }
+
+    #[test]
+    fn test_sequences_and_option() {
+        let seq: Seq0_64K<u16> = vec![1, 2].into();
+        assert_eq!(roundtrip(seq), vec![2, 0, 1, 0, 2, 0]);
+
+        let nested: Seq0_255<B0_16M> = vec![B0_16M(vec![5])].into();
+        assert_eq!(roundtrip(nested), vec![1, 1, 0, 0, 5]);
+
+        assert_eq!(roundtrip(OPTION::<u8>(None)), vec![0]);
+        assert_eq!(roundtrip(OPTION(Some(3u8))), vec![1, 3]);
+        assert!(OPTION::<u8>::parse(&[2, 3, 3]).is_err());
+
+        // Count claims more elements than there are bytes
+        assert!(Seq0_64K::<u16>::parse(&[0xFF, 0xFF, 0x00]).is_err());
+    }
+
+    #[test]
+    fn test_strict_parse() {
+        assert!(u32::parse(&[1, 2, 3]).is_err());
+        assert!(u16::parse(&[1, 2, 3]).is_err());
+    }
+}
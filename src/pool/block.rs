@@ -0,0 +1,150 @@
+//! Block assembly for shares that meet the network target
+
+use crate::common::{MiningJob, Share, Solution, TplHeader};
+use super::tx::{write_compact_size, Transaction};
+use super::work;
+
+/// Build the block for a share on `job` if its header meets the network
+/// target. The share's hash is recomputed rather than trusted.
+pub fn assemble(job: &MiningJob, share: &Share) -> Option<Solution> {
+    let mut coinbase = Vec::with_capacity(job.cb_prefix.len() + share.extranonce.len() + job.cb_suffix.len());
+    coinbase.extend_from_slice(&job.cb_prefix);
+    coinbase.extend_from_slice(&share.extranonce);
+    coinbase.extend_from_slice(&job.cb_suffix);
+
+    let root = work::merkle_root_from_path(work::sha256d(&coinbase), &job.merkle_path);
+    let header = work::block_header(
+        share.version,
+        &job.prev_hash,
+        &root,
+        share.ntime,
+        job.bits,
+        share.nonce,
+    );
+    let hash = work::sha256d(&header);
+
+    if !work::le_lte(&hash, &work::target_from_bits(job.bits)) {
+        return None;
+    }
+
+    // The job's coinbase is the stripped form; a block that commits to
+    // witnesses needs the witness reserved value back on it
+    if job.cb_witness {
+        let mut tx = Transaction::parse(&coinbase).ok()?;
+        tx.inputs[0].witness = vec![vec![0u8; 32]];
+        coinbase = tx.serialize();
+    }
+
+    let mut block = header.to_vec();
+    write_compact_size(&mut block, job.txs.len() as u64 + 1);
+    block.extend_from_slice(&coinbase);
+    for tx in job.txs.iter() {
+        block.extend_from_slice(tx);
+    }
+
+    let mut extranonce = job.extranonce_prefix.clone();
+    extranonce.extend_from_slice(&share.extranonce);
+
+    Some(Solution {
+        tpl_id: job.tpl_id,
+        hash,
+        header: TplHeader {
+            version: share.version,
+            prev_hash: job.prev_hash,
+            bits: job.bits,
+            time: share.ntime,
+        },
+        nonce: share.nonce,
+        extranonce,
+        coinbase,
+        block,
+        solo: job.solo,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn job(bits: u32) -> MiningJob {
+        MiningJob {
+            tpl_id: 1,
+            version: 0x20000000,
+            prev_hash: [1; 32],
+            bits,
+            time: 0,
+            min_time: 0,
+            cb_prefix: vec![0xAA; 10],
+            cb_suffix: vec![0xBB; 10],
+            extranonce_len: 4,
+            extranonce_prefix: vec![0xCC; 2],
+            merkle_path: Vec::new(),
+            txs: Arc::new(vec![vec![0xDD; 5]]),
+            cb_witness: false,
+            solo: false,
+        }
+    }
+
+    fn share() -> Share {
+        Share {
+            tpl_id: 1,
+            version: 0x20000000,
+            ntime: 1_700_000_000,
+            nonce: 0,
+            extranonce: vec![0xEE; 4],
+            hash: [0; 32],
+            solo: false,
+        }
+    }
+
+    #[test]
+    fn test_solve_builds_block() {
+        // Regtest difficulty: every other hash qualifies, so find a nonce
+        let j = job(0x207fffff);
+        let sol = (0..64)
+            .find_map(|nonce| assemble(&j, &Share { nonce, ..share() }))
+            .expect("no regtest solution in 64 nonces");
+
+        assert_eq!(sol.coinbase.len(), 10 + 4 + 10);
+        assert_eq!(sol.extranonce, [vec![0xCC; 2], vec![0xEE; 4]].concat());
+        assert_eq!(sol.block.len(), 80 + 1 + sol.coinbase.len() + 5);
+        assert_eq!(sol.block[80], 2);
+        assert_eq!(work::sha256d(&sol.block[..80]), sol.hash);
+    }
+
+    #[test]
+    fn test_solve_restores_coinbase_witness() {
+        use crate::common::CoinbaseOut;
+        use crate::pool::sv2_messages::{build_cb_prefix, build_cb_suffix, cb_strip_witness};
+
+        let outs = [CoinbaseOut { value: 50, script_pubkey: vec![0x51] }];
+        let prefix = build_cb_prefix(2, 100, b"t", 4);
+        let suffix = build_cb_suffix(&outs, Some(&[0x33; 32]));
+        let (cb_prefix, cb_suffix) = cb_strip_witness(&prefix, &suffix);
+        let j = MiningJob {
+            cb_prefix: cb_prefix.clone(),
+            cb_suffix: cb_suffix.clone(),
+            extranonce_prefix: Vec::new(),
+            cb_witness: true,
+            ..job(0x207fffff)
+        };
+
+        let sol = (0..64)
+            .find_map(|nonce| assemble(&j, &Share { nonce, ..share() }))
+            .expect("no regtest solution in 64 nonces");
+
+        let stripped = [cb_prefix, vec![0xEE; 4], cb_suffix].concat();
+        let cb = Transaction::parse(&sol.coinbase).unwrap();
+        assert!(cb.has_witness());
+        assert_eq!(cb.txid(), work::sha256d(&stripped));
+        assert_eq!(sol.coinbase, [prefix, vec![0xEE; 4], suffix].concat());
+    }
+
+    #[test]
+    fn test_solve_checks_network_target() {
+        // Mainnet difficulty 1 won't be met by a handful of nonces
+        let j = job(0x1d00ffff);
+        assert!((0..64).all(|nonce| assemble(&j, &Share { nonce, ..share() }).is_none()));
+    }
+}
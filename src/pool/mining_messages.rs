@@ -0,0 +1,250 @@
+//! Stratum V2 Mining Protocol Messages
+
+use super::binary::*;
+
+pub mod msg_types {
+    pub const OPEN_STANDARD_CHANNEL: u8 = 0x10;
+    pub const OPEN_STANDARD_CHANNEL_OK: u8 = 0x11;
+    pub const OPEN_CHANNEL_ERR: u8 = 0x12;
+    pub const OPEN_EXTENDED_CHANNEL: u8 = 0x13;
+    pub const OPEN_EXTENDED_CHANNEL_OK: u8 = 0x14;
+    pub const NEW_MINING_JOB: u8 = 0x15;
+    pub const SUBMIT_SHARES_STANDARD: u8 = 0x1A;
+    pub const SUBMIT_SHARES_EXTENDED: u8 = 0x1B;
+    pub const SUBMIT_SHARES_OK: u8 = 0x1C;
+    pub const SUBMIT_SHARES_ERR: u8 = 0x1D;
+    pub const NEW_EXTENDED_JOB: u8 = 0x1F;
+    pub const SET_NEW_PREV_HASH: u8 = 0x20;
+    pub const SET_TARGET: u8 = 0x21;
+    pub const SET_CUSTOM_MINING_JOB: u8 = 0x22;
+    pub const SET_CUSTOM_MINING_JOB_OK: u8 = 0x23;
+    pub const SET_CUSTOM_MINING_JOB_ERR: u8 = 0x24;
+}
+
+pub const MINING_EXT: u16 = 0x0000;
+pub const PROTO_MINING: u8 = 0;
+
+/// Set in the extension field for messages addressed to a channel
+pub const CHANNEL_BIT: u16 = 0x8000;
+
+/// SetupConnection flags for the Mining protocol
+pub mod mining_flags {
+    // Sent by the downstream
+    pub const REQUIRES_WORK_SELECTION: u32 = 1 << 1;
+    pub const REQUIRES_VERSION_ROLLING: u32 = 1 << 2;
+}
+
+// ============================================================================
+// Channel setup
+// ============================================================================
+
+sv2_message! {
+    pub struct OpenStandardChannel {
+        pub req_id: u32,
+        pub user: STR0_255,
+        pub hashrate: F32,
+        pub max_target: U256,
+    }
+}
+
+sv2_message! {
+    pub struct OpenStandardChannelOk {
+        pub req_id: u32,
+        pub channel_id: u32,
+        pub target: U256,
+        pub extranonce_prefix: B0_32,
+        pub group_channel_id: u32,
+    }
+}
+
+sv2_message! {
+    pub struct OpenExtendedChannel {
+        pub req_id: u32,
+        pub user: STR0_255,
+        pub hashrate: F32,
+        pub max_target: U256,
+        pub min_extranonce_size: u16,
+    }
+}
+
+sv2_message! {
+    pub struct OpenExtendedChannelOk {
+        pub req_id: u32,
+        pub channel_id: u32,
+        pub target: U256,
+        pub extranonce_size: u16,
+        pub extranonce_prefix: B0_32,
+    }
+}
+
+sv2_message! {
+    pub struct OpenChannelErr {
+        pub req_id: u32,
+        pub code: STR0_255,
+    }
+}
+
+sv2_message! {
+    pub struct SetTarget {
+        pub channel_id: u32,
+        pub max_target: U256,
+    }
+}
+
+// ============================================================================
+// Jobs
+// ============================================================================
+
+sv2_message! {
+    pub struct NewMiningJob {
+        pub channel_id: u32,
+        pub job_id: u32,
+        pub min_ntime: OPTION<u32>,
+        pub version: u32,
+        pub merkle_root: U256,
+    }
+}
+
+sv2_message! {
+    pub struct NewExtendedJob {
+        pub channel_id: u32,
+        pub job_id: u32,
+        pub min_ntime: OPTION<u32>,
+        pub version: u32,
+        pub version_rolling: bool,
+        pub merkle_path: Seq0_255<U256>,
+        pub cb_prefix: B0_64K,
+        pub cb_suffix: B0_64K,
+    }
+}
+
+sv2_message! {
+    pub struct SetNewPrevHash {
+        pub channel_id: u32,
+        pub job_id: u32,
+        pub prev_hash: U256,
+        pub min_ntime: u32,
+        pub bits: u32,
+    }
+}
+
+// ============================================================================
+// Custom jobs (work selection)
+// ============================================================================
+
+sv2_message! {
+    pub struct SetCustomMiningJob {
+        pub channel_id: u32,
+        pub req_id: u32,
+        pub token: B0_255,
+        pub version: u32,
+        pub prev_hash: U256,
+        pub min_ntime: u32,
+        pub bits: u32,
+        pub cb_tx_version: u32,
+        pub cb_prefix: B0_255,
+        pub cb_sequence: u32,
+        pub cb_value_remaining: u64,
+        pub cb_outputs: B0_64K,
+        pub cb_locktime: u32,
+        pub merkle_path: Seq0_255<U256>,
+        pub extranonce_size: u16,
+    }
+}
+
+sv2_message! {
+    pub struct SetCustomMiningJobOk {
+        pub channel_id: u32,
+        pub req_id: u32,
+        pub job_id: u32,
+    }
+}
+
+sv2_message! {
+    pub struct SetCustomMiningJobErr {
+        pub channel_id: u32,
+        pub req_id: u32,
+        pub code: STR0_255,
+    }
+}
+
+// ============================================================================
+// Shares
+// ============================================================================
+
+sv2_message! {
+    pub struct SubmitSharesStandard {
+        pub channel_id: u32,
+        pub seq: u32,
+        pub job_id: u32,
+        pub nonce: u32,
+        pub ntime: u32,
+        pub version: u32,
+    }
+}
+
+sv2_message! {
+    pub struct SubmitSharesExtended {
+        pub channel_id: u32,
+        pub seq: u32,
+        pub job_id: u32,
+        pub nonce: u32,
+        pub ntime: u32,
+        pub version: u32,
+        pub extranonce: B0_32,
+    }
+}
+
+sv2_message! {
+    pub struct SubmitSharesOk {
+        pub channel_id: u32,
+        pub last_seq: u32,
+        pub accepted: u32,
+        pub shares_sum: u64,
+    }
+}
+
+sv2_message! {
+    pub struct SubmitSharesErr {
+        pub channel_id: u32,
+        pub seq: u32,
+        pub code: STR0_255,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extended_job_roundtrip() {
+        let job = NewExtendedJob {
+            channel_id: 1,
+            job_id: 2,
+            min_ntime: OPTION(None),
+            version: 0x20000000,
+            version_rolling: true,
+            merkle_path: vec![U256([3; 32])].into(),
+            cb_prefix: vec![1, 2, 3].into(),
+            cb_suffix: vec![4, 5].into(),
+        };
+        let buf = job.serialize().unwrap();
+        // channel(4) job(4) option(1) version(4) bool(1) path(1+32) prefix(2+3) suffix(2+2)
+        assert_eq!(buf.len(), 4 + 4 + 1 + 4 + 1 + 33 + 5 + 4);
+        assert_eq!(NewExtendedJob::parse(&buf).unwrap(), job);
+    }
+
+    #[test]
+    fn test_submit_extended_parse() {
+        let mut raw = Vec::new();
+        for v in [7u32, 1, 2, 0xDEADBEEF, 1_700_000_000, 0x20000000] {
+            raw.extend_from_slice(&v.to_le_bytes());
+        }
+        raw.extend_from_slice(&[4, 0xAA, 0xBB, 0xCC, 0xDD]);
+
+        let s = SubmitSharesExtended::parse(&raw).unwrap();
+        assert_eq!(s.channel_id, 7);
+        assert_eq!(s.nonce, 0xDEADBEEF);
+        assert_eq!(s.extranonce.as_slice(), &[0xAA, 0xBB, 0xCC, 0xDD]);
+    }
+}
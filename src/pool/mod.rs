@@ -1,24 +1,138 @@
 //! Pool Client - Stratum V2 Job Declaration Protocol
 
+pub mod binary;
+pub mod block;
+pub mod codec;
+pub mod noise;
+pub mod mining_messages;
+pub mod solo;
 pub mod sv2_messages;
+pub mod tokens;
+pub mod tx;
+pub mod upstream;
+pub mod work;
 
-use bytes::BytesMut;
-use noise_sv2::{Initiator, NoiseCodec};
+pub use solo::{SoloConfig, SoloFallback};
+pub use upstream::MiningUpstream;
+
+use futures::{SinkExt, StreamExt};
+use noise_sv2::NoiseCodec;
+use secp256k1::XOnlyPublicKey;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tokio::sync::{broadcast, mpsc};
+use tokio_util::codec::Framed;
 use tracing::{debug, error, info, warn};
 
-use crate::common::{Event, CoinbaseOut, Sv2Error, Result};
+use crate::common::{
+    Event, CoinbaseOut, CustomJob, MiningJob, PoolCoinbase, Solution, Sv2Error, Result, TplSnapshot,
+};
+use codec::{Sv2Frame, Sv2NoiseCodec};
 use sv2_messages::*;
+use tokens::TokenPool;
+use tx::Transaction;
+
+/// Fresh short-hash nonces tried before giving up on a template whose
+/// short IDs keep colliding
+const MAX_NONCE_ROLLS: usize = 8;
+
+/// Hard cap on declarations in flight, whatever `max_in_flight` says. Each
+/// one holds its template's transactions until it resolves.
+const MAX_PENDING: usize = 32;
+
+/// How often unanswered declarations are looked for
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PoolConnConfig {
     pub address: String,
+    /// Base58-check authority key that must sign the pool's Noise static key
+    #[serde(default)]
+    pub authority_pubkey: Option<String>,
+    /// Ask the pool to let us mine on a token before its job is acknowledged
+    #[serde(default)]
+    pub async_mining: bool,
+    #[serde(default = "default_vendor")]
+    pub vendor: String,
+    #[serde(default)]
+    pub hardware_version: String,
+    #[serde(default = "default_firmware")]
+    pub firmware: String,
+    #[serde(default)]
+    pub device_id: String,
+    /// Pool's Mining Protocol endpoint. When set, declared jobs are mined
+    /// on an extended channel there via SetCustomMiningJob.
+    #[serde(default)]
+    pub mining_address: Option<String>,
+    #[serde(default = "default_user")]
+    pub user_identity: String,
+    /// Nominal hashrate (H/s) reported when opening the mining channel
+    #[serde(default = "default_hashrate")]
+    pub hashrate: f32,
+    /// Declarations in flight at once when the pool allows async mining;
+    /// otherwise each waits for the previous one to resolve
+    #[serde(default = "default_max_in_flight")]
+    pub max_in_flight: usize,
+    /// Job tokens kept allocated ahead of declarations
+    #[serde(default = "default_token_pool")]
+    pub token_pool: usize,
+    /// Seconds an unused token is trusted for
+    #[serde(default = "default_token_ttl")]
+    pub token_ttl: u64,
+    /// Seconds to wait for the pool to answer a declaration
+    #[serde(default = "default_decl_timeout")]
+    pub decl_timeout: u64,
+    /// Declare the newest template again when a declaration times out
+    #[serde(default = "default_decl_retry")]
+    pub decl_retry: bool,
+}
+
+impl PoolConnConfig {
+    pub fn authority_key(&self) -> Result<Option<XOnlyPublicKey>> {
+        match self.authority_pubkey.as_deref().map(str::trim) {
+            None | Some("") => Ok(None),
+            Some(s) => noise::parse_authority_key(s).map(Some),
+        }
+    }
+}
+
+fn default_vendor() -> String {
+    "sv2-jdc".into()
+}
+
+fn default_firmware() -> String {
+    env!("CARGO_PKG_VERSION").into()
+}
+
+fn default_user() -> String {
+    "sv2-jdc".into()
+}
+
+fn default_hashrate() -> f32 {
+    100e12
+}
+
+fn default_max_in_flight() -> usize {
+    4
+}
+
+fn default_token_pool() -> usize {
+    4
+}
+
+fn default_token_ttl() -> u64 {
+    600
+}
+
+fn default_decl_timeout() -> u64 {
+    30
+}
+
+fn default_decl_retry() -> bool {
+    true
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -29,25 +143,25 @@ enum Handshake {
     Done,
 }
 
+/// Common-protocol SetupConnection negotiation, run after the Noise
+/// handshake. Nothing JD-specific is sent until this reaches `Done`.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum DeclState {
-    NeedToken,
-    AwaitToken { req: u32 },
-    Ready,
-    Pending { req: u32 },
-    AwaitTx { req: u32 },
+enum Setup {
+    Idle,
+    Sent,
+    Done { flags: u32 },
 }
 
 #[derive(Debug, Clone)]
 struct PendingDecl {
     tpl_id: u64,
-    req_id: u32,
-    txs: Vec<Vec<u8>>,
-    txids: Vec<[u8; 32]>,
-    #[allow(dead_code)]
+    txs: Arc<Vec<Vec<u8>>>,
     nonce: u64,
-    #[allow(dead_code)]
     sent_at: Instant,
+    job: MiningJob,
+    custom: CustomJob,
+    /// Handed to miners on declaration rather than on acknowledgement
+    early: bool,
 }
 
 pub struct PoolClient {
@@ -55,14 +169,32 @@ pub struct PoolClient {
     bus_tx: broadcast::Sender<Event>,
     bus_rx: broadcast::Receiver<Event>,
     hs_state: Handshake,
-    decl_state: DeclState,
-    token: Option<Vec<u8>>,
+    setup: Setup,
+    tokens: TokenPool,
     req_seq: u32,
     hash_nonce: u64,
     pending: HashMap<u32, PendingDecl>,
-    blk_version: u32,
-    blk_height: u64,
-    coinbase_val: u64,
+    /// Whether the pool's latest token allows async mining
+    async_ok: bool,
+    /// Coinbase requirements of the pool's latest token
+    pool_cb: Option<PoolCoinbase>,
+    /// Newest template that arrived while declarations couldn't go out
+    queued: Option<(Arc<TplSnapshot>, Vec<CoinbaseOut>)>,
+    /// Newest template seen at all, for retrying a timed-out declaration
+    latest: Option<(Arc<TplSnapshot>, Vec<CoinbaseOut>)>,
+    /// Prev hash of the current chain tip, internal byte order
+    tip: Option<[u8; 32]>,
+    /// Miners are on solo jobs; declarations go on, but their jobs are
+    /// kept from miners until the pool is usable again
+    solo: bool,
+    /// Newest job the pool acknowledged while we were mining solo
+    held: Option<(CustomJob, MiningJob)>,
+    /// Newest template handed to miners, so a late acknowledgement of an
+    /// older declaration doesn't take them back
+    live_tpl: Option<u64>,
+    /// Extranonce prefix of the upstream mining channel. Declarations
+    /// wait for it when a mining endpoint is configured.
+    upstream_prefix: Option<Vec<u8>>,
 }
 
 impl PoolClient {
@@ -71,19 +203,32 @@ impl PoolClient {
         bus_tx: broadcast::Sender<Event>,
         bus_rx: broadcast::Receiver<Event>,
     ) -> Self {
+        let upstream_prefix = match cfg.mining_address {
+            Some(_) => None,
+            None => Some(Vec::new()),
+        };
+
+        let tokens = TokenPool::new(cfg.token_pool, Duration::from_secs(cfg.token_ttl));
+
         Self {
             cfg,
             bus_tx,
             bus_rx,
             hs_state: Handshake::Init,
-            decl_state: DeclState::NeedToken,
-            token: None,
+            setup: Setup::Idle,
+            tokens,
             req_seq: 0,
             hash_nonce: rand::random(),
             pending: HashMap::new(),
-            blk_version: 0x20000000,
-            blk_height: 0,
-            coinbase_val: 0,
+            async_ok: false,
+            pool_cb: None,
+            queued: None,
+            latest: None,
+            tip: None,
+            solo: false,
+            held: None,
+            live_tpl: None,
+            upstream_prefix,
         }
     }
 
@@ -95,17 +240,20 @@ impl PoolClient {
     pub async fn run(mut self) -> Result<()> {
         info!("Pool client starting");
 
-        loop {
-            let addr: SocketAddr = self
-                .cfg
-                .address
-                .parse()
-                .map_err(|e| Sv2Error::PoolConnection(format!("bad addr: {}", e)))?;
+        let authority = self.cfg.authority_key()?;
+        if authority.is_none() {
+            warn!("No authority_pubkey configured, pool identity is NOT verified");
+        }
+
+        let addr = self.cfg.address.clone();
+        split_host_port(&addr)?;
 
+        loop {
             let _ = self.bus_tx.send(Event::PoolConnecting);
             info!("Connecting to {}", addr);
 
-            let stream = match TcpStream::connect(addr).await {
+            // Resolved on every attempt, so a pool that moves is followed
+            let stream = match TcpStream::connect(addr.as_str()).await {
                 Ok(s) => s,
                 Err(e) => {
                     error!("Connect failed: {}", e);
@@ -119,19 +267,24 @@ impl PoolClient {
             let _ = self.bus_tx.send(Event::PoolUp);
             info!("TCP connected");
 
-            match self.handshake(stream).await {
+            match self.handshake(stream, authority).await {
                 Ok((s, codec)) => {
                     info!("Noise handshake done");
                     self.hs_state = Handshake::Done;
                     let _ = self.bus_tx.send(Event::HandshakeDone);
 
-                    self.decl_state = DeclState::NeedToken;
-                    self.token = None;
+                    self.setup = Setup::Idle;
+                    self.tokens.reset();
+                    self.async_ok = false;
+                    self.pool_cb = None;
+                    // Request IDs don't carry over to a new connection
+                    self.pending.clear();
 
                     if let Err(e) = self.run_protocol(s, codec).await {
                         error!("Protocol error: {}", e);
                         let _ = self.bus_tx.send(Event::Err(e.to_string()));
                     }
+                    let _ = self.bus_tx.send(Event::PoolDown);
                 }
                 Err(e) => {
                     error!("Handshake failed: {}", e);
@@ -144,136 +297,64 @@ impl PoolClient {
         }
     }
 
-    async fn handshake(&mut self, mut stream: TcpStream) -> Result<(TcpStream, NoiseCodec)> {
+    async fn handshake(
+        &mut self,
+        mut stream: TcpStream,
+        authority: Option<XOnlyPublicKey>,
+    ) -> Result<(TcpStream, NoiseCodec)> {
         let _ = self.bus_tx.send(Event::Handshaking);
-        info!("Starting Noise NX");
-
-        let mut init = Initiator::new(None);
-
-        // Step 0: Generate and send ephemeral public key
-        let msg0 = init
-            .step_0()
-            .map_err(|e| Sv2Error::NoiseHandshake(format!("step0: {:?}", e)))?;
-
-        debug!("Sending {} bytes", msg0.len());
-        stream
-            .write_all(&msg0)
-            .await
-            .map_err(|e| Sv2Error::NoiseHandshake(format!("send: {}", e)))?;
+        info!("Starting Noise NX (authority check: {})", authority.is_some());
 
         self.hs_state = Handshake::Sent;
-
-        // Read responder's message (contains their keys + signature)
-        let mut buf = vec![0u8; 1024];
-        let n = stream
-            .read(&mut buf)
-            .await
-            .map_err(|e| Sv2Error::NoiseHandshake(format!("recv: {}", e)))?;
-
-        if n == 0 {
-            return Err(Sv2Error::NoiseHandshake("closed".into()));
-        }
-
-        buf.truncate(n);
-        debug!("Received {} bytes", n);
-
-        // Step 2: Process responder message and get codec
-        // noise_sv2 expects exactly 234 bytes for the handshake response
-        const EXPECTED_LEN: usize = 234;
-        if buf.len() < EXPECTED_LEN {
-            return Err(Sv2Error::NoiseHandshake(
-                format!("response too short: {} < {}", buf.len(), EXPECTED_LEN)
-            ));
-        }
-        
-        let mut response: [u8; EXPECTED_LEN] = [0u8; EXPECTED_LEN];
-        response.copy_from_slice(&buf[..EXPECTED_LEN]);
-        
-        let codec = init
-            .step_2(response)
-            .map_err(|e| Sv2Error::NoiseHandshake(format!("step2: {:?}", e)))?;
+        let codec = noise::initiator_handshake(&mut stream, authority).await?;
 
         info!("Encrypted channel ready");
         Ok((stream, codec))
     }
 
-    async fn run_protocol(&mut self, stream: TcpStream, mut codec: NoiseCodec) -> Result<()> {
+    async fn run_protocol(&mut self, stream: TcpStream, codec: NoiseCodec) -> Result<()> {
         info!("Running SV2 protocol");
 
-        let (mut rd, mut wr) = stream.into_split();
-        let (out_tx, mut out_rx) = mpsc::channel::<Vec<u8>>(32);
+        let framed = Framed::new(stream, Sv2NoiseCodec::new(codec));
+        let (mut sink, mut frames) = framed.split();
+        let (out_tx, mut out_rx) = mpsc::channel::<Sv2Frame>(32);
+        let mut sweep = tokio::time::interval(SWEEP_INTERVAL);
 
-        self.request_token(&out_tx, &mut codec, &mut wr).await?;
-
-        let mut buf = BytesMut::with_capacity(65536);
+        self.setup_connection(&out_tx).await?;
 
         loop {
             tokio::select! {
-                res = rd.read_buf(&mut buf) => {
+                res = frames.next() => {
                     match res {
-                        Ok(0) => {
+                        None => {
                             error!("Pool closed connection");
                             return Err(Sv2Error::PoolConnection("closed".into()));
                         }
-                        Ok(n) => {
-                            debug!("Read {} bytes", n);
-                            self.process_data(&mut buf, &mut codec, &out_tx).await?;
+                        Some(Ok(frame)) => {
+                            self.handle_msg(frame.ext, frame.mtype, &frame.payload, &out_tx).await?;
                         }
-                        Err(e) => {
+                        Some(Err(e)) => {
                             error!("Read error: {}", e);
-                            return Err(Sv2Error::Io(e));
+                            return Err(e);
                         }
                     }
                 }
 
-                Some(data) = out_rx.recv() => {
-                    let mut enc = data;
-                    codec.encrypt(&mut enc)
-                        .map_err(|e| Sv2Error::Framing(format!("encrypt: {:?}", e)))?;
-                    wr.write_all(&enc).await.map_err(Sv2Error::Io)?;
-                    debug!("Wrote {} encrypted bytes", enc.len());
+                Some(frame) = out_rx.recv() => {
+                    let len = frame.payload.len();
+                    sink.send(frame).await?;
+                    debug!("Wrote frame, payload {} bytes", len);
                 }
 
                 Ok(ev) = self.bus_rx.recv() => {
                     self.handle_event(ev, &out_tx).await?;
                 }
-            }
-        }
-    }
-
-    async fn process_data(
-        &mut self,
-        buf: &mut BytesMut,
-        codec: &mut NoiseCodec,
-        out_tx: &mpsc::Sender<Vec<u8>>,
-    ) -> Result<()> {
-        const HDR: usize = 6;
-
-        while buf.len() >= HDR {
-            let ext = u16::from_le_bytes([buf[0], buf[1]]);
-            let mtype = buf[2];
-            let mlen = u32::from_le_bytes([buf[3], buf[4], buf[5], 0]) as usize;
 
-            let total = HDR + mlen;
-            if buf.len() < total {
-                break;
+                _ = sweep.tick() => {
+                    self.sweep_pending(Instant::now(), &out_tx).await?;
+                }
             }
-
-            let frame = buf.split_to(total);
-            
-            let payload = if mlen > 0 {
-                let mut data = frame[HDR..].to_vec();
-                codec.decrypt(&mut data)
-                    .map_err(|e| Sv2Error::Framing(format!("decrypt: {:?}", e)))?;
-                data
-            } else {
-                Vec::new()
-            };
-
-            self.handle_msg(ext, mtype, &payload, out_tx).await?;
         }
-
-        Ok(())
     }
 
     async fn handle_msg(
@@ -281,19 +362,32 @@ impl PoolClient {
         ext: u16,
         mtype: u8,
         data: &[u8],
-        out_tx: &mpsc::Sender<Vec<u8>>,
+        out_tx: &mpsc::Sender<Sv2Frame>,
     ) -> Result<()> {
         debug!("SV2 msg: ext=0x{:04X}, type=0x{:02X}, len={}", ext, mtype, data.len());
 
         match mtype {
+            msg_types::SETUP_CONN_OK => {
+                self.on_setup_ok(data, out_tx).await?;
+            }
+            msg_types::SETUP_CONN_ERR => {
+                self.on_setup_err(data)?;
+            }
+            _ if !matches!(self.setup, Setup::Done { .. }) => {
+                warn!("Ignoring msg 0x{:02X} before setup", mtype);
+            }
             msg_types::ALLOC_TOKEN_OK => {
                 self.on_token_ok(data).await?;
+                self.declare_queued(out_tx).await?;
             }
             msg_types::DECL_JOB_OK => {
                 self.on_job_ok(data).await?;
+                self.declare_queued(out_tx).await?;
             }
             msg_types::DECL_JOB_ERR => {
                 self.on_job_err(data).await?;
+                self.request_tokens(out_tx).await?;
+                self.declare_queued(out_tx).await?;
             }
             msg_types::IDENTIFY_TXS => {
                 self.on_identify_txs(data, out_tx).await?;
@@ -309,25 +403,107 @@ impl PoolClient {
         Ok(())
     }
 
-    async fn request_token<W: AsyncWriteExt + Unpin>(
-        &mut self,
-        _out_tx: &mpsc::Sender<Vec<u8>>,
-        codec: &mut NoiseCodec,
-        wr: &mut W,
-    ) -> Result<()> {
-        let rid = self.next_req();
-        
-        let msg = AllocToken::new(rid, "sv2-jdc", 8);
-        let payload = msg.serialize()?;
-        let frame = build_frame(msg_types::ALLOC_TOKEN, DECL_EXT, &payload);
+    async fn setup_connection(&mut self, out_tx: &mpsc::Sender<Sv2Frame>) -> Result<()> {
+        let (host, port) = split_host_port(&self.cfg.address)?;
 
-        let mut enc = frame;
-        codec.encrypt(&mut enc)
-            .map_err(|e| Sv2Error::Framing(format!("encrypt: {:?}", e)))?;
-        wr.write_all(&enc).await.map_err(Sv2Error::Io)?;
+        let flags = if self.cfg.async_mining {
+            jd_flags::REQUIRES_ASYNC_JOB_MINING
+        } else {
+            0
+        };
+
+        let msg = SetupConn {
+            protocol: PROTO_JD,
+            min_ver: SV2_MIN_VERSION,
+            max_ver: SV2_MAX_VERSION,
+            flags,
+            host: host.into(),
+            port,
+            vendor: self.cfg.vendor.as_str().into(),
+            hw_ver: self.cfg.hardware_version.as_str().into(),
+            firmware: self.cfg.firmware.as_str().into(),
+            device_id: self.cfg.device_id.as_str().into(),
+        };
+        let frame = Sv2Frame::new(msg_types::SETUP_CONN, COMMON_EXT, msg.serialize()?);
+
+        out_tx.send(frame).await.map_err(|_| Sv2Error::ChannelSend)?;
 
-        self.decl_state = DeclState::AwaitToken { req: rid };
-        info!("Requested token (req={})", rid);
+        self.setup = Setup::Sent;
+        info!("Sent SetupConnection (flags=0x{:08X})", flags);
+
+        Ok(())
+    }
+
+    async fn on_setup_ok(&mut self, data: &[u8], out_tx: &mpsc::Sender<Sv2Frame>) -> Result<()> {
+        if self.setup != Setup::Sent {
+            return Err(Sv2Error::InvalidState(
+                format!("unexpected SetupConnection.Success in {:?}", self.setup)
+            ));
+        }
+
+        let msg = SetupConnOk::parse(data)?;
+
+        if !(SV2_MIN_VERSION..=SV2_MAX_VERSION).contains(&msg.used_ver) {
+            return Err(Sv2Error::PoolConnection(
+                format!("pool picked unsupported version {}", msg.used_ver)
+            ));
+        }
+
+        info!("Setup done: version={}, flags=0x{:08X}", msg.used_ver, msg.flags);
+        self.setup = Setup::Done { flags: msg.flags };
+        let _ = self.bus_tx.send(Event::SetupDone);
+
+        if self.cfg.async_mining && !self.async_granted() {
+            warn!("Pool did not grant async job mining");
+        }
+
+        self.request_tokens(out_tx).await
+    }
+
+    /// The pool agreed at setup to let us mine on declared jobs before it
+    /// acknowledges them; each token still says whether that's allowed
+    fn async_granted(&self) -> bool {
+        matches!(self.setup, Setup::Done { flags }
+            if flags & jd_flags::REQUIRES_ASYNC_JOB_MINING != 0)
+    }
+
+    /// Declarations allowed in flight at once. Only with async mining
+    /// granted at setup and by the latest token can more than one be out.
+    fn in_flight_limit(&self) -> usize {
+        if self.async_ok && self.async_granted() {
+            self.cfg.max_in_flight.clamp(1, MAX_PENDING)
+        } else {
+            1
+        }
+    }
+
+    fn on_setup_err(&mut self, data: &[u8]) -> Result<()> {
+        let msg = SetupConnErr::parse(data)?;
+
+        error!("Setup rejected: code={}, flags=0x{:08X}", msg.code, msg.flags);
+        self.setup = Setup::Idle;
+        let _ = self.bus_tx.send(Event::HandshakeErr(format!("setup: {}", msg.code)));
+
+        Err(Sv2Error::PoolConnection(format!("setup rejected: {}", msg.code)))
+    }
+
+    /// Top the token pool up
+    async fn request_tokens(&mut self, out_tx: &mpsc::Sender<Sv2Frame>) -> Result<()> {
+        if !matches!(self.setup, Setup::Done { .. }) {
+            return Ok(());
+        }
+
+        for _ in 0..self.tokens.wanted(Instant::now()) {
+            let rid = self.next_req();
+
+            let msg = AllocToken::new(rid, &self.cfg.user_identity, 8);
+            let frame = Sv2Frame::new(msg_types::ALLOC_TOKEN, DECL_EXT, msg.serialize()?);
+
+            out_tx.send(frame).await.map_err(|_| Sv2Error::ChannelSend)?;
+
+            self.tokens.requested(rid, Instant::now());
+            debug!("Requested token (req={})", rid);
+        }
 
         Ok(())
     }
@@ -338,8 +514,18 @@ impl PoolClient {
         info!("Got token: req={}, len={}, async={}",
             msg.req_id, msg.token.len(), msg.async_ok);
 
-        self.token = Some(msg.token);
-        self.decl_state = DeclState::Ready;
+        let coinbase = msg.pool_coinbase()?;
+        if self.pool_cb.as_ref() != Some(&coinbase) {
+            info!(
+                "Pool coinbase: {} required outputs, {} bytes of outputs allowed",
+                coinbase.outputs.len(), coinbase.max_extra
+            );
+            self.pool_cb = Some(coinbase.clone());
+            let _ = self.bus_tx.send(Event::PoolCoinbase(coinbase.clone()));
+        }
+
+        self.async_ok = msg.async_ok;
+        self.tokens.allocated(msg.req_id, msg.token.into_inner(), msg.async_ok, coinbase, Instant::now());
 
         let _ = self.bus_tx.send(Event::PoolUp);
         Ok(())
@@ -350,18 +536,26 @@ impl PoolClient {
         
         info!("Job OK: req={}, token_len={}", msg.req_id, msg.new_token.len());
 
-        if !msg.new_token.is_empty() {
-            self.token = Some(msg.new_token.clone());
-        }
-
-        if let Some(p) = self.pending.remove(&msg.req_id) {
+        if let Some(mut p) = self.pending.remove(&msg.req_id) {
+            // With async mining the job went out when it was declared
+            if !p.early && self.live_tpl.map_or(true, |live| p.tpl_id > live) {
+                if !msg.new_token.is_empty() {
+                    p.custom.token = msg.new_token.to_vec();
+                }
+                if self.solo {
+                    self.held = Some((p.custom, p.job));
+                } else {
+                    self.go_live(p.custom, p.job);
+                }
+            }
             let _ = self.bus_tx.send(Event::JobOk {
                 tpl_id: p.tpl_id,
-                token: msg.new_token,
+                token: msg.new_token.into_inner(),
             });
+        } else {
+            debug!("Late answer for req={}, already given up on", msg.req_id);
         }
 
-        self.decl_state = DeclState::Ready;
         Ok(())
     }
 
@@ -371,33 +565,41 @@ impl PoolClient {
         error!("Job failed: req={}, code={:?}, msg={}",
             msg.req_id, msg.code, msg.details);
 
+        // The rest were likely issued alongside it and won't do either
+        if msg.code == DeclErrCode::BadToken {
+            warn!("Pool rejected our token, allocating fresh ones");
+            self.tokens.flush();
+        }
+
         if let Some(p) = self.pending.remove(&msg.req_id) {
             let _ = self.bus_tx.send(Event::JobFailed {
                 tpl_id: p.tpl_id,
                 reason: format!("{:?}: {}", msg.code, msg.details),
             });
+        } else {
+            debug!("Late answer for req={}, already given up on", msg.req_id);
         }
 
-        self.decl_state = DeclState::Ready;
         Ok(())
     }
 
     async fn on_identify_txs(
         &mut self,
         data: &[u8],
-        out_tx: &mpsc::Sender<Vec<u8>>,
+        out_tx: &mpsc::Sender<Sv2Frame>,
     ) -> Result<()> {
         let msg = IdentifyTxs::parse(data)?;
         
         info!("Pool wants {} txs for req={}", msg.positions.len(), msg.req_id);
 
-        let p = self.pending.get(&msg.req_id)
-            .ok_or_else(|| Sv2Error::InvalidState(
-                format!("no pending for req={}", msg.req_id)
-            ))?;
+        // Timed out or built on a stale tip; the pool can't know yet
+        let Some(p) = self.pending.get(&msg.req_id) else {
+            warn!("Pool wants txs for req={}, already given up on, ignoring", msg.req_id);
+            return Ok(());
+        };
 
         let mut txs = Vec::new();
-        for &pos in &msg.positions {
+        for &pos in msg.positions.iter() {
             if let Some(tx) = p.txs.get(pos as usize) {
                 txs.push(tx.clone());
             } else {
@@ -405,14 +607,15 @@ impl PoolClient {
             }
         }
 
-        let resp = ProvideTxs { req_id: msg.req_id, txs: txs.clone() };
-        let payload = resp.serialize()?;
-        let frame = build_frame(msg_types::PROVIDE_TXS, DECL_EXT, &payload);
+        let resp = ProvideTxs {
+            req_id: msg.req_id,
+            txs: txs.iter().cloned().map(B0_16M::from).collect(),
+        };
+        let frame = Sv2Frame::new(msg_types::PROVIDE_TXS, DECL_EXT, resp.serialize()?);
 
         out_tx.send(frame).await.map_err(|_| Sv2Error::ChannelSend)?;
 
         info!("Sent {} txs", txs.len());
-        self.decl_state = DeclState::AwaitTx { req: msg.req_id };
         Ok(())
     }
 
@@ -422,15 +625,42 @@ impl PoolClient {
         Ok(())
     }
 
-    async fn handle_event(&mut self, ev: Event, out_tx: &mpsc::Sender<Vec<u8>>) -> Result<()> {
+    async fn handle_event(&mut self, ev: Event, out_tx: &mpsc::Sender<Sv2Frame>) -> Result<()> {
         match ev {
-            Event::DeclareJob { tpl_id, outputs, txs } => {
-                self.declare_job(tpl_id, outputs, txs, out_tx).await?;
+            Event::DeclareJob { template, outputs } => {
+                self.latest = Some((template.clone(), outputs.clone()));
+                self.declare_job(template, outputs, out_tx).await?;
+            }
+
+            Event::NewPrevHash { height, prev_hash } => {
+                self.on_new_tip(height, prev_hash);
+                self.declare_queued(out_tx).await?;
+            }
+
+            Event::SoloMode(on) => {
+                self.solo = on;
+                // Get miners back on a pool job without waiting for a template
+                match self.held.take() {
+                    Some((custom, job)) if !on => self.go_live(custom, job),
+                    _ if !on && self.queued.is_none() => {
+                        self.queued = self.latest.clone();
+                        self.declare_queued(out_tx).await?;
+                    }
+                    _ => {}
+                }
+            }
+
+            Event::ChannelUp { extranonce_prefix, .. } if self.cfg.mining_address.is_some() => {
+                self.upstream_prefix = Some(extranonce_prefix);
+                self.declare_queued(out_tx).await?;
+            }
+
+            Event::ChannelDown if self.cfg.mining_address.is_some() => {
+                self.upstream_prefix = None;
             }
 
-            Event::NewTemplate { height, fees, .. } => {
-                self.blk_height = height;
-                self.coinbase_val = fees + 312500000;
+            Event::BlockFound(sol) => {
+                self.push_solution(&sol, out_tx).await?;
             }
 
             Event::Shutdown => {
@@ -444,75 +674,666 @@ impl PoolClient {
         Ok(())
     }
 
+    /// Tell the pool about a block we found on one of our declared jobs
+    async fn push_solution(&mut self, sol: &Solution, out_tx: &mpsc::Sender<Sv2Frame>) -> Result<()> {
+        // A solo block pays only us; the node has it already
+        if sol.solo {
+            return Ok(());
+        }
+
+        let msg = PushSolution {
+            extranonce: sol.extranonce.clone().into(),
+            prev_hash: sol.header.prev_hash.into(),
+            ntime: sol.header.time,
+            nonce: sol.nonce,
+            bits: sol.header.bits,
+            version: sol.header.version,
+        };
+        let frame = Sv2Frame::new(msg_types::PUSH_SOLUTION, DECL_EXT, msg.serialize()?);
+        out_tx.send(frame).await.map_err(|_| Sv2Error::ChannelSend)?;
+
+        info!("Pushed solution for tpl={} to pool", sol.tpl_id);
+        Ok(())
+    }
+
+    /// Short IDs under the current nonce, re-rolling it on a collision
+    fn short_hashes(&mut self, wtxids: &[[u8; 32]]) -> Option<(u64, Vec<ShortTxId>)> {
+        for _ in 0..MAX_NONCE_ROLLS {
+            if let Some(shorts) = calc_short_hashes(wtxids, self.hash_nonce) {
+                return Some((self.hash_nonce, shorts));
+            }
+            warn!("Short ID collision under nonce {:016x}, re-rolling", self.hash_nonce);
+            self.hash_nonce = rand::random();
+        }
+        None
+    }
+
+    /// Hand an acknowledged job to miners and the upstream channel
+    fn go_live(&mut self, custom: CustomJob, job: MiningJob) {
+        self.live_tpl = Some(job.tpl_id);
+        let _ = self.bus_tx.send(Event::CustomJob(custom));
+        let _ = self.bus_tx.send(Event::NewMiningJob(job));
+    }
+
+    /// Abandon everything built on the old tip. The new tip's first template
+    /// then finds its slots free instead of queueing behind stale work.
+    fn on_new_tip(&mut self, height: u64, prev_hash: [u8; 32]) {
+        self.tip = Some(prev_hash);
+
+        let before = self.pending.len();
+        self.pending.retain(|_, p| p.job.prev_hash == prev_hash);
+        let mut stale = before - self.pending.len();
+
+        if self.queued.as_ref().is_some_and(|(t, _)| t.header.prev_hash != prev_hash) {
+            self.queued = None;
+            stale += 1;
+        }
+        if self.latest.as_ref().is_some_and(|(t, _)| t.header.prev_hash != prev_hash) {
+            self.latest = None;
+        }
+        if self.held.as_ref().is_some_and(|(_, j)| j.prev_hash != prev_hash) {
+            self.held = None;
+        }
+
+        if stale > 0 {
+            info!("New tip at height {}: dropped {} stale declarations", height, stale);
+            let _ = self.bus_tx.send(Event::StaleAvoided(stale as u64));
+        }
+    }
+
+    /// Top the token pool up and give up on declarations the pool hasn't
+    /// answered in time. Their slots go to the newest template, if retrying
+    /// is on.
+    async fn sweep_pending(&mut self, now: Instant, out_tx: &mpsc::Sender<Sv2Frame>) -> Result<()> {
+        // Tokens expire and requests go unanswered on their own time
+        self.request_tokens(out_tx).await?;
+
+        let timeout = Duration::from_secs(self.cfg.decl_timeout);
+        let expired: Vec<u32> = self
+            .pending
+            .iter()
+            .filter(|(_, p)| now.duration_since(p.sent_at) >= timeout)
+            .map(|(&rid, _)| rid)
+            .collect();
+        if expired.is_empty() {
+            return Ok(());
+        }
+
+        let mut timed_out = Vec::new();
+        for rid in expired {
+            let Some(p) = self.pending.remove(&rid) else { continue };
+            warn!(
+                "No answer for req={} (tpl={}, nonce={:016x}) after {}s, giving up",
+                rid, p.tpl_id, p.nonce, timeout.as_secs()
+            );
+            let _ = self.bus_tx.send(Event::JobFailed {
+                tpl_id: p.tpl_id,
+                reason: format!("timed out after {}s", timeout.as_secs()),
+            });
+            timed_out.push(p.tpl_id);
+        }
+
+        // Anything newer already held back goes first anyway
+        if self.cfg.decl_retry && self.queued.is_none() {
+            if let Some((tpl, outputs)) = &self.latest {
+                let in_flight = self.pending.values().any(|p| p.tpl_id == tpl.id);
+                let live = self.live_tpl.is_some_and(|l| l >= tpl.id) && !timed_out.contains(&tpl.id);
+                if !in_flight && !live {
+                    info!("Retrying with tpl={}", tpl.id);
+                    self.queued = Some((tpl.clone(), outputs.clone()));
+                }
+            }
+        }
+
+        self.declare_queued(out_tx).await
+    }
+
+    /// Declare the template held back last, if there's room for it now
+    async fn declare_queued(&mut self, out_tx: &mpsc::Sender<Sv2Frame>) -> Result<()> {
+        match self.queued.take() {
+            Some((tpl, outputs)) => self.declare_job(tpl, outputs, out_tx).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Why a declaration can't go out right now, if it can't
+    fn blocked(&mut self) -> Option<String> {
+        if !matches!(self.setup, Setup::Done { .. }) {
+            return Some(format!("setup not done: {:?}", self.setup));
+        }
+        if !self.tokens.has_token(Instant::now()) {
+            return Some("no token".into());
+        }
+        if self.upstream_prefix.is_none() {
+            return Some("no upstream mining channel yet".into());
+        }
+        if self.pending.len() >= self.in_flight_limit() {
+            return Some(format!("{} declarations in flight", self.pending.len()));
+        }
+        None
+    }
+
     async fn declare_job(
         &mut self,
-        tpl_id: u64,
+        tpl: Arc<TplSnapshot>,
         outputs: Vec<CoinbaseOut>,
-        txs: Vec<Vec<u8>>,
-        out_tx: &mpsc::Sender<Vec<u8>>,
+        out_tx: &mpsc::Sender<Sv2Frame>,
     ) -> Result<()> {
-        let tok = match &self.token {
-            Some(t) => t.clone(),
-            None => {
-                warn!("No token, skipping declaration");
+        if self.tip.is_some_and(|t| t != tpl.header.prev_hash) {
+            debug!("Not declaring tpl={}: built on a stale tip", tpl.id);
+            let _ = self.bus_tx.send(Event::StaleAvoided(1));
+            return Ok(());
+        }
+
+        // Only the newest template is worth declaring once there's room
+        if let Some(why) = self.blocked() {
+            debug!("Holding tpl={}: {}", tpl.id, why);
+            self.queued = Some((tpl, outputs));
+            return self.request_tokens(out_tx).await;
+        }
+
+        let tpl_id = tpl.id;
+        let header = tpl.header;
+        let up_prefix = self.upstream_prefix.clone().unwrap_or_default();
+
+        let parsed = match tpl.txs.iter().map(|t| Transaction::parse(t)).collect::<Result<Vec<_>>>() {
+            Ok(p) => p,
+            Err(e) => {
+                warn!("Bad transaction in tpl={}: {}, skipping declaration", tpl_id, e);
                 return Ok(());
             }
         };
+        let txids: Vec<[u8; 32]> = parsed.iter().map(Transaction::txid).collect();
+        let wtxids: Vec<[u8; 32]> = parsed.iter().map(Transaction::wtxid).collect();
+
+        let Some((nonce, shorts)) = self.short_hashes(&wtxids) else {
+            warn!("Short IDs for tpl={} keep colliding, skipping declaration", tpl_id);
+            return Ok(());
+        };
 
-        if self.decl_state != DeclState::Ready {
-            debug!("Not ready: {:?}", self.decl_state);
+        // The pool's outputs ride along with whichever token we spend
+        let Some(pool_cb) = self.tokens.peek(Instant::now()).map(|t| t.coinbase.clone()) else {
+            self.queued = Some((tpl, outputs));
             return Ok(());
+        };
+
+        // With nothing configured or required the whole reward goes to an
+        // OP_RETURN
+        let ours = if outputs.is_empty() && pool_cb.outputs.is_empty() {
+            vec![CoinbaseOut { value: 0, script_pubkey: vec![0x6A] }]
+        } else {
+            outputs.clone()
+        };
+        let cb_outputs = match merge_pool_outputs(&ours, &pool_cb, tpl.coinbase_value) {
+            Ok(o) => o,
+            Err(e) => {
+                warn!("Coinbase outputs break the pool's constraints, not declaring tpl={}: {}", tpl_id, e);
+                let _ = self.bus_tx.send(Event::JobFailed { tpl_id, reason: e.to_string() });
+                return Ok(());
+            }
+        };
+
+        // Without the commitment a block with segwit transactions is invalid
+        let commitment = parsed
+            .iter()
+            .any(Transaction::has_witness)
+            .then(|| calc_witness_commitment(&wtxids));
+        if let (Some(c), Some(expected)) = (&commitment, &tpl.witness_commitment) {
+            if witness_script(c) != *expected {
+                warn!("Witness commitment for tpl={} disagrees with the template source, skipping declaration", tpl_id);
+                return Ok(());
+            }
         }
 
+        let Some(token) = self.tokens.take(Instant::now()) else {
+            self.queued = Some((tpl, outputs));
+            return Ok(());
+        };
+        self.request_tokens(out_tx).await?;
+        let tok = token.bytes;
+        let early = token.async_ok && self.async_granted() && !self.solo;
+
         let rid = self.next_req();
         
-        info!("Declaring job: tpl={}, req={}, txs={}", tpl_id, rid, txs.len());
+        let vsize: usize = parsed.iter().map(Transaction::vsize).sum();
+        info!(
+            "Declaring job: tpl={}, req={}, txs={}, vsize={}, fees={}",
+            tpl_id, rid, tpl.txs.len(), vsize, tpl.fees
+        );
 
-        let txids: Vec<[u8; 32]> = txs.iter().map(|t| calc_txid(t)).collect();
-        let nonce = self.hash_nonce;
-        let shorts: Vec<u64> = txids.iter().map(|id| calc_short_hash(id, nonce)).collect();
-        let hash_list = calc_tx_list_hash(&txs);
+        let hash_list = calc_tx_list_hash(&txids);
 
-        let script = outputs
-            .first()
-            .map(|o| o.script_pubkey.clone())
-            .unwrap_or_else(|| vec![0x6A]);
+        // The upstream channel prefix sits ahead of our own extranonce
+        let extranonce_size = up_prefix.len() + CB_EXTRANONCE_LEN;
+        let prefix = build_cb_prefix(CB_TX_VERSION, tpl.height, CB_TAG, extranonce_size);
+        let suffix = build_cb_suffix(&cb_outputs, commitment.as_ref());
 
-        let prefix = build_cb_prefix(self.blk_version, self.blk_height, b"sv2-jdc");
-        let suffix = build_cb_suffix(self.coinbase_val, &script, None);
+        let (mut job_prefix, job_suffix) = cb_strip_witness(&prefix, &suffix);
+        job_prefix.extend_from_slice(&up_prefix);
+
+        let custom = CustomJob {
+            tpl_id,
+            token: tok.clone(),
+            header,
+            script_prefix: cb_script_prefix(tpl.height, CB_TAG),
+            cb_value: tpl.coinbase_value,
+            cb_outputs: build_cb_outputs(&cb_outputs, commitment.as_ref()),
+            merkle_path: merkle_path(&txids),
+            extranonce_size: extranonce_size as u16,
+        };
+        let mining_job = MiningJob {
+            tpl_id,
+            version: header.version,
+            prev_hash: header.prev_hash,
+            bits: header.bits,
+            time: header.time,
+            min_time: tpl.min_time,
+            cb_prefix: job_prefix,
+            cb_suffix: job_suffix,
+            extranonce_len: CB_EXTRANONCE_LEN,
+            extranonce_prefix: up_prefix,
+            merkle_path: merkle_path(&txids),
+            txs: tpl.txs.clone(),
+            cb_witness: commitment.is_some(),
+            solo: false,
+        };
 
         let job = DeclJob {
             req_id: rid,
-            token: tok,
-            version: self.blk_version,
-            cb_prefix: prefix,
-            cb_suffix: suffix,
+            token: tok.into(),
+            version: header.version,
+            cb_prefix: prefix.into(),
+            cb_suffix: suffix.into(),
             hash_nonce: nonce,
-            short_hashes: shorts,
-            tx_list_hash: hash_list,
-            extra: Vec::new(),
+            short_hashes: shorts.into(),
+            tx_list_hash: hash_list.into(),
+            extra: B0_64K::default(),
         };
 
-        let payload = job.serialize()?;
-        let frame = build_frame(msg_types::DECL_JOB, DECL_EXT, &payload);
+        let frame = Sv2Frame::new(msg_types::DECL_JOB, DECL_EXT, job.serialize()?);
 
-        let tx_count = txs.len();
+        let tx_count = tpl.txs.len();
         self.pending.insert(rid, PendingDecl {
             tpl_id,
-            req_id: rid,
-            txs,
-            txids,
+            txs: tpl.txs.clone(),
             nonce,
             sent_at: Instant::now(),
+            job: mining_job.clone(),
+            custom: custom.clone(),
+            early,
         });
 
         out_tx.send(frame).await.map_err(|_| Sv2Error::ChannelSend)?;
 
-        self.decl_state = DeclState::Pending { req: rid };
+        if early {
+            self.go_live(custom, mining_job);
+        }
 
         let _ = self.bus_tx.send(Event::JobSent { tpl_id, txs: tx_count });
-        info!("Job sent: req={}", rid);
+        info!("Job sent: req={}, in flight={}", rid, self.pending.len());
         
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::TplHeader;
+
+    /// A client past setup with async mining granted, the bus it publishes
+    /// on and the frames it sends
+    struct Harness {
+        pc: PoolClient,
+        bus: broadcast::Receiver<Event>,
+        out_tx: mpsc::Sender<Sv2Frame>,
+        out_rx: mpsc::Receiver<Sv2Frame>,
+    }
+
+    /// `cfg` is added to the pool section after the address
+    fn harness(cfg: &str) -> Harness {
+        let cfg: PoolConnConfig = toml::from_str(&format!("address = \"127.0.0.1:34254\"\n{}", cfg)).unwrap();
+        let (tx, rx) = broadcast::channel(64);
+        let bus = tx.subscribe();
+        let mut pc = PoolClient::new(cfg, tx, rx);
+        pc.setup = Setup::Done { flags: jd_flags::REQUIRES_ASYNC_JOB_MINING };
+        let (out_tx, out_rx) = mpsc::channel(16);
+        Harness { pc, bus, out_tx, out_rx }
+    }
+
+    fn tpl(id: u64) -> Arc<TplSnapshot> {
+        tpl_on(id, [1; 32])
+    }
+
+    fn tpl_on(id: u64, prev_hash: [u8; 32]) -> Arc<TplSnapshot> {
+        Arc::new(TplSnapshot {
+            id,
+            height: 840_000,
+            header: TplHeader { version: 0x20000000, prev_hash, bits: 0x1d00ffff, time: 2 },
+            min_time: 1,
+            txs: Arc::new(Vec::new()),
+            fees: 0,
+            coinbase_value: 312_500_000,
+            witness_commitment: None,
+        })
+    }
+
+    async fn token(pc: &mut PoolClient, req_id: u32, async_ok: bool, out_tx: &mpsc::Sender<Sv2Frame>) {
+        let ok = AllocTokenOk {
+            req_id,
+            token: vec![req_id as u8; 4].into(),
+            max_cb_extra: 100,
+            async_ok,
+            cb_outputs: vec![0].into(),
+        };
+        pc.handle_msg(DECL_EXT, msg_types::ALLOC_TOKEN_OK, &ok.serialize().unwrap(), out_tx).await.unwrap();
+    }
+
+    /// Async tokens for requests 1 to `n`
+    async fn tokens(pc: &mut PoolClient, n: u32, out_tx: &mpsc::Sender<Sv2Frame>) {
+        for r in 1..=n {
+            token(pc, r, true, out_tx).await;
+        }
+    }
+
+    fn sent(out_rx: &mut mpsc::Receiver<Sv2Frame>, mtype: u8) -> Vec<Sv2Frame> {
+        std::iter::from_fn(|| out_rx.try_recv().ok()).filter(|f| f.mtype == mtype).collect()
+    }
+
+    #[tokio::test]
+    async fn test_token_pool() {
+        let Harness { mut pc, out_tx, mut out_rx, .. } = harness("max_in_flight = 4");
+
+        pc.request_tokens(&out_tx).await.unwrap();
+        let reqs: Vec<u32> = sent(&mut out_rx, msg_types::ALLOC_TOKEN)
+            .iter()
+            .map(|f| AllocToken::parse(&f.payload).unwrap().req_id)
+            .collect();
+        assert_eq!(reqs.len(), 4);
+
+        // Nothing more is asked for while they're on their way
+        pc.request_tokens(&out_tx).await.unwrap();
+        assert!(sent(&mut out_rx, msg_types::ALLOC_TOKEN).is_empty());
+
+        for &r in &reqs {
+            token(&mut pc, r, true, &out_tx).await;
+        }
+
+        // Each declaration spends its own token and orders a replacement
+        pc.declare_job(tpl(1), Vec::new(), &out_tx).await.unwrap();
+        pc.declare_job(tpl(2), Vec::new(), &out_tx).await.unwrap();
+        let frames = std::iter::from_fn(|| out_rx.try_recv().ok()).collect::<Vec<_>>();
+        let tokens: Vec<Vec<u8>> = frames
+            .iter()
+            .filter(|f| f.mtype == msg_types::DECL_JOB)
+            .map(|f| DeclJob::parse(&f.payload).unwrap().token.into_inner())
+            .collect();
+        assert_eq!(tokens, vec![vec![reqs[0] as u8; 4], vec![reqs[1] as u8; 4]]);
+        assert_eq!(frames.iter().filter(|f| f.mtype == msg_types::ALLOC_TOKEN).count(), 2);
+        assert!(pc.pending.values().all(|p| p.early));
+
+        // A rejected token takes the rest with it
+        let req = *pc.pending.keys().next().unwrap();
+        let err = DeclJobErr { req_id: req, code: DeclErrCode::BadToken, details: "".into() };
+        pc.handle_msg(DECL_EXT, msg_types::DECL_JOB_ERR, &err.serialize().unwrap(), &out_tx).await.unwrap();
+        assert!(!pc.tokens.has_token(Instant::now()));
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_declarations() {
+        let Harness { mut pc, out_tx, mut out_rx, .. } = harness("max_in_flight = 2");
+
+        // Held until there's a token, then declared
+        pc.declare_job(tpl(1), Vec::new(), &out_tx).await.unwrap();
+        assert!(pc.pending.is_empty());
+        tokens(&mut pc, 4, &out_tx).await;
+        assert_eq!(pc.pending.len(), 1);
+
+        pc.declare_job(tpl(2), Vec::new(), &out_tx).await.unwrap();
+        pc.declare_job(tpl(3), Vec::new(), &out_tx).await.unwrap();
+        pc.declare_job(tpl(4), Vec::new(), &out_tx).await.unwrap();
+        assert_eq!(pc.pending.len(), 2);
+        assert_eq!(pc.queued.as_ref().map(|(t, _)| t.id), Some(4));
+
+        let reqs: Vec<u32> = sent(&mut out_rx, msg_types::DECL_JOB)
+            .iter()
+            .map(|f| DeclJob::parse(&f.payload).unwrap().req_id)
+            .collect();
+
+        // Each resolves on its own and frees a slot for the newest template
+        let err = DeclJobErr { req_id: reqs[0], code: DeclErrCode::BadParams, details: "no".into() };
+        pc.handle_msg(DECL_EXT, msg_types::DECL_JOB_ERR, &err.serialize().unwrap(), &out_tx).await.unwrap();
+        assert!(pc.queued.is_none());
+        let mut tpls: Vec<u64> = pc.pending.values().map(|p| p.tpl_id).collect();
+        tpls.sort();
+        assert_eq!(tpls, vec![2, 4]);
+
+        let ok = DeclJobOk { req_id: reqs[1], new_token: vec![8].into() };
+        pc.handle_msg(DECL_EXT, msg_types::DECL_JOB_OK, &ok.serialize().unwrap(), &out_tx).await.unwrap();
+        assert_eq!(pc.pending.values().map(|p| p.tpl_id).collect::<Vec<_>>(), vec![4]);
+    }
+
+    #[tokio::test]
+    async fn test_one_in_flight_without_async() {
+        let Harness { mut pc, out_tx, out_rx: _out_rx, .. } = harness("max_in_flight = 4");
+        token(&mut pc, 1, false, &out_tx).await;
+        token(&mut pc, 2, false, &out_tx).await;
+
+        pc.declare_job(tpl(1), Vec::new(), &out_tx).await.unwrap();
+        pc.declare_job(tpl(2), Vec::new(), &out_tx).await.unwrap();
+        assert_eq!(pc.pending.len(), 1);
+        assert_eq!(pc.queued.as_ref().map(|(t, _)| t.id), Some(2));
+        assert!(pc.pending.values().all(|p| !p.early));
+
+        // Tokens allowing it don't help if setup never granted async mining
+        let Harness { mut pc, out_tx, out_rx: _out_rx, .. } = harness("max_in_flight = 4");
+        pc.setup = Setup::Done { flags: 0 };
+        token(&mut pc, 1, true, &out_tx).await;
+        token(&mut pc, 2, true, &out_tx).await;
+
+        pc.declare_job(tpl(1), Vec::new(), &out_tx).await.unwrap();
+        pc.declare_job(tpl(2), Vec::new(), &out_tx).await.unwrap();
+        assert_eq!(pc.pending.len(), 1);
+        assert_eq!(pc.queued.as_ref().map(|(t, _)| t.id), Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_pool_coinbase_outputs() {
+        let Harness { mut pc, mut bus, out_tx, mut out_rx } = harness("max_in_flight = 4");
+
+        // One fixed-value pool output and 40 bytes of outputs in all
+        let pool_out = CoinbaseOut { value: 1000, script_pubkey: vec![0x51] };
+        let ok = AllocTokenOk {
+            req_id: 1,
+            token: vec![1; 4].into(),
+            max_cb_extra: 40,
+            async_ok: true,
+            cb_outputs: build_cb_outputs(std::slice::from_ref(&pool_out), None).into(),
+        };
+        pc.handle_msg(DECL_EXT, msg_types::ALLOC_TOKEN_OK, &ok.serialize().unwrap(), &out_tx).await.unwrap();
+        let announced = std::iter::from_fn(|| bus.try_recv().ok())
+            .find_map(|e| match e {
+                Event::PoolCoinbase(cb) => Some(cb),
+                _ => None,
+            });
+        assert_eq!(announced.map(|cb| cb.outputs), Some(vec![pool_out.clone()]));
+
+        // 10 + 31 bytes is too much: nothing declared, token kept
+        let wide = CoinbaseOut { value: 0, script_pubkey: vec![0x52; 22] };
+        pc.declare_job(tpl(1), vec![wide], &out_tx).await.unwrap();
+        assert!(sent(&mut out_rx, msg_types::DECL_JOB).is_empty());
+        assert!(pc.tokens.has_token(Instant::now()));
+        assert!(std::iter::from_fn(|| bus.try_recv().ok())
+            .any(|e| matches!(e, Event::JobFailed { tpl_id: 1, .. })));
+
+        // The pool's output goes first and ours takes the rest
+        let ours = CoinbaseOut { value: 0, script_pubkey: vec![0x52; 20] };
+        pc.declare_job(tpl(2), vec![ours.clone()], &out_tx).await.unwrap();
+        let p = pc.pending.values().next().unwrap();
+        let rest = CoinbaseOut { value: 312_500_000 - 1000, ..ours };
+        assert_eq!(p.custom.cb_outputs, build_cb_outputs(&[pool_out, rest], None));
+    }
+
+    #[tokio::test]
+    async fn test_declaration_timeout() {
+        let Harness { mut pc, mut bus, out_tx, mut out_rx } = harness("max_in_flight = 4");
+        tokens(&mut pc, 4, &out_tx).await;
+
+        let ev = Event::DeclareJob { template: tpl(1), outputs: Vec::new() };
+        pc.handle_event(ev, &out_tx).await.unwrap();
+        let (&first, p) = pc.pending.iter().next().unwrap();
+        let t0 = p.sent_at;
+
+        pc.sweep_pending(t0 + Duration::from_secs(29), &out_tx).await.unwrap();
+        assert!(pc.pending.contains_key(&first));
+
+        // Given up on, reported, and the newest template declared again
+        pc.sweep_pending(t0 + Duration::from_secs(30), &out_tx).await.unwrap();
+        assert!(std::iter::from_fn(|| bus.try_recv().ok())
+            .any(|e| matches!(e, Event::JobFailed { tpl_id: 1, ref reason } if reason.contains("timed out"))));
+        assert!(!pc.pending.contains_key(&first));
+        assert_eq!(pc.pending.values().map(|p| p.tpl_id).collect::<Vec<_>>(), vec![1]);
+        assert_eq!(sent(&mut out_rx, msg_types::DECL_JOB).len(), 2);
+
+        // A late answer to the abandoned one changes nothing
+        let ok = DeclJobOk { req_id: first, new_token: B0_255::default() };
+        pc.handle_msg(DECL_EXT, msg_types::DECL_JOB_OK, &ok.serialize().unwrap(), &out_tx).await.unwrap();
+        assert_eq!(pc.pending.len(), 1);
+
+        pc.cfg.decl_retry = false;
+        pc.sweep_pending(Instant::now() + Duration::from_secs(60), &out_tx).await.unwrap();
+        assert!(pc.pending.is_empty());
+        assert!(sent(&mut out_rx, msg_types::DECL_JOB).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_new_tip_drops_stale() {
+        let Harness { mut pc, mut bus, out_tx, mut out_rx } = harness("max_in_flight = 1");
+        tokens(&mut pc, 4, &out_tx).await;
+
+        // One in flight, one held back, both on the old tip
+        pc.declare_job(tpl(1), Vec::new(), &out_tx).await.unwrap();
+        pc.declare_job(tpl(2), Vec::new(), &out_tx).await.unwrap();
+        assert_eq!(pc.pending.len(), 1);
+        assert!(pc.queued.is_some());
+
+        let ev = Event::NewPrevHash { height: 840_001, prev_hash: [2; 32] };
+        pc.handle_event(ev, &out_tx).await.unwrap();
+        assert!(pc.pending.is_empty());
+        assert!(pc.queued.is_none());
+
+        // A late template for the old tip is skipped, the new one goes
+        // straight out
+        pc.declare_job(tpl(3), Vec::new(), &out_tx).await.unwrap();
+        pc.declare_job(tpl_on(4, [2; 32]), Vec::new(), &out_tx).await.unwrap();
+        assert_eq!(pc.pending.values().map(|p| p.tpl_id).collect::<Vec<_>>(), vec![4]);
+        assert_eq!(sent(&mut out_rx, msg_types::DECL_JOB).len(), 2);
+
+        let stale: u64 = std::iter::from_fn(|| bus.try_recv().ok())
+            .filter_map(|e| match e {
+                Event::StaleAvoided(n) => Some(n),
+                _ => None,
+            })
+            .sum();
+        assert_eq!(stale, 3);
+    }
+
+    #[tokio::test]
+    async fn test_solo_holds_pool_jobs() {
+        let Harness { mut pc, mut bus, out_tx, mut out_rx } = harness("max_in_flight = 4");
+        let mining_jobs = |bus: &mut broadcast::Receiver<Event>| {
+            std::iter::from_fn(|| bus.try_recv().ok())
+                .filter(|e| matches!(e, Event::NewMiningJob(_)))
+                .count()
+        };
+
+        pc.handle_event(Event::SoloMode(true), &out_tx).await.unwrap();
+        token(&mut pc, 1, true, &out_tx).await;
+
+        // Still declared, but neither early nor on acknowledgement do
+        // miners get it
+        pc.declare_job(tpl(1), Vec::new(), &out_tx).await.unwrap();
+        let req = DeclJob::parse(&sent(&mut out_rx, msg_types::DECL_JOB)[0].payload).unwrap().req_id;
+        let ok = DeclJobOk { req_id: req, new_token: B0_255::default() };
+        pc.handle_msg(DECL_EXT, msg_types::DECL_JOB_OK, &ok.serialize().unwrap(), &out_tx).await.unwrap();
+        assert_eq!(mining_jobs(&mut bus), 0);
+
+        pc.handle_event(Event::SoloMode(false), &out_tx).await.unwrap();
+        assert_eq!(mining_jobs(&mut bus), 1);
+        assert_eq!(pc.live_tpl, Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_expired_tokens_replaced() {
+        let Harness { mut pc, out_tx, mut out_rx, .. } = harness("token_ttl = 0");
+        pc.setup = Setup::Done { flags: 0 };
+
+        pc.request_tokens(&out_tx).await.unwrap();
+        let reqs: Vec<u32> = sent(&mut out_rx, msg_types::ALLOC_TOKEN)
+            .iter()
+            .map(|f| AllocToken::parse(&f.payload).unwrap().req_id)
+            .collect();
+        for &r in &reqs {
+            token(&mut pc, r, false, &out_tx).await;
+        }
+
+        // Every token is dead on arrival: a held template asks for more
+        pc.declare_job(tpl(1), Vec::new(), &out_tx).await.unwrap();
+        assert!(pc.queued.is_some());
+        let reqs: Vec<u32> = sent(&mut out_rx, msg_types::ALLOC_TOKEN)
+            .iter()
+            .map(|f| AllocToken::parse(&f.payload).unwrap().req_id)
+            .collect();
+        assert_eq!(reqs.len(), 4);
+
+        // And the sweep replaces them without any template coming in
+        pc.queued = None;
+        for &r in &reqs {
+            token(&mut pc, r, false, &out_tx).await;
+        }
+        assert!(sent(&mut out_rx, msg_types::ALLOC_TOKEN).is_empty());
+        pc.sweep_pending(Instant::now(), &out_tx).await.unwrap();
+        assert_eq!(sent(&mut out_rx, msg_types::ALLOC_TOKEN).len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_identify_txs_after_tip_change() {
+        let Harness { mut pc, out_tx, mut out_rx, .. } = harness("max_in_flight = 4");
+        token(&mut pc, 1, true, &out_tx).await;
+
+        pc.declare_job(tpl(1), Vec::new(), &out_tx).await.unwrap();
+        let req = *pc.pending.keys().next().unwrap();
+        let ev = Event::NewPrevHash { height: 840_001, prev_hash: [2; 32] };
+        pc.handle_event(ev, &out_tx).await.unwrap();
+
+        // The pool still asks about the dropped declaration: not fatal
+        let ids = IdentifyTxs { req_id: req, positions: vec![0].into() };
+        pc.handle_msg(DECL_EXT, msg_types::IDENTIFY_TXS, &ids.serialize().unwrap(), &out_tx).await.unwrap();
+        assert!(sent(&mut out_rx, msg_types::PROVIDE_TXS).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_push_solution_by_origin() {
+        let Harness { mut pc, out_tx, mut out_rx, .. } = harness("max_in_flight = 4");
+        let sol = |solo| Solution {
+            tpl_id: 1,
+            hash: [0; 32],
+            header: TplHeader { version: 0x20000000, prev_hash: [1; 32], bits: 0x1d00ffff, time: 2 },
+            nonce: 3,
+            extranonce: vec![0; CB_EXTRANONCE_LEN],
+            coinbase: Vec::new(),
+            block: Vec::new(),
+            solo,
+        };
+
+        // Found on a pool job just as the fallback kicked in: still pushed
+        pc.handle_event(Event::SoloMode(true), &out_tx).await.unwrap();
+        pc.handle_event(Event::BlockFound(sol(false)), &out_tx).await.unwrap();
+        assert_eq!(sent(&mut out_rx, msg_types::PUSH_SOLUTION).len(), 1);
+
+        // A late solo block after the pool is back: not the pool's
+        pc.handle_event(Event::SoloMode(false), &out_tx).await.unwrap();
+        pc.handle_event(Event::BlockFound(sol(true)), &out_tx).await.unwrap();
+        assert!(sent(&mut out_rx, msg_types::PUSH_SOLUTION).is_empty());
+    }
+}
@@ -0,0 +1,210 @@
+//! Noise NX handshakes and authority key handling
+//!
+//! Authority keys use the SRI encoding: base58-check over a 2-byte LE
+//! version (currently 1) followed by the 32-byte x-only public key.
+//! Secret keys are plain base58-check over the 32 bytes.
+
+use noise_sv2::{
+    Initiator, NoiseCodec, Responder, ELLSWIFT_ENCODING_SIZE,
+    INITIATOR_EXPECTED_HANDSHAKE_MESSAGE_SIZE,
+};
+use secp256k1::{Keypair, Secp256k1, XOnlyPublicKey};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::debug;
+
+use crate::common::{Sv2Error, Result};
+
+pub const KEY_VERSION: u16 = 1;
+
+/// Clock drift tolerated by noise_sv2 when checking the certificate window
+const CERT_LEEWAY: u32 = 10;
+
+pub fn parse_authority_key(s: &str) -> Result<XOnlyPublicKey> {
+    let raw = bs58::decode(s.trim())
+        .with_check(None)
+        .into_vec()
+        .map_err(|e| Sv2Error::NoiseHandshake(format!("authority key: {}", e)))?;
+
+    if raw.len() != 34 {
+        return Err(Sv2Error::NoiseHandshake(
+            format!("authority key: expected 34 bytes, got {}", raw.len())
+        ));
+    }
+
+    let ver = u16::from_le_bytes([raw[0], raw[1]]);
+    if ver != KEY_VERSION {
+        return Err(Sv2Error::NoiseHandshake(format!("authority key: unknown version {}", ver)));
+    }
+
+    XOnlyPublicKey::from_slice(&raw[2..])
+        .map_err(|e| Sv2Error::NoiseHandshake(format!("authority key: {}", e)))
+}
+
+pub fn encode_authority_key(key: &XOnlyPublicKey) -> String {
+    let mut raw = KEY_VERSION.to_le_bytes().to_vec();
+    raw.extend_from_slice(&key.serialize());
+    bs58::encode(raw).with_check().into_string()
+}
+
+pub fn parse_authority_secret(s: &str) -> Result<Keypair> {
+    let raw = bs58::decode(s.trim())
+        .with_check(None)
+        .into_vec()
+        .map_err(|e| Sv2Error::NoiseHandshake(format!("authority secret: {}", e)))?;
+
+    Keypair::from_seckey_slice(&Secp256k1::new(), &raw)
+        .map_err(|e| Sv2Error::NoiseHandshake(format!("authority secret: {}", e)))
+}
+
+pub fn unix_now() -> u32 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or(0)
+}
+
+/// Explain why the responder's certificate was refused
+pub fn cert_failure(valid_from: u32, not_valid_after: u32, now: u32) -> Sv2Error {
+    let reason = if valid_from.saturating_sub(CERT_LEEWAY) > now {
+        format!("certificate not valid before {} (now {})", valid_from, now)
+    } else if not_valid_after.saturating_add(CERT_LEEWAY) < now {
+        format!("certificate expired at {} (now {})", not_valid_after, now)
+    } else {
+        "certificate signature does not match authority key".to_string()
+    };
+
+    Sv2Error::NoiseHandshake(reason)
+}
+
+/// Map a `step_2` failure to a handshake error
+pub fn step2_error(e: noise_sv2::Error) -> Sv2Error {
+    match e {
+        noise_sv2::Error::InvalidCertificate(cert) => {
+            cert_failure(cert.valid_from, cert.not_valid_after, unix_now())
+        }
+        other => Sv2Error::NoiseHandshake(format!("step2: {:?}", other)),
+    }
+}
+
+/// Run the initiator side of Noise NX on a fresh connection. With an
+/// authority key the responder's certificate must verify against it.
+pub async fn initiator_handshake(
+    stream: &mut TcpStream,
+    authority: Option<XOnlyPublicKey>,
+) -> Result<NoiseCodec> {
+    let mut init = Initiator::new(authority);
+
+    // Step 0: Generate and send ephemeral public key
+    let msg0 = init
+        .step_0()
+        .map_err(|e| Sv2Error::NoiseHandshake(format!("step0: {:?}", e)))?;
+
+    debug!("Sending {} bytes", msg0.len());
+    stream
+        .write_all(&msg0)
+        .await
+        .map_err(|e| Sv2Error::NoiseHandshake(format!("send: {}", e)))?;
+
+    // Read responder's message (contains their keys + signature). Read
+    // exactly that many bytes so the first transport frame stays in the
+    // socket for the framed codec.
+    let mut response = [0u8; INITIATOR_EXPECTED_HANDSHAKE_MESSAGE_SIZE];
+    stream
+        .read_exact(&mut response)
+        .await
+        .map_err(|e| Sv2Error::NoiseHandshake(format!("recv: {}", e)))?;
+
+    debug!("Received {} bytes", response.len());
+
+    init.step_2(response).map_err(step2_error)
+}
+
+/// Run the responder side of Noise NX, presenting a certificate for a
+/// fresh static key signed by `authority`
+pub async fn responder_handshake(
+    stream: &mut TcpStream,
+    authority: &Keypair,
+    cert_validity: Duration,
+) -> Result<NoiseCodec> {
+    let public = authority.x_only_public_key().0.serialize();
+    let secret = authority.secret_bytes();
+    let mut resp = Responder::from_authority_kp(&public, &secret, cert_validity)
+        .map_err(|e| Sv2Error::NoiseHandshake(format!("authority key: {:?}", e)))?;
+
+    let mut msg0 = [0u8; ELLSWIFT_ENCODING_SIZE];
+    stream
+        .read_exact(&mut msg0)
+        .await
+        .map_err(|e| Sv2Error::NoiseHandshake(format!("recv: {}", e)))?;
+
+    let (msg1, codec) = resp
+        .step_1(msg0)
+        .map_err(|e| Sv2Error::NoiseHandshake(format!("step1: {:?}", e)))?;
+
+    debug!("Sending {} bytes", msg1.len());
+    stream
+        .write_all(&msg1)
+        .await
+        .map_err(|e| Sv2Error::NoiseHandshake(format!("send: {}", e)))?;
+
+    Ok(codec)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sri_authority_key() {
+        let pk = parse_authority_key("9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72").unwrap();
+        assert_eq!(
+            hex::encode(pk.serialize()),
+            "24ee3c3804a1aaa4c03b80ea19f7a5863c916e8994b7db94a3bad7ee092b6ce7"
+        );
+
+        // Flipped last character breaks the checksum
+        assert!(parse_authority_key("9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH73").is_err());
+
+        let short = bs58::encode([1u8, 0, 2, 3]).with_check().into_string();
+        assert!(parse_authority_key(&short).is_err());
+    }
+
+    #[test]
+    fn test_authority_key_encoding() {
+        let kp = Keypair::new(&Secp256k1::new(), &mut rand::thread_rng());
+        let pk = kp.x_only_public_key().0;
+        assert_eq!(parse_authority_key(&encode_authority_key(&pk)).unwrap(), pk);
+
+        let secret = bs58::encode(kp.secret_bytes()).with_check().into_string();
+        assert_eq!(parse_authority_secret(&secret).unwrap(), kp);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_pair() {
+        let kp = Keypair::new(&Secp256k1::new(), &mut rand::thread_rng());
+        let pk = kp.x_only_public_key().0;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (mut s, _) = listener.accept().await.unwrap();
+            responder_handshake(&mut s, &kp, Duration::from_secs(60)).await
+        });
+
+        let mut c = TcpStream::connect(addr).await.unwrap();
+        assert!(initiator_handshake(&mut c, Some(pk)).await.is_ok());
+        assert!(server.await.unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_cert_failure_reasons() {
+        let msg = |e: Sv2Error| e.to_string();
+        assert!(msg(cert_failure(2000, 3000, 1000)).contains("not valid before"));
+        assert!(msg(cert_failure(1000, 2000, 3000)).contains("expired"));
+        assert!(msg(cert_failure(1000, 3000, 2000)).contains("signature"));
+        // Inside the leeway the window is fine, so blame the signature
+        assert!(msg(cert_failure(1000, 2000, 2005)).contains("signature"));
+    }
+}
@@ -0,0 +1,340 @@
+//! Solo-mining fallback
+//!
+//! When the pool keeps rejecting our declarations, can't be handshaken
+//! with, or stays unreachable too long, miners are switched to jobs whose
+//! coinbase pays only our configured outputs. Shares are still checked by
+//! the downstream server and blocks go to the node as usual; the pool
+//! client keeps declaring in the background and the first job the pool
+//! accepts again ends the fallback.
+
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+use crate::common::{CoinbaseOut, Event, MiningJob, Result, Sv2Error, TplSnapshot};
+use super::sv2_messages::*;
+use super::tx::Transaction;
+
+/// How often the disconnect threshold is checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SoloConfig {
+    pub enabled: bool,
+    /// Consecutive rejected or failed declarations; 0 disables
+    pub max_job_failures: u32,
+    /// Consecutive failed handshakes or setups; 0 disables
+    pub max_handshake_failures: u32,
+    /// Seconds without a working pool connection; 0 disables
+    pub max_disconnect_secs: u64,
+}
+
+impl Default for SoloConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_job_failures: 3,
+            max_handshake_failures: 3,
+            max_disconnect_secs: 60,
+        }
+    }
+}
+
+pub struct SoloFallback {
+    cfg: SoloConfig,
+    bus_tx: broadcast::Sender<Event>,
+    bus_rx: broadcast::Receiver<Event>,
+    active: bool,
+    job_failures: u32,
+    hs_failures: u32,
+    /// Since when the pool has been unusable
+    down_since: Option<Instant>,
+    /// Newest template and our outputs, to mine on when falling back
+    latest: Option<(Arc<TplSnapshot>, Vec<CoinbaseOut>)>,
+}
+
+impl SoloFallback {
+    pub fn new(
+        cfg: SoloConfig,
+        bus_tx: broadcast::Sender<Event>,
+        bus_rx: broadcast::Receiver<Event>,
+    ) -> Self {
+        Self {
+            cfg,
+            bus_tx,
+            bus_rx,
+            active: false,
+            job_failures: 0,
+            hs_failures: 0,
+            down_since: None,
+            latest: None,
+        }
+    }
+
+    pub async fn run(mut self) -> Result<()> {
+        info!("Solo fallback armed");
+
+        let mut ticker = tokio::time::interval(CHECK_INTERVAL);
+
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => self.check(Instant::now()),
+
+                ev = self.bus_rx.recv() => {
+                    match ev {
+                        Ok(Event::Shutdown) | Err(broadcast::error::RecvError::Closed) => {
+                            return Ok(());
+                        }
+                        Ok(ev) => self.on_event(ev, Instant::now()),
+                        Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    }
+                }
+            }
+        }
+    }
+
+    fn on_event(&mut self, ev: Event, now: Instant) {
+        match ev {
+            Event::DeclareJob { template, outputs } => {
+                self.latest = Some((template, outputs));
+                if self.active {
+                    self.mine();
+                }
+            }
+            Event::NewPrevHash { prev_hash, .. } => {
+                if self.latest.as_ref().is_some_and(|(t, _)| t.header.prev_hash != prev_hash) {
+                    self.latest = None;
+                }
+            }
+            Event::JobOk { .. } => {
+                self.job_failures = 0;
+                self.down_since = None;
+                if self.active {
+                    info!("Pool accepted a declaration again, leaving solo mining");
+                    self.active = false;
+                    let _ = self.bus_tx.send(Event::SoloMode(false));
+                }
+            }
+            Event::JobFailed { .. } => {
+                self.job_failures += 1;
+            }
+            // Not on HandshakeDone: the setup after it can still fail, or
+            // the pool hang up straight away
+            Event::SetupDone => {
+                self.hs_failures = 0;
+                self.down_since = None;
+            }
+            Event::HandshakeErr(_) => {
+                self.hs_failures += 1;
+                self.down_since.get_or_insert(now);
+            }
+            Event::PoolDown => {
+                self.down_since.get_or_insert(now);
+            }
+            _ => return,
+        }
+        self.check(now);
+    }
+
+    /// Fall back if any trigger has tripped
+    fn check(&mut self, now: Instant) {
+        if self.active {
+            return;
+        }
+
+        let cfg = &self.cfg;
+        let why = if cfg.max_job_failures > 0 && self.job_failures >= cfg.max_job_failures {
+            format!("{} declarations failed in a row", self.job_failures)
+        } else if cfg.max_handshake_failures > 0 && self.hs_failures >= cfg.max_handshake_failures {
+            format!("{} handshakes failed in a row", self.hs_failures)
+        } else if let Some(since) = self.down_since.filter(|_| cfg.max_disconnect_secs > 0) {
+            let down = now.duration_since(since);
+            if down < Duration::from_secs(cfg.max_disconnect_secs) {
+                return;
+            }
+            format!("pool unusable for {}s", down.as_secs())
+        } else {
+            return;
+        };
+
+        warn!("Falling back to solo mining: {}", why);
+        self.active = true;
+        let _ = self.bus_tx.send(Event::SoloMode(true));
+        self.mine();
+    }
+
+    /// Hand miners a solo job on the newest template
+    fn mine(&self) {
+        let Some((tpl, outputs)) = &self.latest else {
+            return;
+        };
+        match solo_job(tpl, outputs) {
+            Ok(job) => {
+                info!("Solo job: tpl={}, txs={}", tpl.id, tpl.txs.len());
+                let _ = self.bus_tx.send(Event::NewMiningJob(job));
+            }
+            Err(e) => warn!("Can't build solo job for tpl={}: {}", tpl.id, e),
+        }
+    }
+}
+
+/// A job on `tpl` whose coinbase splits the whole reward among `outputs`
+fn solo_job(tpl: &TplSnapshot, outputs: &[CoinbaseOut]) -> Result<MiningJob> {
+    if outputs.is_empty() {
+        return Err(Sv2Error::InvalidState("solo mining needs coinbase_outputs".into()));
+    }
+
+    let parsed = tpl.txs.iter().map(|t| Transaction::parse(t)).collect::<Result<Vec<_>>>()?;
+    let txids: Vec<[u8; 32]> = parsed.iter().map(Transaction::txid).collect();
+    let wtxids: Vec<[u8; 32]> = parsed.iter().map(Transaction::wtxid).collect();
+
+    let outputs = resolve_cb_outputs(outputs, tpl.coinbase_value)?;
+    let commitment = parsed
+        .iter()
+        .any(Transaction::has_witness)
+        .then(|| calc_witness_commitment(&wtxids));
+
+    let prefix = build_cb_prefix(CB_TX_VERSION, tpl.height, CB_TAG, CB_EXTRANONCE_LEN);
+    let suffix = build_cb_suffix(&outputs, commitment.as_ref());
+    let (cb_prefix, cb_suffix) = cb_strip_witness(&prefix, &suffix);
+
+    Ok(MiningJob {
+        tpl_id: tpl.id,
+        version: tpl.header.version,
+        prev_hash: tpl.header.prev_hash,
+        bits: tpl.header.bits,
+        time: tpl.header.time,
+        min_time: tpl.min_time,
+        cb_prefix,
+        cb_suffix,
+        extranonce_len: CB_EXTRANONCE_LEN,
+        extranonce_prefix: Vec::new(),
+        merkle_path: merkle_path(&txids),
+        txs: tpl.txs.clone(),
+        cb_witness: commitment.is_some(),
+        solo: true,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::TplHeader;
+
+    fn tpl() -> Arc<TplSnapshot> {
+        Arc::new(TplSnapshot {
+            id: 7,
+            height: 840_000,
+            header: TplHeader { version: 0x20000000, prev_hash: [1; 32], bits: 0x1d00ffff, time: 2 },
+            min_time: 1,
+            txs: Arc::new(Vec::new()),
+            fees: 0,
+            coinbase_value: 312_500_000,
+            witness_commitment: None,
+        })
+    }
+
+    fn fallback(cfg: SoloConfig) -> (SoloFallback, broadcast::Receiver<Event>) {
+        let (tx, rx) = broadcast::channel(64);
+        let bus = tx.subscribe();
+        (SoloFallback::new(cfg, tx, rx), bus)
+    }
+
+    fn solo_jobs(bus: &mut broadcast::Receiver<Event>) -> Vec<MiningJob> {
+        std::iter::from_fn(|| bus.try_recv().ok())
+            .filter_map(|e| match e {
+                Event::NewMiningJob(job) => Some(job),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_solo_job() {
+        let ours = CoinbaseOut { value: 0, script_pubkey: vec![0x51] };
+        let job = solo_job(&tpl(), &[ours]).unwrap();
+
+        let mut cb = job.cb_prefix.clone();
+        cb.extend_from_slice(&[0; CB_EXTRANONCE_LEN]);
+        cb.extend_from_slice(&job.cb_suffix);
+        let tx = Transaction::parse(&cb).unwrap();
+        assert_eq!(tx.outputs.len(), 1);
+        assert_eq!(tx.outputs[0].value, 312_500_000);
+
+        assert!(solo_job(&tpl(), &[]).is_err());
+    }
+
+    #[test]
+    fn test_triggers() {
+        let t0 = Instant::now();
+        let outputs = vec![CoinbaseOut { value: 0, script_pubkey: vec![0x51] }];
+        let (mut solo, mut bus) = fallback(SoloConfig { enabled: true, ..Default::default() });
+        solo.on_event(Event::DeclareJob { template: tpl(), outputs: outputs.clone() }, t0);
+
+        // Failures only count in a row
+        for _ in 0..2 {
+            solo.on_event(Event::JobFailed { tpl_id: 7, reason: "no".into() }, t0);
+        }
+        solo.on_event(Event::JobOk { tpl_id: 7, token: Vec::new() }, t0);
+        solo.on_event(Event::JobFailed { tpl_id: 7, reason: "no".into() }, t0);
+        assert!(!solo.active);
+
+        // A pool that stays away long enough
+        solo.on_event(Event::PoolDown, t0);
+        solo.check(t0 + Duration::from_secs(59));
+        assert!(!solo.active);
+        solo.check(t0 + Duration::from_secs(60));
+        assert!(solo.active);
+        assert_eq!(solo_jobs(&mut bus).len(), 1);
+
+        // Templates keep being mined solo until the pool takes a job
+        solo.on_event(Event::DeclareJob { template: tpl(), outputs }, t0);
+        assert_eq!(solo_jobs(&mut bus).len(), 1);
+        solo.on_event(Event::JobOk { tpl_id: 7, token: Vec::new() }, t0);
+        assert!(!solo.active);
+        assert!(std::iter::from_fn(|| bus.try_recv().ok()).any(|e| matches!(e, Event::SoloMode(false))));
+
+        // Handshakes that keep failing
+        for _ in 0..3 {
+            solo.on_event(Event::HandshakeErr("bad".into()), t0);
+        }
+        assert!(solo.active);
+    }
+
+    #[test]
+    fn test_setup_failures() {
+        let t0 = Instant::now();
+        let (mut solo, _bus) = fallback(SoloConfig { enabled: true, ..Default::default() });
+
+        // Noise goes through every time, SetupConnection never does
+        for _ in 0..3 {
+            solo.on_event(Event::HandshakeDone, t0);
+            solo.on_event(Event::HandshakeErr("setup: unsupported-protocol".into()), t0);
+        }
+        assert!(solo.active);
+    }
+
+    #[test]
+    fn test_drops_after_handshake() {
+        let t0 = Instant::now();
+        let (mut solo, _bus) = fallback(SoloConfig { enabled: true, ..Default::default() });
+
+        // Hung up on right after each handshake, for a minute
+        for secs in (0..=60).step_by(5) {
+            let now = t0 + Duration::from_secs(secs);
+            solo.on_event(Event::HandshakeDone, now);
+            solo.on_event(Event::PoolDown, now);
+        }
+        assert!(solo.active);
+
+        // A completed setup does count as the pool being back
+        let (mut solo, _bus) = fallback(SoloConfig { enabled: true, ..Default::default() });
+        solo.on_event(Event::PoolDown, t0);
+        solo.on_event(Event::SetupDone, t0 + Duration::from_secs(30));
+        solo.check(t0 + Duration::from_secs(60));
+        assert!(!solo.active);
+    }
+}
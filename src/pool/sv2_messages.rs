@@ -1,9 +1,18 @@
 //! Stratum V2 Job Declaration Protocol Messages
 
-use crate::common::{Sv2Error, Result};
+use crate::common::{CoinbaseOut, PoolCoinbase, Result, Sv2Error};
+use super::tx::{parse_outputs, write_compact_size};
 use sha2::{Sha256, Digest};
+use siphasher::sip::SipHasher24;
+use std::collections::HashSet;
+use std::hash::Hasher;
+
+pub use super::binary::*;
 
 pub mod msg_types {
+    pub const SETUP_CONN: u8 = 0x00;
+    pub const SETUP_CONN_OK: u8 = 0x01;
+    pub const SETUP_CONN_ERR: u8 = 0x02;
     pub const ALLOC_TOKEN: u8 = 0x50;
     pub const ALLOC_TOKEN_OK: u8 = 0x51;
     pub const DECL_JOB: u8 = 0x52;
@@ -12,188 +21,163 @@ pub mod msg_types {
     pub const IDENTIFY_TXS: u8 = 0x55;
     pub const PROVIDE_TXS: u8 = 0x56;
     pub const PROVIDE_TXS_OK: u8 = 0x57;
+    pub const PUSH_SOLUTION: u8 = 0x60;
 }
 
+pub const COMMON_EXT: u16 = 0x0000;
 pub const DECL_EXT: u16 = 0x0002;
 
+pub const PROTO_JD: u8 = 1;
+
+pub const SV2_MIN_VERSION: u16 = 2;
+pub const SV2_MAX_VERSION: u16 = 2;
+
+/// Coinbase tx version
+pub const CB_TX_VERSION: u32 = 2;
+
+/// Tag written into the coinbase scriptSig after the height
+pub const CB_TAG: &[u8] = b"sv2-jdc";
+
+/// scriptSig space reserved for the extranonce: a 4-byte per-channel
+/// prefix assigned by the downstream server plus 8 bytes for the miner
+pub const CB_EXTRANONCE_LEN: usize = 12;
+
+/// SetupConnection flags for the Job Declaration protocol
+pub mod jd_flags {
+    /// Tokens from AllocateMiningJobToken.Success may be used on a mining
+    /// connection before the matching DeclareMiningJob is acknowledged
+    pub const REQUIRES_ASYNC_JOB_MINING: u32 = 1 << 0;
+}
+
 // ============================================================================
-// AllocateMiningJobToken (0x50)
+// SetupConnection (0x00)
 // ============================================================================
 
-#[derive(Debug, Clone)]
-pub struct AllocToken {
-    pub req_id: u32,
-    pub user: String,
-    pub min_nonce2: u16,
+sv2_message! {
+    pub struct SetupConn {
+        pub protocol: u8,
+        pub min_ver: u16,
+        pub max_ver: u16,
+        pub flags: u32,
+        pub host: STR0_255,
+        pub port: u16,
+        pub vendor: STR0_255,
+        pub hw_ver: STR0_255,
+        pub firmware: STR0_255,
+        pub device_id: STR0_255,
+    }
 }
 
-impl AllocToken {
-    pub fn new(req_id: u32, user: &str, min_nonce2: u16) -> Self {
-        Self { req_id, user: user.into(), min_nonce2 }
+/// Host and port of a configured `host:port` address, as SetupConnection
+/// carries them. Hostnames are kept as written; IPv6 hosts may be
+/// bracketed.
+pub fn split_host_port(addr: &str) -> Result<(&str, u16)> {
+    let bad = |why: &str| Sv2Error::PoolConnection(format!("bad addr {}: {}", addr, why));
+    let (host, port) = addr.rsplit_once(':').ok_or_else(|| bad("no port"))?;
+    let port = port.parse().map_err(|_| bad("bad port"))?;
+    let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+    if host.is_empty() {
+        return Err(bad("no host"));
     }
+    Ok((host, port))
+}
 
-    pub fn serialize(&self) -> Result<Vec<u8>> {
-        let mut buf = Vec::new();
-        buf.extend_from_slice(&self.req_id.to_le_bytes());
-        
-        let ubytes = self.user.as_bytes();
-        if ubytes.len() > 255 {
-            return Err(Sv2Error::Serialization("user too long".into()));
-        }
-        buf.push(ubytes.len() as u8);
-        buf.extend_from_slice(ubytes);
-        buf.extend_from_slice(&self.min_nonce2.to_le_bytes());
-        
-        Ok(buf)
+// ============================================================================
+// SetupConnectionSuccess (0x01)
+// ============================================================================
+
+sv2_message! {
+    pub struct SetupConnOk {
+        pub used_ver: u16,
+        pub flags: u32,
     }
 }
 
 // ============================================================================
-// AllocateMiningJobTokenSuccess (0x51)
+// SetupConnectionError (0x02)
 // ============================================================================
 
-#[derive(Debug, Clone)]
-pub struct AllocTokenOk {
-    pub req_id: u32,
-    pub token: Vec<u8>,
-    pub max_cb_extra: u32,
-    pub async_ok: bool,
-    pub constraints: Vec<CbConstraint>,
+sv2_message! {
+    pub struct SetupConnErr {
+        pub flags: u32,
+        pub code: STR0_255,
+    }
 }
 
-impl AllocTokenOk {
-    pub fn parse(data: &[u8]) -> Result<Self> {
-        if data.len() < 4 {
-            return Err(Sv2Error::Serialization("too short".into()));
-        }
-        
-        let mut pos = 0;
-        let req_id = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
-        pos += 4;
-        
-        if pos >= data.len() {
-            return Err(Sv2Error::Serialization("missing token len".into()));
-        }
-        let tlen = data[pos] as usize;
-        pos += 1;
-        
-        if pos + tlen > data.len() {
-            return Err(Sv2Error::Serialization("truncated token".into()));
-        }
-        let token = data[pos..pos + tlen].to_vec();
-        pos += tlen;
-        
-        if pos + 4 > data.len() {
-            return Err(Sv2Error::Serialization("missing max_cb_extra".into()));
-        }
-        let max_cb_extra = u32::from_le_bytes([
-            data[pos], data[pos + 1], data[pos + 2], data[pos + 3]
-        ]);
-        pos += 4;
-        
-        if pos >= data.len() {
-            return Err(Sv2Error::Serialization("missing async flag".into()));
-        }
-        let async_ok = data[pos] != 0;
-        
-        Ok(Self {
-            req_id,
-            token,
-            max_cb_extra,
-            async_ok,
-            constraints: Vec::new(),
-        })
+// ============================================================================
+// AllocateMiningJobToken (0x50)
+// ============================================================================
+
+sv2_message! {
+    pub struct AllocToken {
+        pub req_id: u32,
+        pub user: STR0_255,
+        pub min_nonce2: u16,
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct CbConstraint {
-    pub script: Vec<u8>,
+impl AllocToken {
+    pub fn new(req_id: u32, user: &str, min_nonce2: u16) -> Self {
+        Self { req_id, user: user.into(), min_nonce2 }
+    }
 }
 
 // ============================================================================
-// DeclareMiningJob (0x52)
+// AllocateMiningJobTokenSuccess (0x51)
 // ============================================================================
 
-#[derive(Debug, Clone)]
-pub struct DeclJob {
-    pub req_id: u32,
-    pub token: Vec<u8>,
-    pub version: u32,
-    pub cb_prefix: Vec<u8>,
-    pub cb_suffix: Vec<u8>,
-    pub hash_nonce: u64,
-    pub short_hashes: Vec<u64>,
-    pub tx_list_hash: [u8; 32],
-    pub extra: Vec<u8>,
+sv2_message! {
+    pub struct AllocTokenOk {
+        pub req_id: u32,
+        pub token: B0_255,
+        pub max_cb_extra: u32,
+        pub async_ok: bool,
+        /// Outputs the pool requires, serialized as in a transaction
+        pub cb_outputs: B0_64K,
+    }
 }
 
-impl DeclJob {
-    pub fn serialize(&self) -> Result<Vec<u8>> {
-        let mut buf = Vec::new();
-        
-        buf.extend_from_slice(&self.req_id.to_le_bytes());
-        
-        if self.token.len() > 255 {
-            return Err(Sv2Error::Serialization("token too long".into()));
-        }
-        buf.push(self.token.len() as u8);
-        buf.extend_from_slice(&self.token);
-        
-        buf.extend_from_slice(&self.version.to_le_bytes());
-        
-        let plen = self.cb_prefix.len() as u16;
-        buf.extend_from_slice(&plen.to_le_bytes());
-        buf.extend_from_slice(&self.cb_prefix);
-        
-        let slen = self.cb_suffix.len() as u16;
-        buf.extend_from_slice(&slen.to_le_bytes());
-        buf.extend_from_slice(&self.cb_suffix);
-        
-        buf.extend_from_slice(&self.hash_nonce.to_le_bytes());
-        
-        let count = self.short_hashes.len() as u16;
-        buf.extend_from_slice(&count.to_le_bytes());
-        for h in &self.short_hashes {
-            buf.extend_from_slice(&h.to_le_bytes());
+impl AllocTokenOk {
+    pub fn pool_coinbase(&self) -> Result<PoolCoinbase> {
+        // Pools without outputs of their own may leave the field empty,
+        // count byte and all
+        if self.cb_outputs.is_empty() {
+            return Ok(PoolCoinbase { outputs: Vec::new(), max_extra: self.max_cb_extra });
         }
-        
-        buf.extend_from_slice(&self.tx_list_hash);
-        
-        let elen = self.extra.len() as u16;
-        buf.extend_from_slice(&elen.to_le_bytes());
-        buf.extend_from_slice(&self.extra);
-        
-        Ok(buf)
+        let outputs = parse_outputs(&self.cb_outputs)?
+            .into_iter()
+            .map(|o| CoinbaseOut { value: o.value, script_pubkey: o.script_pubkey })
+            .collect();
+        Ok(PoolCoinbase { outputs, max_extra: self.max_cb_extra })
     }
 }
 
 // ============================================================================
-// DeclareMiningJobSuccess (0x53)
+// DeclareMiningJob (0x52)
 // ============================================================================
 
-#[derive(Debug, Clone)]
-pub struct DeclJobOk {
-    pub req_id: u32,
-    pub new_token: Vec<u8>,
+sv2_message! {
+    pub struct DeclJob {
+        pub req_id: u32,
+        pub token: B0_255,
+        pub version: u32,
+        pub cb_prefix: B0_64K,
+        pub cb_suffix: B0_64K,
+        pub hash_nonce: u64,
+        pub short_hashes: Seq0_64K<ShortTxId>,
+        pub tx_list_hash: U256,
+        pub extra: B0_64K,
+    }
 }
 
-impl DeclJobOk {
-    pub fn parse(data: &[u8]) -> Result<Self> {
-        if data.len() < 4 {
-            return Err(Sv2Error::Serialization("too short".into()));
-        }
-        
-        let req_id = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
-        
-        let tlen = data.get(4).copied().unwrap_or(0) as usize;
-        let new_token = if tlen > 0 && data.len() > 5 + tlen {
-            data[5..5 + tlen].to_vec()
-        } else {
-            Vec::new()
-        };
-        
-        Ok(Self { req_id, new_token })
+// ============================================================================
+// DeclareMiningJobSuccess (0x53)
+// ============================================================================
+
+sv2_message! {
+    pub struct DeclJobOk {
+        pub req_id: u32,
+        pub new_token: B0_255,
     }
 }
 
@@ -220,30 +204,23 @@ impl From<u8> for DeclErrCode {
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct DeclJobErr {
-    pub req_id: u32,
-    pub code: DeclErrCode,
-    pub details: String,
+impl Sv2Encode for DeclErrCode {
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+        (*self as u8).encode(buf)
+    }
 }
 
-impl DeclJobErr {
-    pub fn parse(data: &[u8]) -> Result<Self> {
-        if data.len() < 5 {
-            return Err(Sv2Error::Serialization("too short".into()));
-        }
-        
-        let req_id = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
-        let code = DeclErrCode::from(data[4]);
-        
-        let dlen = data.get(5).copied().unwrap_or(0) as usize;
-        let details = if dlen > 0 && data.len() > 6 + dlen {
-            String::from_utf8_lossy(&data[6..6 + dlen]).into()
-        } else {
-            String::new()
-        };
-        
-        Ok(Self { req_id, code, details })
+impl Sv2Decode for DeclErrCode {
+    fn decode(r: &mut Sv2Reader<'_>) -> Result<Self> {
+        Ok(Self::from(u8::decode(r)?))
+    }
+}
+
+sv2_message! {
+    pub struct DeclJobErr {
+        pub req_id: u32,
+        pub code: DeclErrCode,
+        pub details: STR0_255,
     }
 }
 
@@ -251,33 +228,10 @@ impl DeclJobErr {
 // IdentifyTransactions (0x55)
 // ============================================================================
 
-#[derive(Debug, Clone)]
-pub struct IdentifyTxs {
-    pub req_id: u32,
-    pub positions: Vec<u16>,
-}
-
-impl IdentifyTxs {
-    pub fn parse(data: &[u8]) -> Result<Self> {
-        if data.len() < 4 {
-            return Err(Sv2Error::Serialization("too short".into()));
-        }
-        
-        let req_id = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
-        
-        let mut positions = Vec::new();
-        if data.len() > 6 {
-            let cnt = u16::from_le_bytes([data[4], data[5]]) as usize;
-            let mut pos = 6;
-            for _ in 0..cnt {
-                if pos + 2 <= data.len() {
-                    positions.push(u16::from_le_bytes([data[pos], data[pos + 1]]));
-                    pos += 2;
-                }
-            }
-        }
-        
-        Ok(Self { req_id, positions })
+sv2_message! {
+    pub struct IdentifyTxs {
+        pub req_id: u32,
+        pub positions: Seq0_64K<u16>,
     }
 }
 
@@ -285,30 +239,10 @@ impl IdentifyTxs {
 // ProvideMissingTransactions (0x56)
 // ============================================================================
 
-#[derive(Debug, Clone)]
-pub struct ProvideTxs {
-    pub req_id: u32,
-    pub txs: Vec<Vec<u8>>,
-}
-
-impl ProvideTxs {
-    pub fn serialize(&self) -> Result<Vec<u8>> {
-        let mut buf = Vec::new();
-        
-        buf.extend_from_slice(&self.req_id.to_le_bytes());
-        
-        let cnt = self.txs.len() as u16;
-        buf.extend_from_slice(&cnt.to_le_bytes());
-        
-        for tx in &self.txs {
-            let len = tx.len() as u32;
-            buf.push((len & 0xFF) as u8);
-            buf.push(((len >> 8) & 0xFF) as u8);
-            buf.push(((len >> 16) & 0xFF) as u8);
-            buf.extend_from_slice(tx);
-        }
-        
-        Ok(buf)
+sv2_message! {
+    pub struct ProvideTxs {
+        pub req_id: u32,
+        pub txs: Seq0_64K<B0_16M>,
     }
 }
 
@@ -316,19 +250,24 @@ impl ProvideTxs {
 // ProvideMissingTransactionsSuccess (0x57)
 // ============================================================================
 
-#[derive(Debug, Clone)]
-pub struct ProvideTxsOk {
-    pub req_id: u32,
+sv2_message! {
+    pub struct ProvideTxsOk {
+        pub req_id: u32,
+    }
 }
 
-impl ProvideTxsOk {
-    pub fn parse(data: &[u8]) -> Result<Self> {
-        if data.len() < 4 {
-            return Err(Sv2Error::Serialization("too short".into()));
-        }
-        Ok(Self {
-            req_id: u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
-        })
+// ============================================================================
+// PushSolution (0x60)
+// ============================================================================
+
+sv2_message! {
+    pub struct PushSolution {
+        pub extranonce: B0_32,
+        pub prev_hash: U256,
+        pub ntime: u32,
+        pub nonce: u32,
+        pub bits: u32,
+        pub version: u32,
     }
 }
 
@@ -354,37 +293,49 @@ pub fn build_frame(mtype: u8, ext: u16, payload: &[u8]) -> Vec<u8> {
 // Crypto utilities
 // ============================================================================
 
-pub fn calc_short_hash(txid: &[u8; 32], nonce: u64) -> u64 {
-    let mut h = Sha256::new();
-    h.update(&nonce.to_le_bytes());
-    h.update(txid);
-    let out = h.finalize();
-    
-    u64::from_le_bytes([
-        out[0], out[1], out[2], out[3],
-        out[4], out[5], out[6], out[7],
-    ])
-}
-
-pub fn calc_txid(raw: &[u8]) -> [u8; 32] {
-    let h1 = Sha256::digest(raw);
-    let h2 = Sha256::digest(&h1);
-    
-    let mut id = [0u8; 32];
-    id.copy_from_slice(&h2);
-    id.reverse();
-    id
+/// SipHash keys for a `tx_short_hash_nonce`: the first two little-endian
+/// u64s of SHA256(nonce), as in BIP152
+pub fn short_hash_keys(nonce: u64) -> (u64, u64) {
+    let h = Sha256::digest(nonce.to_le_bytes());
+    let k0 = u64::from_le_bytes(h[0..8].try_into().unwrap());
+    let k1 = u64::from_le_bytes(h[8..16].try_into().unwrap());
+    (k0, k1)
+}
+
+/// SHORT_TX_ID of a wtxid (internal byte order)
+pub fn calc_short_hash(wtxid: &[u8; 32], keys: (u64, u64)) -> ShortTxId {
+    let mut h = SipHasher24::new_with_keys(keys.0, keys.1);
+    h.write(wtxid);
+    ShortTxId(h.finish() & ShortTxId::MAX)
+}
+
+/// Short IDs for a template's wtxids, or None if two of them collide
+/// under this nonce and the pool couldn't tell them apart
+pub fn calc_short_hashes(wtxids: &[[u8; 32]], nonce: u64) -> Option<Vec<ShortTxId>> {
+    let keys = short_hash_keys(nonce);
+    let mut seen = HashSet::with_capacity(wtxids.len());
+    let mut out = Vec::with_capacity(wtxids.len());
+
+    for id in wtxids {
+        let short = calc_short_hash(id, keys);
+        if !seen.insert(short) {
+            return None;
+        }
+        out.push(short);
+    }
+    Some(out)
 }
 
-pub fn calc_tx_list_hash(txs: &[Vec<u8>]) -> [u8; 32] {
+/// Double SHA256 over the concatenated txids (internal byte order)
+pub fn calc_tx_list_hash(txids: &[[u8; 32]]) -> [u8; 32] {
     let mut h = Sha256::new();
     
-    for tx in txs {
-        h.update(&calc_txid(tx));
+    for id in txids {
+        h.update(id);
     }
     
     let h1 = h.finalize();
-    let h2 = Sha256::digest(&h1);
+    let h2 = Sha256::digest(h1);
     
     let mut out = [0u8; 32];
     out.copy_from_slice(&h2);
@@ -395,7 +346,9 @@ pub fn calc_tx_list_hash(txs: &[Vec<u8>]) -> [u8; 32] {
 // Coinbase builder
 // ============================================================================
 
-pub fn build_cb_prefix(ver: u32, height: u64, tag: &[u8]) -> Vec<u8> {
+/// Coinbase bytes up to the extranonce. The scriptSig length counts
+/// `extranonce_len` bytes that the miner fills in.
+pub fn build_cb_prefix(ver: u32, height: u64, tag: &[u8], extranonce_len: usize) -> Vec<u8> {
     let mut buf = Vec::new();
     
     buf.extend_from_slice(&ver.to_le_bytes());
@@ -406,8 +359,8 @@ pub fn build_cb_prefix(ver: u32, height: u64, tag: &[u8]) -> Vec<u8> {
     buf.extend_from_slice(&[0u8; 32]); // null prevout
     buf.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF]); // prevout index
     
-    let hscript = encode_height(height);
-    let slen = hscript.len() + tag.len();
+    let script = cb_script_prefix(height, tag);
+    let slen = script.len() + extranonce_len;
     
     if slen < 0xFD {
         buf.push(slen as u8);
@@ -416,45 +369,157 @@ pub fn build_cb_prefix(ver: u32, height: u64, tag: &[u8]) -> Vec<u8> {
         buf.extend_from_slice(&(slen as u16).to_le_bytes());
     }
     
-    buf.extend_from_slice(&hscript);
-    buf.extend_from_slice(tag);
+    buf.extend_from_slice(&script);
     
     buf
 }
 
-pub fn build_cb_suffix(value: u64, script: &[u8], witness: Option<&[u8; 32]>) -> Vec<u8> {
+/// scriptSig bytes ahead of the extranonce: BIP34 height and our tag
+pub fn cb_script_prefix(height: u64, tag: &[u8]) -> Vec<u8> {
+    let mut s = encode_height(height);
+    s.extend_from_slice(tag);
+    s
+}
+
+/// Coinbase bytes after the extranonce. `outputs` must already be
+/// resolved (see `resolve_cb_outputs`).
+pub fn build_cb_suffix(outputs: &[CoinbaseOut], witness: Option<&[u8; 32]>) -> Vec<u8> {
     let mut buf = Vec::new();
     
     buf.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF]); // sequence
+    buf.extend_from_slice(&build_cb_outputs(outputs, witness));
     
-    let outs = if witness.is_some() { 2 } else { 1 };
-    buf.push(outs);
-    
-    buf.extend_from_slice(&value.to_le_bytes());
+    buf.push(0x01); // witness stack count
+    buf.push(0x20); // 32 bytes
+    buf.extend_from_slice(&[0u8; 32]); // witness nonce
+    buf.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]); // locktime
     
-    if script.len() < 0xFD {
-        buf.push(script.len() as u8);
-    } else {
-        buf.push(0xFD);
-        buf.extend_from_slice(&(script.len() as u16).to_le_bytes());
+    buf
+}
+
+/// Drop the segwit marker/flag and the witness stack from a coinbase made
+/// by `build_cb_prefix`/`build_cb_suffix`, giving the parts hashed into
+/// the txid
+pub fn cb_strip_witness(prefix: &[u8], suffix: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let mut p = prefix[..4].to_vec();
+    p.extend_from_slice(&prefix[6..]);
+
+    // witness count + push len + 32-byte nonce, then the locktime
+    let wit = suffix.len() - 4 - 34;
+    let mut s = suffix[..wit].to_vec();
+    s.extend_from_slice(&suffix[suffix.len() - 4..]);
+
+    (p, s)
+}
+
+/// Output count and outputs of the coinbase: `outputs` in order, then the
+/// witness commitment if any
+pub fn build_cb_outputs(outputs: &[CoinbaseOut], witness: Option<&[u8; 32]>) -> Vec<u8> {
+    let mut buf = Vec::new();
+
+    let outs = outputs.len() + witness.is_some() as usize;
+    write_compact_size(&mut buf, outs as u64);
+
+    for o in outputs {
+        buf.extend_from_slice(&o.value.to_le_bytes());
+        write_compact_size(&mut buf, o.script_pubkey.len() as u64);
+        buf.extend_from_slice(&o.script_pubkey);
     }
-    buf.extend_from_slice(script);
-    
+
     if let Some(w) = witness {
         buf.extend_from_slice(&0u64.to_le_bytes());
         let wscript = witness_script(w);
         buf.push(wscript.len() as u8);
         buf.extend_from_slice(&wscript);
     }
-    
-    buf.push(0x01); // witness stack count
-    buf.push(0x20); // 32 bytes
-    buf.extend_from_slice(&[0u8; 32]); // witness nonce
-    buf.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]); // locktime
-    
+
     buf
 }
 
+/// Serialized size of `outputs`, without the output count
+pub fn cb_outputs_size(outputs: &[CoinbaseOut]) -> usize {
+    outputs
+        .iter()
+        .map(|o| {
+            let mut len = Vec::new();
+            write_compact_size(&mut len, o.script_pubkey.len() as u64);
+            8 + len.len() + o.script_pubkey.len()
+        })
+        .sum()
+}
+
+/// The pool's outputs at the amounts it asked for, followed by ours
+/// resolved over what is left of `cb_value`. The pool's outputs are
+/// charged against `max_extra` first; ours must fit in what remains.
+pub fn merge_pool_outputs(
+    ours: &[CoinbaseOut],
+    pool: &PoolCoinbase,
+    cb_value: u64,
+) -> Result<Vec<CoinbaseOut>> {
+    let pool_size = cb_outputs_size(&pool.outputs);
+    let room = (pool.max_extra as usize).checked_sub(pool_size).ok_or_else(|| {
+        Sv2Error::InvalidState(format!(
+            "pool outputs take {} bytes, more than the {} it allows",
+            pool_size, pool.max_extra
+        ))
+    })?;
+    let size = cb_outputs_size(ours);
+    if size > room {
+        return Err(Sv2Error::InvalidState(format!(
+            "coinbase outputs take {} bytes, pool leaves room for {}",
+            size, room
+        )));
+    }
+
+    // A zero-value pool output is just that, not a share of the remainder
+    let left = pool
+        .outputs
+        .iter()
+        .try_fold(cb_value, |left, o| left.checked_sub(o.value))
+        .ok_or_else(|| Sv2Error::InvalidState(format!(
+            "pool outputs pay more than the coinbase value {}", cb_value
+        )))?;
+    let ours = resolve_cb_outputs(ours, left)?;
+    Ok(pool.outputs.iter().cloned().chain(ours).collect())
+}
+
+/// Give every configured output its amount out of `cb_value`, the
+/// template's subsidy plus fees. Fixed outputs keep their value; `value = 0`
+/// entries split what is left, the first taking any rounding dust. Order
+/// is kept as configured.
+pub fn resolve_cb_outputs(outputs: &[CoinbaseOut], cb_value: u64) -> Result<Vec<CoinbaseOut>> {
+    let fixed = outputs
+        .iter()
+        .try_fold(0u64, |acc, o| acc.checked_add(o.value))
+        .filter(|&total| total <= cb_value)
+        .ok_or_else(|| Sv2Error::InvalidState(format!(
+            "coinbase outputs pay more than the coinbase value {}", cb_value
+        )))?;
+
+    let shares = outputs.iter().filter(|o| o.value == 0).count() as u64;
+    let rest = cb_value - fixed;
+    let (each, dust) = match shares {
+        0 => (0, 0),
+        n => (rest / n, rest % n),
+    };
+
+    let mut first = true;
+    Ok(outputs
+        .iter()
+        .map(|o| {
+            let value = match o.value {
+                0 if first => {
+                    first = false;
+                    each + dust
+                }
+                0 => each,
+                v => v,
+            };
+            CoinbaseOut { value, script_pubkey: o.script_pubkey.clone() }
+        })
+        .collect())
+}
+
 fn encode_height(h: u64) -> Vec<u8> {
     let mut out = Vec::new();
     
@@ -479,7 +544,8 @@ fn encode_height(h: u64) -> Vec<u8> {
     out
 }
 
-fn witness_script(commitment: &[u8; 32]) -> Vec<u8> {
+/// scriptPubKey of the BIP141 commitment output
+pub fn witness_script(commitment: &[u8; 32]) -> Vec<u8> {
     let mut s = Vec::new();
     s.push(0x6A); // OP_RETURN
     s.push(0x24); // push 36
@@ -517,13 +583,48 @@ pub fn merkle_root(txids: &[[u8; 32]]) -> [u8; 32] {
     level[0]
 }
 
+/// Siblings of the coinbase leaf, bottom up, for the given non-coinbase
+/// txids (internal byte order)
+pub fn merkle_path(txids: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    let mut path = Vec::new();
+
+    // The coinbase hash never feeds into a sibling, so any placeholder works
+    let mut level: Vec<[u8; 32]> = Vec::with_capacity(txids.len() + 1);
+    level.push([0u8; 32]);
+    level.extend_from_slice(txids);
+
+    while level.len() > 1 {
+        path.push(level[1]);
+
+        let mut next = Vec::new();
+        for i in (0..level.len()).step_by(2) {
+            let left = level[i];
+            let right = if i + 1 < level.len() { level[i + 1] } else { left };
+            next.push(merkle_pair(&left, &right));
+        }
+        level = next;
+    }
+
+    path
+}
+
+/// BIP141 commitment for a block whose non-coinbase wtxids are `wtxids`.
+/// The coinbase counts as an all-zero wtxid, and the witness reserved
+/// value is the all-zero one our coinbase carries.
+pub fn calc_witness_commitment(wtxids: &[[u8; 32]]) -> [u8; 32] {
+    let mut leaves = Vec::with_capacity(wtxids.len() + 1);
+    leaves.push([0u8; 32]);
+    leaves.extend_from_slice(wtxids);
+    witness_commitment(&[0u8; 32], &merkle_root(&leaves))
+}
+
 fn merkle_pair(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
     let mut cat = Vec::with_capacity(64);
     cat.extend_from_slice(a);
     cat.extend_from_slice(b);
     
     let h1 = Sha256::digest(&cat);
-    let h2 = Sha256::digest(&h1);
+    let h2 = Sha256::digest(h1);
     
     let mut out = [0u8; 32];
     out.copy_from_slice(&h2);
@@ -536,7 +637,7 @@ pub fn witness_commitment(nonce: &[u8; 32], root: &[u8; 32]) -> [u8; 32] {
     cat.extend_from_slice(nonce);
     
     let h1 = Sha256::digest(&cat);
-    let h2 = Sha256::digest(&h1);
+    let h2 = Sha256::digest(h1);
     
     let mut out = [0u8; 32];
     out.copy_from_slice(&h2);
@@ -555,6 +656,134 @@ mod tests {
         assert_eq!(buf[4], 5);
     }
     
+    #[test]
+    fn test_setup_conn_roundtrip() {
+        let msg = SetupConn {
+            protocol: PROTO_JD,
+            min_ver: 2,
+            max_ver: 2,
+            flags: jd_flags::REQUIRES_ASYNC_JOB_MINING,
+            host: "127.0.0.1".into(),
+            port: 34254,
+            vendor: "sv2-jdc".into(),
+            hw_ver: STR0_255::default(),
+            firmware: "0.1.0".into(),
+            device_id: STR0_255::default(),
+        };
+        let buf = msg.serialize().unwrap();
+        assert_eq!(buf[0], PROTO_JD);
+        assert_eq!(&buf[5..9], &1u32.to_le_bytes());
+        assert_eq!(buf[9] as usize, "127.0.0.1".len());
+
+        let ok = SetupConnOk::parse(&[0x02, 0x00, 0x01, 0x00, 0x00, 0x00]).unwrap();
+        assert_eq!(ok.used_ver, 2);
+        assert_eq!(ok.flags, 1);
+
+        let mut raw = vec![0u8; 4];
+        raw.push(25);
+        raw.extend_from_slice(b"unsupported-feature-flags");
+        let err = SetupConnErr::parse(&raw).unwrap();
+        assert_eq!(&*err.code, "unsupported-feature-flags");
+        assert!(SetupConnErr::parse(&raw[..10]).is_err());
+    }
+
+    #[test]
+    fn test_split_host_port() {
+        assert_eq!(split_host_port("pool.example.com:3336").unwrap(), ("pool.example.com", 3336));
+        assert_eq!(split_host_port("127.0.0.1:34254").unwrap(), ("127.0.0.1", 34254));
+        assert_eq!(split_host_port("[::1]:34254").unwrap(), ("::1", 34254));
+        assert!(split_host_port("pool.example.com").is_err());
+        assert!(split_host_port(":3336").is_err());
+        assert!(split_host_port("pool.example.com:http").is_err());
+    }
+
+    #[test]
+    fn test_decl_job_layout() {
+        let job = DeclJob {
+            req_id: 7,
+            token: vec![0xAB; 3].into(),
+            version: 0x20000000,
+            cb_prefix: vec![1, 2].into(),
+            cb_suffix: vec![3].into(),
+            hash_nonce: 9,
+            short_hashes: vec![ShortTxId(0x11), ShortTxId(0x22)].into(),
+            tx_list_hash: U256([0xEE; 32]),
+            extra: B0_64K::default(),
+        };
+        let buf = job.serialize().unwrap();
+
+        // req_id(4) token(1+3) version(4) prefix(2+2) suffix(2+1) nonce(8)
+        // shorts(2+12) hash(32) extra(2)
+        assert_eq!(buf.len(), 4 + 4 + 4 + 4 + 3 + 8 + 14 + 32 + 2);
+        assert_eq!(&buf[12..14], &2u16.to_le_bytes());
+        assert_eq!(DeclJob::parse(&buf).unwrap(), job);
+    }
+
+    #[test]
+    fn test_strict_parsers() {
+        // A token that exactly fills the payload used to be dropped
+        let ok = DeclJobOk::parse(&[1, 0, 0, 0, 2, 0xAA, 0xBB]).unwrap();
+        assert_eq!(ok.new_token.as_slice(), &[0xAA, 0xBB]);
+        assert!(DeclJobOk::parse(&[1, 0, 0, 0, 3, 0xAA, 0xBB]).is_err());
+
+        // Truncated position lists are errors, not silently shortened
+        assert!(IdentifyTxs::parse(&[1, 0, 0, 0, 2, 0, 5, 0]).is_err());
+        let ids = IdentifyTxs::parse(&[1, 0, 0, 0, 2, 0, 5, 0, 6, 0]).unwrap();
+        assert_eq!(ids.positions.as_slice(), &[5, 6]);
+
+        assert!(ProvideTxsOk::parse(&[1, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_pool_coinbase() {
+        let pool_out = CoinbaseOut { value: 1000, script_pubkey: vec![0x51; 22] };
+        let mut raw = Vec::new();
+        raw.push(1);
+        raw.extend_from_slice(&1000u64.to_le_bytes());
+        raw.push(22);
+        raw.extend_from_slice(&[0x51; 22]);
+
+        let msg = AllocTokenOk {
+            req_id: 1,
+            token: vec![0xAA].into(),
+            max_cb_extra: 62,
+            async_ok: true,
+            cb_outputs: raw.clone().into(),
+        };
+        let parsed = AllocTokenOk::parse(&msg.serialize().unwrap()).unwrap();
+        let pool = parsed.pool_coinbase().unwrap();
+        assert_eq!(pool.outputs, vec![pool_out.clone()]);
+        assert_eq!(pool.max_extra, 62);
+
+        // Pool's output first; 31 + 31 bytes fits exactly, one more doesn't
+        let ours = CoinbaseOut { value: 0, script_pubkey: vec![0x52; 22] };
+        let merged = merge_pool_outputs(std::slice::from_ref(&ours), &pool, 5000).unwrap();
+        let rest = CoinbaseOut { value: 4000, ..ours.clone() };
+        assert_eq!(merged, vec![pool_out, rest]);
+        assert!(merge_pool_outputs(&[ours.clone(), ours.clone()], &pool, 5000).is_err());
+        let long = CoinbaseOut { value: 0, script_pubkey: vec![0x52; 23] };
+        assert!(merge_pool_outputs(&[long], &pool, 5000).is_err());
+        assert!(merge_pool_outputs(&[], &pool, 5000).is_ok());
+        // The pool's own output alone is over the limit
+        let tight = PoolCoinbase { max_extra: 30, ..pool.clone() };
+        assert!(merge_pool_outputs(&[], &tight, 5000).is_err());
+        assert!(merge_pool_outputs(std::slice::from_ref(&ours), &pool, 999).is_err());
+
+        // A zero-value pool output stays at zero
+        let zero = CoinbaseOut { value: 0, script_pubkey: vec![0x6A] };
+        let pool0 = PoolCoinbase { outputs: vec![zero.clone()], max_extra: 100 };
+        let merged = merge_pool_outputs(std::slice::from_ref(&ours), &pool0, 5000).unwrap();
+        assert_eq!(merged, vec![zero, CoinbaseOut { value: 5000, ..ours }]);
+
+        // No outputs at all, not even a count
+        let none = AllocTokenOk { cb_outputs: Vec::new().into(), ..parsed.clone() };
+        assert!(none.pool_coinbase().unwrap().outputs.is_empty());
+
+        raw.push(0);
+        let bad = AllocTokenOk { cb_outputs: raw.into(), ..parsed };
+        assert!(bad.pool_coinbase().is_err());
+    }
+
     #[test]
     fn test_frame_builder() {
         let payload = vec![0x01, 0x02, 0x03];
@@ -573,4 +802,96 @@ mod tests {
         assert_eq!(encode_height(127), vec![0x01, 0x7F]);
         assert_eq!(encode_height(256), vec![0x02, 0x00, 0x01]);
     }
+
+    #[test]
+    fn test_coinbase_parts() {
+        let prefix = build_cb_prefix(CB_TX_VERSION, 256, b"tag", CB_EXTRANONCE_LEN);
+        // version(4) marker/flag(2) inputs(1) prevout(36) script len(1)
+        assert_eq!(prefix[43] as usize, 3 + 3 + CB_EXTRANONCE_LEN);
+
+        let outs = [CoinbaseOut { value: 50, script_pubkey: vec![0x51] }];
+        let suffix = build_cb_suffix(&outs, None);
+        let (p, s) = cb_strip_witness(&prefix, &suffix);
+        assert_eq!(p.len(), prefix.len() - 2);
+        assert_eq!(&p[4..6], &[0x01, 0x00]);
+        assert_eq!(s.len(), suffix.len() - 34);
+        assert_eq!(&s[s.len() - 4..], &[0u8; 4]);
+    }
+
+    #[test]
+    fn test_cb_outputs() {
+        let out = |value, script: u8| CoinbaseOut { value, script_pubkey: vec![script] };
+
+        let res = resolve_cb_outputs(&[out(0, 1), out(30, 2), out(0, 3)], 101).unwrap();
+        let values: Vec<u64> = res.iter().map(|o| o.value).collect();
+        assert_eq!(values, vec![36, 30, 35]);
+        assert_eq!(res[2].script_pubkey, vec![3]);
+
+        assert!(resolve_cb_outputs(&[out(60, 1), out(50, 2)], 100).is_err());
+        assert!(resolve_cb_outputs(&[out(u64::MAX, 1), out(1, 2)], 100).is_err());
+
+        // count, then value(8) + script len(1) + script(1) per output,
+        // commitment last
+        let buf = build_cb_outputs(&res, Some(&[0xCD; 32]));
+        assert_eq!(buf[0], 4);
+        assert_eq!(&buf[1..9], &36u64.to_le_bytes());
+        assert_eq!(&buf[1 + 2 * 10..1 + 2 * 10 + 8], &35u64.to_le_bytes());
+        assert_eq!(&buf[buf.len() - 32..], &[0xCD; 32]);
+    }
+
+    #[test]
+    fn test_merkle_path() {
+        let txids: Vec<[u8; 32]> = (1..=4u8).map(|i| [i; 32]).collect();
+        let cb = [0xCC; 32];
+
+        let mut all = vec![cb];
+        all.extend_from_slice(&txids);
+        let expected = merkle_root(&all);
+
+        let mut root = cb;
+        for sib in merkle_path(&txids) {
+            root = merkle_pair(&root, &sib);
+        }
+        assert_eq!(root, expected);
+        assert!(merkle_path(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_witness_commitment() {
+        let w = [[7u8; 32], [8u8; 32]];
+        let root = merkle_pair(&merkle_pair(&[0; 32], &w[0]), &merkle_pair(&w[1], &w[1]));
+        let c = calc_witness_commitment(&w);
+        assert_eq!(c, witness_commitment(&[0; 32], &root));
+
+        let script = witness_script(&c);
+        assert_eq!(hex::encode(&script[..6]), "6a24aa21a9ed");
+        assert_eq!(&script[6..], &c);
+    }
+
+    #[test]
+    fn test_short_hash() {
+        // SipHash-2-4 reference vectors, key 00..0f
+        let keys = (0x0706050403020100, 0x0f0e0d0c0b0a0908);
+        let sip = |msg: &[u8]| {
+            let mut h = SipHasher24::new_with_keys(keys.0, keys.1);
+            h.write(msg);
+            h.finish()
+        };
+        assert_eq!(sip(&[]), 0x726fdb47dd0e0e31);
+        assert_eq!(sip(&(0..15).collect::<Vec<u8>>()), 0xa129ca6149be45e5);
+
+        let keys = short_hash_keys(42);
+        assert_eq!(keys, (0xc6f218bc089104ed, 0x0b8542ead0e86943));
+
+        let wtxid: [u8; 32] = core::array::from_fn(|i| i as u8);
+        assert_eq!(calc_short_hash(&wtxid, keys), ShortTxId(0x91cb0eb0b8c8));
+        assert_eq!(calc_short_hash(&[0xAA; 32], short_hash_keys(0)), ShortTxId(0x458e431f1d3c));
+    }
+
+    #[test]
+    fn test_short_hash_collision() {
+        let ids = [[1; 32], [2; 32]];
+        assert_eq!(calc_short_hashes(&ids, 7).unwrap().len(), 2);
+        assert!(calc_short_hashes(&[[1; 32], [1; 32]], 7).is_none());
+    }
 }
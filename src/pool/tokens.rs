@@ -0,0 +1,156 @@
+//! Mining job token pool
+//!
+//! Every DeclareMiningJob spends a token from AllocateMiningJobToken. The
+//! pool keeps a few allocated ahead of time and tops itself up as they are
+//! used, so a declaration never waits on a token round-trip. Tokens older
+//! than the configured lifetime are thrown away unused, and requests the
+//! pool never answered are given up on and asked again.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+use crate::common::PoolCoinbase;
+
+/// How long an AllocateMiningJobToken may go unanswered
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub bytes: Vec<u8>,
+    /// The pool allows mining on it before the declaration is acknowledged
+    pub async_ok: bool,
+    /// Coinbase requirements that came with it
+    pub coinbase: PoolCoinbase,
+    issued: Instant,
+}
+
+#[derive(Debug)]
+pub struct TokenPool {
+    ready: VecDeque<Token>,
+    /// Outstanding requests by req_id, with when they were sent
+    requested: HashMap<u32, Instant>,
+    size: usize,
+    ttl: Duration,
+}
+
+impl TokenPool {
+    pub fn new(size: usize, ttl: Duration) -> Self {
+        Self {
+            ready: VecDeque::new(),
+            requested: HashMap::new(),
+            size: size.max(1),
+            ttl,
+        }
+    }
+
+    /// Drop expired tokens and requests that timed out
+    fn expire(&mut self, now: Instant) {
+        let ttl = self.ttl;
+        let before = self.ready.len();
+        self.ready.retain(|t| now.duration_since(t.issued) < ttl);
+        if self.ready.len() < before {
+            debug!("{} tokens expired unused", before - self.ready.len());
+        }
+        self.requested.retain(|_, sent| now.duration_since(*sent) < REQUEST_TIMEOUT);
+    }
+
+    /// Tokens to request now to get back to full
+    pub fn wanted(&mut self, now: Instant) -> usize {
+        self.expire(now);
+        self.size.saturating_sub(self.ready.len() + self.requested.len())
+    }
+
+    pub fn requested(&mut self, req_id: u32, now: Instant) {
+        self.requested.insert(req_id, now);
+    }
+
+    /// Add an allocated token. Answers to requests we gave up on are still
+    /// good tokens, so they're kept too.
+    pub fn allocated(
+        &mut self,
+        req_id: u32,
+        bytes: Vec<u8>,
+        async_ok: bool,
+        coinbase: PoolCoinbase,
+        now: Instant,
+    ) {
+        self.requested.remove(&req_id);
+        self.ready.push_back(Token { bytes, async_ok, coinbase, issued: now });
+    }
+
+    pub fn has_token(&mut self, now: Instant) -> bool {
+        self.expire(now);
+        !self.ready.is_empty()
+    }
+
+    /// The token `take` would hand out next
+    pub fn peek(&mut self, now: Instant) -> Option<&Token> {
+        self.expire(now);
+        self.ready.front()
+    }
+
+    /// The oldest live token
+    pub fn take(&mut self, now: Instant) -> Option<Token> {
+        self.expire(now);
+        self.ready.pop_front()
+    }
+
+    /// Forget every token, e.g. when the pool stops accepting them
+    pub fn flush(&mut self) {
+        self.ready.clear();
+    }
+
+    /// Start over on a new connection
+    pub fn reset(&mut self) {
+        self.ready.clear();
+        self.requested.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_refill_and_take() {
+        let t0 = Instant::now();
+        let mut pool = TokenPool::new(3, Duration::from_secs(600));
+
+        assert_eq!(pool.wanted(t0), 3);
+        for req in 1..=3 {
+            pool.requested(req, t0);
+        }
+        assert_eq!(pool.wanted(t0), 0);
+        assert!(!pool.has_token(t0));
+
+        pool.allocated(1, vec![1], true, PoolCoinbase::default(), t0);
+        pool.allocated(2, vec![2], false, PoolCoinbase::default(), t0);
+        assert_eq!(pool.take(t0).map(|t| t.bytes), Some(vec![1]));
+
+        // One used and one still on its way: ask for the used one only
+        assert_eq!(pool.wanted(t0), 1);
+        assert!(pool.has_token(t0));
+    }
+
+    #[test]
+    fn test_expiry() {
+        let t0 = Instant::now();
+        let mut pool = TokenPool::new(2, Duration::from_secs(60));
+        pool.requested(1, t0);
+        pool.requested(2, t0);
+        pool.allocated(1, vec![1], false, PoolCoinbase::default(), t0);
+
+        // The unanswered request is retried, the stale token dropped
+        let later = t0 + REQUEST_TIMEOUT;
+        assert_eq!(pool.wanted(later), 1);
+        assert!(pool.take(t0 + Duration::from_secs(60)).is_none());
+        assert_eq!(pool.wanted(t0 + Duration::from_secs(60)), 2);
+
+        // A late answer still counts
+        pool.allocated(2, vec![2], false, PoolCoinbase::default(), later);
+        assert!(pool.has_token(later));
+        pool.flush();
+        assert!(!pool.has_token(later));
+    }
+}
@@ -0,0 +1,301 @@
+//! Bitcoin transactions
+//!
+//! Just enough of the format to tell a txid from a wtxid: template data
+//! carries witnesses, but the merkle tree commits to witness-stripped
+//! txids while short IDs and the witness commitment use wtxids.
+
+use super::binary::Sv2Reader;
+use super::work;
+use crate::common::{Result, Sv2Error};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxIn {
+    /// Previous txid, internal byte order
+    pub prev_txid: [u8; 32],
+    pub prev_vout: u32,
+    pub script_sig: Vec<u8>,
+    pub sequence: u32,
+    pub witness: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxOut {
+    pub value: u64,
+    pub script_pubkey: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transaction {
+    pub version: u32,
+    pub inputs: Vec<TxIn>,
+    pub outputs: Vec<TxOut>,
+    pub locktime: u32,
+}
+
+impl Transaction {
+    /// Parse a serialized transaction, with or without witness data.
+    /// Trailing bytes are an error.
+    pub fn parse(raw: &[u8]) -> Result<Self> {
+        let mut r = Sv2Reader::new(raw);
+        let version = read_u32(&mut r)?;
+
+        let mut n_in = read_compact_size(&mut r)?;
+        let segwit = n_in == 0;
+        if segwit {
+            if r.take(1)?[0] != 0x01 {
+                return Err(Sv2Error::Codec("tx: bad segwit flag".into()));
+            }
+            n_in = read_compact_size(&mut r)?;
+        }
+
+        let mut inputs = Vec::with_capacity(bounded(n_in, &r)?);
+        for _ in 0..n_in {
+            let mut prev_txid = [0u8; 32];
+            prev_txid.copy_from_slice(r.take(32)?);
+            inputs.push(TxIn {
+                prev_txid,
+                prev_vout: read_u32(&mut r)?,
+                script_sig: read_bytes(&mut r)?,
+                sequence: read_u32(&mut r)?,
+                witness: Vec::new(),
+            });
+        }
+
+        let n_out = read_compact_size(&mut r)?;
+        let mut outputs = Vec::with_capacity(bounded(n_out, &r)?);
+        for _ in 0..n_out {
+            outputs.push(TxOut {
+                value: u64::from_le_bytes(r.take(8)?.try_into().unwrap()),
+                script_pubkey: read_bytes(&mut r)?,
+            });
+        }
+
+        if segwit {
+            for input in &mut inputs {
+                let n = read_compact_size(&mut r)?;
+                let mut stack = Vec::with_capacity(bounded(n, &r)?);
+                for _ in 0..n {
+                    stack.push(read_bytes(&mut r)?);
+                }
+                input.witness = stack;
+            }
+        }
+
+        let locktime = read_u32(&mut r)?;
+        if r.remaining() != 0 {
+            return Err(Sv2Error::Codec(format!("tx: {} trailing bytes", r.remaining())));
+        }
+
+        Ok(Self { version, inputs, outputs, locktime })
+    }
+
+    pub fn has_witness(&self) -> bool {
+        self.inputs.iter().any(|i| !i.witness.is_empty())
+    }
+
+    /// Full serialization, witness included when there is any
+    pub fn serialize(&self) -> Vec<u8> {
+        self.encode(self.has_witness())
+    }
+
+    /// Serialization without witness data, as hashed into the txid
+    pub fn serialize_no_witness(&self) -> Vec<u8> {
+        self.encode(false)
+    }
+
+    fn encode(&self, witness: bool) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&self.version.to_le_bytes());
+        if witness {
+            buf.extend_from_slice(&[0x00, 0x01]);
+        }
+
+        write_compact_size(&mut buf, self.inputs.len() as u64);
+        for i in &self.inputs {
+            buf.extend_from_slice(&i.prev_txid);
+            buf.extend_from_slice(&i.prev_vout.to_le_bytes());
+            write_bytes(&mut buf, &i.script_sig);
+            buf.extend_from_slice(&i.sequence.to_le_bytes());
+        }
+
+        write_compact_size(&mut buf, self.outputs.len() as u64);
+        for o in &self.outputs {
+            buf.extend_from_slice(&o.value.to_le_bytes());
+            write_bytes(&mut buf, &o.script_pubkey);
+        }
+
+        if witness {
+            for i in &self.inputs {
+                write_compact_size(&mut buf, i.witness.len() as u64);
+                for item in &i.witness {
+                    write_bytes(&mut buf, item);
+                }
+            }
+        }
+
+        buf.extend_from_slice(&self.locktime.to_le_bytes());
+        buf
+    }
+
+    /// Witness-stripped hash, internal byte order
+    pub fn txid(&self) -> [u8; 32] {
+        work::sha256d(&self.serialize_no_witness())
+    }
+
+    /// Hash including witness data, internal byte order. Equal to the
+    /// txid for transactions without witnesses.
+    pub fn wtxid(&self) -> [u8; 32] {
+        work::sha256d(&self.serialize())
+    }
+
+    /// BIP141 weight: base size x3 plus total size
+    pub fn weight(&self) -> usize {
+        self.serialize_no_witness().len() * 3 + self.serialize().len()
+    }
+
+    pub fn vsize(&self) -> usize {
+        self.weight().div_ceil(4)
+    }
+}
+
+/// Refuse counts that can't fit in what is left before allocating
+fn bounded(n: u64, r: &Sv2Reader<'_>) -> Result<usize> {
+    if n > r.remaining() as u64 {
+        return Err(Sv2Error::Codec(format!("tx: count {} exceeds {} remaining bytes", n, r.remaining())));
+    }
+    Ok(n as usize)
+}
+
+fn read_u32(r: &mut Sv2Reader<'_>) -> Result<u32> {
+    Ok(u32::from_le_bytes(r.take(4)?.try_into().unwrap()))
+}
+
+fn read_bytes(r: &mut Sv2Reader<'_>) -> Result<Vec<u8>> {
+    let n = read_compact_size(r)?;
+    let n = bounded(n, r)?;
+    Ok(r.take(n)?.to_vec())
+}
+
+/// Parse a compact-size count followed by that many outputs, as they sit
+/// in a serialized transaction. Trailing bytes are an error.
+pub fn parse_outputs(raw: &[u8]) -> Result<Vec<TxOut>> {
+    let mut r = Sv2Reader::new(raw);
+    let n = read_compact_size(&mut r)?;
+    let mut outputs = Vec::with_capacity(bounded(n, &r)?);
+    for _ in 0..n {
+        outputs.push(TxOut {
+            value: u64::from_le_bytes(r.take(8)?.try_into().unwrap()),
+            script_pubkey: read_bytes(&mut r)?,
+        });
+    }
+
+    if r.remaining() != 0 {
+        return Err(Sv2Error::Codec(format!("outputs: {} trailing bytes", r.remaining())));
+    }
+    Ok(outputs)
+}
+
+pub fn read_compact_size(r: &mut Sv2Reader<'_>) -> Result<u64> {
+    let n = match r.take(1)?[0] {
+        0xFD => u16::from_le_bytes(r.take(2)?.try_into().unwrap()) as u64,
+        0xFE => u32::from_le_bytes(r.take(4)?.try_into().unwrap()) as u64,
+        0xFF => u64::from_le_bytes(r.take(8)?.try_into().unwrap()),
+        b => b as u64,
+    };
+    Ok(n)
+}
+
+pub fn write_compact_size(buf: &mut Vec<u8>, n: u64) {
+    match n {
+        0..=0xFC => buf.push(n as u8),
+        0xFD..=0xFFFF => {
+            buf.push(0xFD);
+            buf.extend_from_slice(&(n as u16).to_le_bytes());
+        }
+        0x1_0000..=0xFFFF_FFFF => {
+            buf.push(0xFE);
+            buf.extend_from_slice(&(n as u32).to_le_bytes());
+        }
+        _ => {
+            buf.push(0xFF);
+            buf.extend_from_slice(&n.to_le_bytes());
+        }
+    }
+}
+
+fn write_bytes(buf: &mut Vec<u8>, data: &[u8]) {
+    write_compact_size(buf, data.len() as u64);
+    buf.extend_from_slice(data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The genesis coinbase
+    const GENESIS_TX: &str = "01000000010000000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000";
+    const GENESIS_TXID: &str = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+
+    fn display(mut h: [u8; 32]) -> String {
+        h.reverse();
+        hex::encode(h)
+    }
+
+    #[test]
+    fn test_legacy_tx() {
+        let raw = hex::decode(GENESIS_TX).unwrap();
+        let tx = Transaction::parse(&raw).unwrap();
+
+        assert_eq!(tx.inputs.len(), 1);
+        assert_eq!(tx.outputs[0].value, 50 * 100_000_000);
+        assert!(!tx.has_witness());
+        assert_eq!(tx.serialize(), raw);
+        assert_eq!(display(tx.txid()), GENESIS_TXID);
+        assert_eq!(tx.wtxid(), tx.txid());
+        assert_eq!(tx.weight(), raw.len() * 4);
+        assert_eq!(tx.vsize(), raw.len());
+    }
+
+    #[test]
+    fn test_witness_tx() {
+        let mut tx = Transaction::parse(&hex::decode(GENESIS_TX).unwrap()).unwrap();
+        tx.inputs[0].witness = vec![vec![0xAB; 32]];
+
+        let raw = tx.serialize();
+        assert_eq!(&raw[4..6], &[0x00, 0x01]);
+        assert_eq!(raw.len(), 204 + 2 + 1 + 1 + 32);
+
+        // The witness moves the wtxid but not the txid
+        let parsed = Transaction::parse(&raw).unwrap();
+        assert_eq!(parsed, tx);
+        assert_eq!(display(parsed.txid()), GENESIS_TXID);
+        assert_eq!(parsed.wtxid(), work::sha256d(&raw));
+        assert_ne!(parsed.wtxid(), parsed.txid());
+        assert_eq!(parsed.weight(), 204 * 3 + raw.len());
+        assert_eq!(parsed.vsize(), (204 * 3 + raw.len()).div_ceil(4));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let raw = hex::decode(GENESIS_TX).unwrap();
+        assert!(Transaction::parse(&raw[..raw.len() - 1]).is_err());
+        assert!(Transaction::parse(&[raw.as_slice(), &[0]].concat()).is_err());
+        // Segwit marker with a bad flag
+        assert!(Transaction::parse(&[1, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_compact_size() {
+        let enc = |n| {
+            let mut b = Vec::new();
+            write_compact_size(&mut b, n);
+            b
+        };
+        assert_eq!(enc(0xFC), vec![0xFC]);
+        assert_eq!(enc(0xFD), vec![0xFD, 0xFD, 0x00]);
+        assert_eq!(enc(0x1_0000), vec![0xFE, 0, 0, 1, 0]);
+
+        let buf = enc(0x1_0000);
+        assert_eq!(read_compact_size(&mut Sv2Reader::new(&buf)).unwrap(), 0x1_0000);
+    }
+}
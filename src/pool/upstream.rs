@@ -0,0 +1,396 @@
+//! Upstream Mining Protocol connection
+//!
+//! Declared jobs only earn credit once they are registered on a mining
+//! channel. This opens an extended channel on the pool's mining endpoint,
+//! registers each declared job with SetCustomMiningJob and forwards
+//! downstream shares that meet the channel target.
+
+use futures::{SinkExt, StreamExt};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::broadcast;
+use tokio_util::codec::Framed;
+use tracing::{debug, error, info, warn};
+
+use crate::common::{CustomJob, Event, Result, Share, Sv2Error};
+use super::binary::*;
+use super::codec::{Sv2Frame, Sv2NoiseCodec};
+use super::mining_messages::*;
+use super::sv2_messages::{
+    self, SetupConn, SetupConnErr, SetupConnOk, CB_EXTRANONCE_LEN, CB_TX_VERSION, COMMON_EXT,
+    split_host_port, SV2_MAX_VERSION, SV2_MIN_VERSION,
+};
+use super::{noise, work, PoolConnConfig};
+
+/// Registered jobs kept for share forwarding
+const MAX_JOBS: usize = 32;
+
+struct Channel {
+    id: u32,
+    target: [u8; 32],
+}
+
+pub struct MiningUpstream {
+    cfg: PoolConnConfig,
+    bus_tx: broadcast::Sender<Event>,
+    bus_rx: broadcast::Receiver<Event>,
+    channel: Option<Channel>,
+    req_seq: u32,
+    share_seq: u32,
+    /// SetCustomMiningJob request id -> template id
+    pending: HashMap<u32, u64>,
+    /// Template id -> upstream job id
+    jobs: BTreeMap<u64, u32>,
+}
+
+impl MiningUpstream {
+    pub fn new(
+        cfg: PoolConnConfig,
+        bus_tx: broadcast::Sender<Event>,
+        bus_rx: broadcast::Receiver<Event>,
+    ) -> Self {
+        Self {
+            cfg,
+            bus_tx,
+            bus_rx,
+            channel: None,
+            req_seq: 0,
+            share_seq: 0,
+            pending: HashMap::new(),
+            jobs: BTreeMap::new(),
+        }
+    }
+
+    fn next_req(&mut self) -> u32 {
+        self.req_seq = self.req_seq.wrapping_add(1);
+        self.req_seq
+    }
+
+    pub async fn run(mut self) -> Result<()> {
+        info!("Starting upstream mining connection");
+
+        let addr = self.cfg.mining_address.clone().unwrap_or_default();
+        split_host_port(&addr)?;
+        let authority = self.cfg.authority_key()?;
+
+        loop {
+            info!("Connecting to pool mining endpoint {}", addr);
+
+            match self.session(&addr, authority).await {
+                Ok(()) => warn!("Pool closed mining connection"),
+                Err(Sv2Error::Shutdown) => {
+                    info!("Upstream mining connection shutting down");
+                    return Ok(());
+                }
+                Err(e) => {
+                    error!("Mining connection error: {}", e);
+                    let _ = self.bus_tx.send(Event::Err(format!("mining channel: {}", e)));
+                }
+            }
+
+            if self.channel.take().is_some() {
+                let _ = self.bus_tx.send(Event::ChannelDown);
+            }
+            self.pending.clear();
+            self.jobs.clear();
+
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        }
+    }
+
+    async fn session(
+        &mut self,
+        addr: &str,
+        authority: Option<secp256k1::XOnlyPublicKey>,
+    ) -> Result<()> {
+        let mut stream = TcpStream::connect(addr).await?;
+        let codec = noise::initiator_handshake(&mut stream, authority).await?;
+        info!("Mining channel encrypted");
+
+        let mut framed = Framed::new(stream, Sv2NoiseCodec::new(codec));
+        framed.send(self.setup_frame(addr)?).await?;
+
+        loop {
+            tokio::select! {
+                res = framed.next() => {
+                    let Some(frame) = res else { return Ok(()) };
+                    for out in self.handle_msg(frame?)? {
+                        framed.send(out).await?;
+                    }
+                }
+
+                Ok(ev) = self.bus_rx.recv() => {
+                    for out in self.handle_event(ev)? {
+                        framed.send(out).await?;
+                    }
+                }
+            }
+        }
+    }
+
+    fn setup_frame(&self, addr: &str) -> Result<Sv2Frame> {
+        let (host, port) = split_host_port(addr)?;
+        let msg = SetupConn {
+            protocol: PROTO_MINING,
+            min_ver: SV2_MIN_VERSION,
+            max_ver: SV2_MAX_VERSION,
+            flags: mining_flags::REQUIRES_WORK_SELECTION | mining_flags::REQUIRES_VERSION_ROLLING,
+            host: host.into(),
+            port,
+            vendor: self.cfg.vendor.as_str().into(),
+            hw_ver: self.cfg.hardware_version.as_str().into(),
+            firmware: self.cfg.firmware.as_str().into(),
+            device_id: self.cfg.device_id.as_str().into(),
+        };
+        Ok(Sv2Frame::new(sv2_messages::msg_types::SETUP_CONN, COMMON_EXT, msg.serialize()?))
+    }
+
+    fn handle_msg(&mut self, frame: Sv2Frame) -> Result<Vec<Sv2Frame>> {
+        debug!("Mining msg: type=0x{:02X}, len={}", frame.mtype, frame.payload.len());
+
+        match frame.mtype {
+            sv2_messages::msg_types::SETUP_CONN_OK => {
+                let msg = SetupConnOk::parse(&frame.payload)?;
+                info!("Mining setup done: version={}", msg.used_ver);
+                Ok(vec![self.open_channel_frame()?])
+            }
+            sv2_messages::msg_types::SETUP_CONN_ERR => {
+                let msg = SetupConnErr::parse(&frame.payload)?;
+                Err(Sv2Error::PoolConnection(format!("mining setup rejected: {}", msg.code)))
+            }
+            msg_types::OPEN_EXTENDED_CHANNEL_OK => {
+                let msg = OpenExtendedChannelOk::parse(&frame.payload)?;
+                self.on_channel_open(msg)?;
+                Ok(Vec::new())
+            }
+            msg_types::OPEN_CHANNEL_ERR => {
+                let msg = OpenChannelErr::parse(&frame.payload)?;
+                Err(Sv2Error::PoolConnection(format!("channel rejected: {}", msg.code)))
+            }
+            msg_types::SET_CUSTOM_MINING_JOB_OK => {
+                let msg = SetCustomMiningJobOk::parse(&frame.payload)?;
+                if let Some(tpl_id) = self.pending.remove(&msg.req_id) {
+                    info!("Custom job registered: tpl={}, job={}", tpl_id, msg.job_id);
+                    self.jobs.insert(tpl_id, msg.job_id);
+                    while self.jobs.len() > MAX_JOBS {
+                        self.jobs.pop_first();
+                    }
+                }
+                Ok(Vec::new())
+            }
+            msg_types::SET_CUSTOM_MINING_JOB_ERR => {
+                let msg = SetCustomMiningJobErr::parse(&frame.payload)?;
+                let tpl_id = self.pending.remove(&msg.req_id);
+                warn!("Custom job rejected: tpl={:?}, {}", tpl_id, msg.code);
+                let _ = self.bus_tx.send(Event::Err(format!("custom job: {}", msg.code)));
+                Ok(Vec::new())
+            }
+            msg_types::SET_TARGET => {
+                let msg = SetTarget::parse(&frame.payload)?;
+                if let Some(ch) = self.channel.as_mut().filter(|c| c.id == msg.channel_id) {
+                    ch.target = msg.max_target.0;
+                    debug!("Upstream target updated");
+                }
+                Ok(Vec::new())
+            }
+            msg_types::SUBMIT_SHARES_OK => {
+                let msg = SubmitSharesOk::parse(&frame.payload)?;
+                debug!("Upstream shares ok: last_seq={}, accepted={}", msg.last_seq, msg.accepted);
+                Ok(Vec::new())
+            }
+            msg_types::SUBMIT_SHARES_ERR => {
+                let msg = SubmitSharesErr::parse(&frame.payload)?;
+                warn!("Upstream share rejected: seq={}, {}", msg.seq, msg.code);
+                Ok(Vec::new())
+            }
+            other => {
+                // The pool's own jobs are of no use to us
+                debug!("Ignoring mining msg 0x{:02X}", other);
+                Ok(Vec::new())
+            }
+        }
+    }
+
+    fn open_channel_frame(&mut self) -> Result<Sv2Frame> {
+        let msg = OpenExtendedChannel {
+            req_id: self.next_req(),
+            user: self.cfg.user_identity.as_str().into(),
+            hashrate: F32(self.cfg.hashrate),
+            max_target: U256([0xFF; 32]),
+            min_extranonce_size: CB_EXTRANONCE_LEN as u16,
+        };
+        Ok(Sv2Frame::new(msg_types::OPEN_EXTENDED_CHANNEL, MINING_EXT, msg.serialize()?))
+    }
+
+    fn on_channel_open(&mut self, msg: OpenExtendedChannelOk) -> Result<()> {
+        if (msg.extranonce_size as usize) < CB_EXTRANONCE_LEN {
+            return Err(Sv2Error::PoolConnection(format!(
+                "channel extranonce too small: {} < {}", msg.extranonce_size, CB_EXTRANONCE_LEN
+            )));
+        }
+
+        info!(
+            "Extended channel {} open: prefix={}, extranonce_size={}",
+            msg.channel_id, hex::encode(msg.extranonce_prefix.as_slice()), msg.extranonce_size
+        );
+
+        self.channel = Some(Channel { id: msg.channel_id, target: msg.target.0 });
+        let _ = self.bus_tx.send(Event::ChannelUp {
+            channel_id: msg.channel_id,
+            extranonce_prefix: msg.extranonce_prefix.into_inner(),
+        });
+        Ok(())
+    }
+
+    fn handle_event(&mut self, ev: Event) -> Result<Vec<Sv2Frame>> {
+        match ev {
+            Event::CustomJob(job) => Ok(self.set_custom_job(job)?.into_iter().collect()),
+            Event::Share(share) => Ok(self.forward_share(share)?.into_iter().collect()),
+            Event::Shutdown => Err(Sv2Error::Shutdown),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    fn set_custom_job(&mut self, job: CustomJob) -> Result<Option<Sv2Frame>> {
+        let Some(channel_id) = self.channel.as_ref().map(|c| c.id) else {
+            warn!("No mining channel, template {} not registered", job.tpl_id);
+            return Ok(None);
+        };
+
+        let req_id = self.next_req();
+        let msg = SetCustomMiningJob {
+            channel_id,
+            req_id,
+            token: job.token.into(),
+            version: job.header.version,
+            prev_hash: job.header.prev_hash.into(),
+            min_ntime: job.header.time,
+            bits: job.header.bits,
+            cb_tx_version: CB_TX_VERSION,
+            cb_prefix: job.script_prefix.into(),
+            cb_sequence: 0xFFFF_FFFF,
+            cb_value_remaining: job.cb_value,
+            cb_outputs: job.cb_outputs.into(),
+            cb_locktime: 0,
+            merkle_path: job.merkle_path.into_iter().map(U256).collect(),
+            extranonce_size: job.extranonce_size,
+        };
+
+        debug!("SetCustomMiningJob: tpl={}, req={}", job.tpl_id, req_id);
+        self.pending.insert(req_id, job.tpl_id);
+        Ok(Some(Sv2Frame::new(msg_types::SET_CUSTOM_MINING_JOB, MINING_EXT, msg.serialize()?)))
+    }
+
+    /// Pass on a downstream share if it also meets the upstream target
+    fn forward_share(&mut self, share: Share) -> Result<Option<Sv2Frame>> {
+        let Some(ch) = &self.channel else {
+            return Ok(None);
+        };
+        // Solo jobs share template ids with declared ones, not coinbases
+        if share.solo {
+            return Ok(None);
+        }
+        let Some(&job_id) = self.jobs.get(&share.tpl_id) else {
+            debug!("Share for unregistered template {}", share.tpl_id);
+            return Ok(None);
+        };
+        if !work::le_lte(&share.hash, &ch.target) {
+            return Ok(None);
+        }
+
+        self.share_seq = self.share_seq.wrapping_add(1);
+        let msg = SubmitSharesExtended {
+            channel_id: ch.id,
+            seq: self.share_seq,
+            job_id,
+            nonce: share.nonce,
+            ntime: share.ntime,
+            version: share.version,
+            extranonce: share.extranonce.into(),
+        };
+        Ok(Some(Sv2Frame::new(
+            msg_types::SUBMIT_SHARES_EXTENDED, MINING_EXT | CHANNEL_BIT, msg.serialize()?,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::TplHeader;
+
+    fn upstream() -> (MiningUpstream, broadcast::Receiver<Event>) {
+        let cfg: PoolConnConfig = toml::from_str(
+            "address = \"127.0.0.1:34254\"\nmining_address = \"127.0.0.1:34256\"",
+        ).unwrap();
+        let (tx, rx) = broadcast::channel(8);
+        let sub = tx.subscribe();
+        (MiningUpstream::new(cfg, tx, rx), sub)
+    }
+
+    fn share(tpl_id: u64, hash: [u8; 32]) -> Share {
+        Share {
+            tpl_id,
+            version: 0x20000000,
+            ntime: 1,
+            nonce: 2,
+            extranonce: vec![0; CB_EXTRANONCE_LEN],
+            hash,
+            solo: false,
+        }
+    }
+
+    #[test]
+    fn test_custom_job_and_share_flow() {
+        let (mut up, mut events) = upstream();
+
+        let ok = OpenExtendedChannelOk {
+            req_id: 1,
+            channel_id: 9,
+            target: U256([0xFF; 32]),
+            extranonce_size: 16,
+            extranonce_prefix: vec![0xAB; 4].into(),
+        };
+        up.handle_msg(Sv2Frame::new(
+            msg_types::OPEN_EXTENDED_CHANNEL_OK, MINING_EXT, ok.serialize().unwrap(),
+        )).unwrap();
+        assert!(matches!(events.try_recv().unwrap(), Event::ChannelUp { channel_id: 9, .. }));
+
+        let job = CustomJob {
+            tpl_id: 4,
+            token: vec![1, 2],
+            header: TplHeader { version: 0x20000000, prev_hash: [3; 32], bits: 0x1d00ffff, time: 5 },
+            script_prefix: vec![0x01, 0x7F],
+            cb_value: 50,
+            cb_outputs: vec![1, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            merkle_path: vec![[6; 32]],
+            extranonce_size: 16,
+        };
+        let frame = up.set_custom_job(job).unwrap().unwrap();
+        let msg = SetCustomMiningJob::parse(&frame.payload).unwrap();
+        assert_eq!(msg.channel_id, 9);
+        assert_eq!(msg.extranonce_size, 16);
+
+        // Shares wait for the pool to register the job
+        assert!(up.forward_share(share(4, [0; 32])).unwrap().is_none());
+
+        let ok = SetCustomMiningJobOk { channel_id: 9, req_id: msg.req_id, job_id: 77 };
+        up.handle_msg(Sv2Frame::new(
+            msg_types::SET_CUSTOM_MINING_JOB_OK, MINING_EXT, ok.serialize().unwrap(),
+        )).unwrap();
+
+        let frame = up.forward_share(share(4, [0; 32])).unwrap().unwrap();
+        let sub = SubmitSharesExtended::parse(&frame.payload).unwrap();
+        assert_eq!(sub.job_id, 77);
+        assert_eq!(sub.extranonce.len(), CB_EXTRANONCE_LEN);
+
+        // A solo job on the same template has another coinbase
+        let solo = Share { solo: true, ..share(4, [0; 32]) };
+        assert!(up.forward_share(solo).unwrap().is_none());
+
+        // Below the upstream target
+        up.channel.as_mut().unwrap().target = [0; 32];
+        assert!(up.forward_share(share(4, [1; 32])).unwrap().is_none());
+    }
+}
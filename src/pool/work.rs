@@ -0,0 +1,139 @@
+//! Block header and share target arithmetic
+//!
+//! Hashes and targets are 256-bit little-endian values, the byte order
+//! used on the wire by SV2 and by the header hash itself.
+
+use sha2::{Digest, Sha256};
+
+pub fn sha256d(data: &[u8]) -> [u8; 32] {
+    let h = Sha256::digest(Sha256::digest(data));
+    let mut out = [0u8; 32];
+    out.copy_from_slice(&h);
+    out
+}
+
+/// Hash a coinbase txid up the merkle path to the block's merkle root
+pub fn merkle_root_from_path(leaf: [u8; 32], path: &[[u8; 32]]) -> [u8; 32] {
+    let mut root = leaf;
+    let mut cat = [0u8; 64];
+    for sib in path {
+        cat[..32].copy_from_slice(&root);
+        cat[32..].copy_from_slice(sib);
+        root = sha256d(&cat);
+    }
+    root
+}
+
+/// Serialize an 80-byte block header
+pub fn block_header(
+    version: u32,
+    prev_hash: &[u8; 32],
+    merkle_root: &[u8; 32],
+    time: u32,
+    bits: u32,
+    nonce: u32,
+) -> [u8; 80] {
+    let mut h = [0u8; 80];
+    h[0..4].copy_from_slice(&version.to_le_bytes());
+    h[4..36].copy_from_slice(prev_hash);
+    h[36..68].copy_from_slice(merkle_root);
+    h[68..72].copy_from_slice(&time.to_le_bytes());
+    h[72..76].copy_from_slice(&bits.to_le_bytes());
+    h[76..80].copy_from_slice(&nonce.to_le_bytes());
+    h
+}
+
+/// Share target for a pool-style difficulty, where difficulty 1 is
+/// 0x00000000FFFF0000...
+pub fn target_from_difficulty(diff: f64) -> [u8; 32] {
+    if diff.is_nan() || diff <= 0.0 {
+        return [0xFF; 32];
+    }
+
+    // 0xFFFF / diff with 64 fractional bits, then shifted up to bit 208
+    let scaled = 65535.0 / diff * 18446744073709551616.0;
+    if scaled >= u128::MAX as f64 {
+        return [0xFF; 32];
+    }
+    let le = (scaled as u128).to_le_bytes();
+    if le[14] != 0 || le[15] != 0 {
+        return [0xFF; 32];
+    }
+
+    let mut t = [0u8; 32];
+    t[18..32].copy_from_slice(&le[..14]);
+    t
+}
+
+/// Expand a compact `nBits` value into a target
+pub fn target_from_bits(bits: u32) -> [u8; 32] {
+    let exp = (bits >> 24) as usize;
+    let mant = bits & 0x007F_FFFF;
+    let mut t = [0u8; 32];
+
+    for i in 0..3 {
+        let byte = ((mant >> (8 * i)) & 0xFF) as u8;
+        // Byte i of the mantissa lands at position exp - 3 + i
+        if let Some(pos) = (exp + i).checked_sub(3) {
+            if pos < 32 {
+                t[pos] = byte;
+            }
+        }
+    }
+    t
+}
+
+/// `a <= b` for little-endian 256-bit values
+pub fn le_lte(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    a.iter().rev().cmp(b.iter().rev()) != std::cmp::Ordering::Greater
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_difficulty_targets() {
+        let diff1 = target_from_bits(0x1d00ffff);
+        assert_eq!(target_from_difficulty(1.0), diff1);
+        assert_eq!(&diff1[26..28], &[0xFF, 0xFF]);
+
+        let t = target_from_difficulty(256.0);
+        assert_eq!(t[25], 0xFF);
+        assert_eq!(t[26], 0xFF);
+        assert_eq!(t[27], 0);
+
+        assert!(le_lte(&target_from_difficulty(2.0), &diff1));
+        assert!(!le_lte(&target_from_difficulty(0.5), &diff1));
+        assert_eq!(target_from_difficulty(0.0), [0xFF; 32]);
+    }
+
+    #[test]
+    fn test_genesis_header() {
+        let mut root = [0u8; 32];
+        hex::decode_to_slice(
+            "3ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a",
+            &mut root,
+        ).unwrap();
+
+        let header = block_header(1, &[0u8; 32], &root, 1231006505, 0x1d00ffff, 2083236893);
+        let mut hash = sha256d(&header);
+        assert!(le_lte(&hash, &target_from_bits(0x1d00ffff)));
+
+        hash.reverse();
+        assert_eq!(
+            hex::encode(hash),
+            "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"
+        );
+    }
+
+    #[test]
+    fn test_merkle_root_from_path() {
+        let leaf = [1u8; 32];
+        assert_eq!(merkle_root_from_path(leaf, &[]), leaf);
+
+        let mut cat = [1u8; 64];
+        cat[32..].copy_from_slice(&[2u8; 32]);
+        assert_eq!(merkle_root_from_path(leaf, &[[2u8; 32]]), sha256d(&cat));
+    }
+}
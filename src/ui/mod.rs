@@ -95,7 +95,7 @@ impl Dashboard {
             .constraints([
                 Constraint::Length(3),
                 Constraint::Length(7),
-                Constraint::Length(10),
+                Constraint::Length(11),
                 Constraint::Min(5),
                 Constraint::Length(1),
             ])
@@ -122,7 +122,9 @@ impl Dashboard {
             ("Disconnected", Color::Red)
         };
 
-        let pool = if self.st.pool_up {
+        let pool = if self.st.solo {
+            ("Solo mining (fallback)", Color::Magenta)
+        } else if self.st.pool_up {
             if self.st.handshake_ok {
                 ("Connected (Encrypted)", Color::Green)
             } else {
@@ -174,6 +176,18 @@ impl Dashboard {
                 Span::raw("Rejected: "),
                 Span::styled(self.st.rejected.to_string(), Style::default().fg(Color::Red)),
             ]),
+            Line::from(vec![
+                Span::raw("Stale avoided: "),
+                Span::styled(self.st.stale_avoided.to_string(), Style::default().fg(Color::DarkGray)),
+            ]),
+            Line::from(vec![
+                Span::raw("Shares: "),
+                Span::styled(self.st.shares.to_string(), Style::default().fg(Color::Cyan)),
+            ]),
+            Line::from(vec![
+                Span::raw("Blocks: "),
+                Span::styled(self.st.blocks.to_string(), Style::default().fg(Color::Green)),
+            ]),
             Line::from(vec![
                 Span::raw("Fees: "),
                 Span::styled(format!("{} sats", self.st.fees), Style::default().fg(Color::Magenta)),
@@ -241,14 +255,52 @@ impl Dashboard {
                 self.st.declared += 1;
                 self.log(format!("↑ Job sent: id={}, txs={}", tpl_id, txs));
             }
-            Event::JobOk { tpl_id, .. } => {
+            Event::JobOk { tpl_id, token } => {
                 self.st.accepted += 1;
-                self.log(format!("✓ Job accepted: id={}", tpl_id));
+                self.log(format!("✓ Job accepted: id={}, token={}", tpl_id, hex::encode(token)));
             }
             Event::JobFailed { tpl_id, reason } => {
                 self.st.rejected += 1;
                 self.log(format!("✗ Job rejected: id={}, {}", tpl_id, reason));
             }
+            Event::NewPrevHash { height, .. } => {
+                self.log(format!("→ New tip: building on h={}", height));
+            }
+            Event::SoloMode(on) => {
+                self.st.solo = on;
+                if on {
+                    self.log("✗ Pool unusable, mining solo");
+                } else {
+                    self.log("✓ Pool back, leaving solo mining");
+                }
+            }
+            Event::StaleAvoided(n) => {
+                self.st.stale_avoided += n;
+                self.log(format!("↺ Dropped {} stale declarations", n));
+            }
+            Event::NewMiningJob(job) => {
+                self.log(format!("→ Mining job: id={}, branches={}", job.tpl_id, job.merkle_path.len()));
+            }
+            Event::ChannelUp { channel_id, .. } => {
+                self.log(format!("✓ Mining channel open: id={}", channel_id));
+            }
+            Event::ChannelDown => {
+                self.log("✗ Mining channel closed");
+            }
+            Event::Share(_) => {
+                self.st.shares += 1;
+            }
+            Event::BlockFound(sol) => {
+                self.st.blocks += 1;
+                self.log(format!("★ Block found: id={}", sol.tpl_id));
+            }
+            Event::TemplateErr(e) => {
+                self.log(format!("✗ Template error: {}", e));
+            }
+            Event::HandshakeErr(e) => {
+                self.st.handshake_ok = false;
+                self.log(format!("✗ Handshake failed: {}", e));
+            }
             Event::Err(e) => {
                 self.log(format!("✗ Error: {}", e));
             }